@@ -0,0 +1,251 @@
+use std::{
+    fmt,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::anyhow;
+use hex::ToHex as _;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tokio::io::{AsyncRead, ReadBuf};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+    /// HMAC-SHA256 under a key derived from the manifest key, kept apart from
+    /// the AES-CTR key it's derived from. The manifest carries its key, so
+    /// these checksums only prove who made a manifest when they're verified
+    /// with the publisher's key, obtained some other way than the manifest
+    /// (see `verify_manifest_with_key`).
+    #[serde(rename = "hmac-sha256")]
+    HmacSha256,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+            HashAlgorithm::HmacSha256 => write!(f, "hmac-sha256"),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "hmac-sha256" => Ok(HashAlgorithm::HmacSha256),
+            _ => Err(anyhow!("unknown hash algorithm: {}", s)),
+        }
+    }
+}
+
+/// Incremental hasher for chunk contents, producing hex-encoded checksums
+pub enum ChunkHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    HmacSha256(Box<hmac::Context>),
+}
+
+impl ChunkHasher {
+    /// `key` is the manifest key, only used by keyed algorithms
+    pub fn new(algorithm: HashAlgorithm, key: &[u8; 16]) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => ChunkHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => ChunkHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::HmacSha256 => {
+                ChunkHasher::HmacSha256(Box::new(hmac::Context::with_key(&hmac_key(key))))
+            }
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ChunkHasher::Sha256(hasher) => hasher.update(data),
+            ChunkHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            ChunkHasher::HmacSha256(context) => context.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> String {
+        match self {
+            ChunkHasher::Sha256(hasher) => hasher.finalize().encode_hex(),
+            ChunkHasher::Blake3(hasher) => hasher.finalize().as_bytes().encode_hex(),
+            ChunkHasher::HmacSha256(context) => context.sign().as_ref().encode_hex(),
+        }
+    }
+}
+
+/// The HMAC key for a manifest key. The manifest key also encrypts chunks, so
+/// checksums are keyed with a hash of it under a label of their own instead.
+fn hmac_key(key: &[u8; 16]) -> hmac::Key {
+    let mut hasher = Sha256::new();
+    hasher.update(b"droplet hmac-sha256 chunk checksum");
+    hasher.update(key);
+    hmac::Key::new(hmac::HMAC_SHA256, &hasher.finalize())
+}
+
+/// Hashes a whole chunk's contents (decompressed and decrypted) the way
+/// generation does, for checking chunks assembled outside of droplet. `key` is
+/// the manifest key, only used by keyed algorithms.
+pub fn hash_chunk(bytes: &[u8], algorithm: HashAlgorithm, key: &[u8; 16]) -> String {
+    let mut hasher = ChunkHasher::new(algorithm, key);
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+/// One step of a Merkle proof: the hash of the sibling of the node proven so
+/// far, and which side of it the sibling is on
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MerkleStep {
+    pub sibling: String,
+    pub sibling_is_left: bool,
+}
+
+fn merkle_leaf(checksum: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0u8]);
+    hasher.update(checksum.as_bytes());
+    hasher.finalize().into()
+}
+
+fn merkle_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Every level of the tree over `checksums`, leaves first, ending with the root
+fn merkle_levels<S: AsRef<str>>(checksums: &[S]) -> Vec<Vec<[u8; 32]>> {
+    let mut levels = vec![checksums
+        .iter()
+        .map(|v| merkle_leaf(v.as_ref()))
+        .collect::<Vec<_>>()];
+    while levels.last().is_some_and(|v| v.len() > 1) {
+        let level = levels.last().unwrap();
+        let parents = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => merkle_node(left, right),
+                // An odd node out moves up a level as it is
+                [node] => *node,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(parents);
+    }
+
+    levels
+}
+
+/// Hex SHA-256 Merkle root over `checksums`, in the order given. Leaves and
+/// inner nodes are hashed with different prefixes, so neither can pass for
+/// the other. The root of no checksums is the hash of nothing.
+pub fn merkle_root<S: AsRef<str>>(checksums: &[S]) -> String {
+    match merkle_levels(checksums).last().and_then(|v| v.first()) {
+        Some(root) => root.encode_hex(),
+        None => Sha256::digest([]).encode_hex(),
+    }
+}
+
+/// The steps from `checksums[index]` up to `merkle_root(checksums)`
+pub fn merkle_proof<S: AsRef<str>>(checksums: &[S], index: usize) -> Option<Vec<MerkleStep>> {
+    if index >= checksums.len() {
+        return None;
+    }
+
+    let mut proof = Vec::new();
+    let mut index = index;
+    let levels = merkle_levels(checksums);
+    for level in &levels[..levels.len() - 1] {
+        let sibling = index ^ 1;
+        if let Some(sibling_hash) = level.get(sibling) {
+            proof.push(MerkleStep {
+                sibling: sibling_hash.encode_hex(),
+                sibling_is_left: sibling < index,
+            });
+        }
+        index /= 2;
+    }
+
+    Some(proof)
+}
+
+/// Whether `proof` leads from `checksum` to `root`
+pub fn verify_merkle_proof(checksum: &str, proof: &[MerkleStep], root: &str) -> bool {
+    let mut node = merkle_leaf(checksum);
+    for step in proof {
+        let mut sibling = [0u8; 32];
+        if hex::decode_to_slice(&step.sibling, &mut sibling).is_err() {
+            return false;
+        }
+        node = if step.sibling_is_left {
+            merkle_node(&sibling, &node)
+        } else {
+            merkle_node(&node, &sibling)
+        };
+    }
+
+    checksums_match(&node.encode_hex::<String>(), root)
+}
+
+/// Compares two checksums in time that depends only on their lengths, so a
+/// mismatch doesn't leak how much of a forged checksum was right
+pub fn checksums_match(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Hashes bytes as they're read through it, so a stream can be checksummed
+/// and copied elsewhere in a single pass
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: ChunkHasher,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R, hasher: ChunkHasher) -> Self {
+        Self { inner, hasher }
+    }
+
+    /// Returns the hasher, which has seen everything read so far
+    pub fn into_hasher(self) -> ChunkHasher {
+        self.hasher
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.hasher.update(&buf.filled()[before..]);
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,225 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::versions::types::VersionFile;
+
+/// How generation decides where chunks start and end
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChunkingStrategy {
+    /// Chunks of `ManifestOptions::chunk_size`, packing small files together
+    #[default]
+    Fixed,
+    /// Boundaries picked by a rolling hash of the contents (FastCDC-style), so
+    /// an edit only moves the boundaries around it. Chunks are `min` to `max`
+    /// bytes, `avg` on average, and never span files.
+    ContentDefined { min: u64, avg: u64, max: u64 },
+}
+
+impl fmt::Display for ChunkingStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkingStrategy::Fixed => write!(f, "fixed"),
+            ChunkingStrategy::ContentDefined { min, avg, max } => {
+                write!(f, "cdc:{}:{}:{}", min, avg, max)
+            }
+        }
+    }
+}
+
+/// Parses `fixed` or `cdc:<min>:<avg>:<max>`, sizes in bytes
+impl FromStr for ChunkingStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        match parts.next().map(|v| v.to_ascii_lowercase()).as_deref() {
+            Some("fixed") if parts.next().is_none() => Ok(ChunkingStrategy::Fixed),
+            Some("cdc") => {
+                let mut size = || -> anyhow::Result<u64> {
+                    let part = parts
+                        .next()
+                        .ok_or_else(|| anyhow!("expected cdc:<min>:<avg>:<max>"))?;
+                    part.parse()
+                        .map_err(|_| anyhow!("invalid chunk size: {}", part))
+                };
+                let strategy = ChunkingStrategy::ContentDefined {
+                    min: size()?,
+                    avg: size()?,
+                    max: size()?,
+                };
+                if parts.next().is_some() {
+                    return Err(anyhow!("expected cdc:<min>:<avg>:<max>"));
+                }
+                strategy.validate()?;
+                Ok(strategy)
+            }
+            _ => Err(anyhow!("unknown chunking strategy: {}", s)),
+        }
+    }
+}
+
+impl ChunkingStrategy {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let ChunkingStrategy::ContentDefined { min, avg, max } = *self {
+            if min == 0 || min > avg || avg > max {
+                return Err(anyhow!(
+                    "content-defined chunk sizes must satisfy 0 < min <= avg <= max, got {}:{}:{}",
+                    min,
+                    avg,
+                    max
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The order fixed-size chunking packs files in. Files are packed in one pass,
+/// so files next to each other in this order end up in the same or
+/// neighbouring chunks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PackingStrategy {
+    /// Largest first, which leaves the fewest part-filled chunks
+    #[default]
+    SizeDescending,
+    /// A directory's files together (largest first within it), each followed
+    /// by its subdirectories, so partial installs touch fewer chunks
+    DirectoryLocality,
+    /// By path
+    Alphabetical,
+}
+
+impl fmt::Display for PackingStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackingStrategy::SizeDescending => write!(f, "size"),
+            PackingStrategy::DirectoryLocality => write!(f, "directory"),
+            PackingStrategy::Alphabetical => write!(f, "alphabetical"),
+        }
+    }
+}
+
+/// Parses `size`, `directory` or `alphabetical`
+impl FromStr for PackingStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "size" => Ok(PackingStrategy::SizeDescending),
+            "directory" => Ok(PackingStrategy::DirectoryLocality),
+            "alphabetical" => Ok(PackingStrategy::Alphabetical),
+            _ => Err(anyhow!("unknown packing strategy: {}", s)),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl PackingStrategy {
+    pub(crate) fn sort(&self, files: &mut [VersionFile]) {
+        match self {
+            PackingStrategy::SizeDescending => files.sort_by_key(|v| std::cmp::Reverse(v.size)),
+            PackingStrategy::DirectoryLocality => files.sort_by(|a, b| {
+                // By components, so `a/b` sorts right after `a`, before `a-b`
+                let directory = |file: &VersionFile| -> Vec<String> {
+                    let mut components: Vec<String> = file
+                        .relative_filename
+                        .split('/')
+                        .map(str::to_owned)
+                        .collect();
+                    components.pop();
+                    components
+                };
+                directory(a)
+                    .cmp(&directory(b))
+                    .then(b.size.cmp(&a.size))
+                    .then(a.relative_filename.cmp(&b.relative_filename))
+            }),
+            PackingStrategy::Alphabetical => {
+                files.sort_by(|a, b| a.relative_filename.cmp(&b.relative_filename))
+            }
+        }
+    }
+}
+
+/// Random values for the gear hash, one per byte value
+#[cfg(not(target_arch = "wasm32"))]
+const GEAR: [u64; 256] = {
+    // splitmix64, so the table is fixed without being typed out
+    let mut table = [0u64; 256];
+    let mut state = 0x6472_6f70_6c65_7421u64;
+    let mut index = 0;
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[index] = value ^ (value >> 31);
+        index += 1;
+    }
+    table
+};
+
+/// Finds content-defined cut points in a stream fed to it in pieces. Uses
+/// FastCDC's normalised chunking: a stricter mask before `avg` and a looser
+/// one after, which keeps chunk sizes close to `avg`.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct ContentDefinedCutter {
+    min: u64,
+    avg: u64,
+    max: u64,
+    mask_strict: u64,
+    mask_loose: u64,
+    hash: u64,
+    length: u64,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ContentDefinedCutter {
+    pub fn new(min: u64, avg: u64, max: u64) -> Self {
+        let bits = avg.max(2).ilog2();
+        // Masks over the hash's high bits, which depend on the most bytes
+        let mask = |bits: u32| {
+            let bits = bits.clamp(1, 63);
+            ((1u64 << bits) - 1) << (64 - bits)
+        };
+
+        Self {
+            min,
+            avg,
+            max,
+            mask_strict: mask(bits + 1),
+            mask_loose: mask(bits - 1),
+            hash: 0,
+            length: 0,
+        }
+    }
+
+    /// Feeds `data`, returning how many of its bytes complete the current
+    /// chunk, if it ends within `data`. The rest should be fed again.
+    pub fn push(&mut self, data: &[u8]) -> Option<usize> {
+        for (index, byte) in data.iter().enumerate() {
+            self.length += 1;
+            // Cut points under `min` are never used, so skip hashing them
+            if self.length < self.min {
+                continue;
+            }
+
+            self.hash = (self.hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if self.length < self.avg {
+                self.mask_strict
+            } else {
+                self.mask_loose
+            };
+            if self.hash & mask == 0 || self.length >= self.max {
+                self.hash = 0;
+                self.length = 0;
+                return Some(index + 1);
+            }
+        }
+
+        None
+    }
+}
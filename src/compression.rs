@@ -0,0 +1,119 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::Write as _;
+use std::{
+    fmt,
+    io::{self, Read as _},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd {
+        level: i32,
+    },
+    Gzip,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd { level } => write!(f, "zstd:{}", level),
+            Compression::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+/// Parses `none`, `gzip`, `zstd` or `zstd:<level>`
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+
+        match (name.to_ascii_lowercase().as_str(), level) {
+            ("none", None) => Ok(Compression::None),
+            ("gzip", None) => Ok(Compression::Gzip),
+            ("zstd", None) => Ok(Compression::Zstd {
+                level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            }),
+            ("zstd", Some(level)) => Ok(Compression::Zstd {
+                level: level
+                    .parse()
+                    .map_err(|_| anyhow!("invalid zstd level: {}", level))?,
+            }),
+            _ => Err(anyhow!("unknown compression: {}", s)),
+        }
+    }
+}
+
+impl Compression {
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd { .. } => zstd::stream::decode_all(data),
+            Compression::Gzip => {
+                let mut result = Vec::new();
+                flate2::read::GzDecoder::new(data).read_to_end(&mut result)?;
+                Ok(result)
+            }
+        }
+    }
+}
+
+/// Streaming compressor that buffers its output in memory until taken
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) enum ChunkCompressor {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ChunkCompressor {
+    /// `None` when `compression` is `Compression::None`
+    pub fn new(compression: Compression) -> io::Result<Option<Self>> {
+        Ok(match compression {
+            Compression::None => None,
+            Compression::Zstd { level } => Some(ChunkCompressor::Zstd(
+                zstd::stream::write::Encoder::new(Vec::new(), level)?,
+            )),
+            Compression::Gzip => Some(ChunkCompressor::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            ))),
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            ChunkCompressor::Zstd(encoder) => encoder.write_all(data),
+            ChunkCompressor::Gzip(encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Compressed bytes produced so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            ChunkCompressor::Zstd(encoder) => std::mem::take(encoder.get_mut()),
+            ChunkCompressor::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    /// Finishes the stream, returning the remaining compressed bytes
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            ChunkCompressor::Zstd(encoder) => encoder.finish(),
+            ChunkCompressor::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
@@ -1,12 +1,26 @@
 #![deny(clippy::all)]
 #![feature(impl_trait_in_bindings)]
 
+//! On `wasm32` only the parts that don't touch the filesystem or spawn
+//! processes are built: manifest parsing, checksums, compression and chunk
+//! verification against downloaded bytes.
+
+pub mod checksum;
+pub mod chunking;
+pub mod compression;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod file_utils;
 pub mod manifest;
+pub mod random;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod ssl;
+pub mod storage;
+pub mod verify;
 pub mod versions;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod vm;
 
+#[cfg(not(target_arch = "wasm32"))]
 extern crate libarchive_drop;
 
 #[cfg(test)]
@@ -1,24 +1,368 @@
-use std::{env, path::PathBuf};
+use std::path::{Path, PathBuf};
 
-use droplet_rs::manifest::generate_manifest_rusty;
-use tokio::runtime::Handle;
+use anyhow::{anyhow, Context as _};
+use clap::{Parser, Subcommand};
+use droplet_rs::{
+    checksum::HashAlgorithm,
+    chunking::{ChunkingStrategy, PackingStrategy},
+    compression::Compression,
+    manifest::{
+        generate_manifest_from_reader, generate_manifest_with_stats, Manifest, ManifestOptions,
+    },
+    ssl::{generate_root_ca, issue_client_from_files},
+    verify::verify_manifest_with_progress,
+};
+use humansize::{format_size, BINARY};
+use tokio::{fs, io::AsyncWriteExt as _};
+
+#[derive(Parser)]
+#[command(
+    name = "droplet",
+    version,
+    about = "Manifest and certificate tooling for Drop"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generate a manifest for a directory or archive, or `-` for an archive
+    /// piped to stdin
+    Generate {
+        source: PathBuf,
+        #[arg(short, long, default_value = "manifest.json")]
+        output: PathBuf,
+        /// Target chunk size, e.g. 64M or 512K
+        #[arg(long, default_value = "64M", value_parser = parse_size)]
+        chunk_size: u64,
+        /// Derive the chunk size to produce roughly this many chunks, overriding --chunk-size
+        #[arg(long)]
+        target_chunks: Option<usize>,
+        #[arg(long, default_value_t = HashAlgorithm::Sha256)]
+        hash: HashAlgorithm,
+        /// Also write each chunk's bytes into this directory
+        #[arg(long)]
+        chunks_dir: Option<PathBuf>,
+        /// Encrypt the chunks written to --chunks-dir
+        #[arg(long, requires = "chunks_dir")]
+        encrypt: bool,
+        /// Compress the chunks written to --chunks-dir: none, gzip, zstd or zstd:<level>
+        #[arg(long, default_value_t = Compression::None, requires = "chunks_dir")]
+        compression: Compression,
+        /// Include dotfiles and hidden/system files
+        #[arg(long)]
+        include_hidden: bool,
+        /// Leave out files smaller than this, e.g. 1K
+        #[arg(long, value_parser = parse_size)]
+        min_file_size: Option<u64>,
+        /// Leave out files larger than this, e.g. 4G
+        #[arg(long, value_parser = parse_size)]
+        max_file_size: Option<u64>,
+        /// Fail on paths that can't be installed on Windows, instead of warning
+        #[arg(long)]
+        strict_filenames: bool,
+        /// Fail if there are no files to manifest, instead of warning
+        #[arg(long)]
+        fail_on_empty: bool,
+        /// How to split files: fixed, or cdc:<min>:<avg>:<max> for content-defined chunks
+        #[arg(long, default_value_t = ChunkingStrategy::Fixed)]
+        chunking: ChunkingStrategy,
+        /// Order to pack files in: size, directory or alphabetical
+        #[arg(long, default_value_t = PackingStrategy::SizeDescending)]
+        packing: PackingStrategy,
+        /// Never split files smaller than this across chunks, e.g. 4M
+        #[arg(long, value_parser = parse_size)]
+        keep_whole_under: Option<u64>,
+        /// Never split files matching this glob, e.g. '*.pak' (repeatable)
+        #[arg(long)]
+        keep_whole: Vec<String>,
+        /// Fail if any chunk would be larger than this, e.g. 256M
+        #[arg(long, value_parser = parse_size)]
+        max_chunk_bytes: Option<u64>,
+        /// Store a Merkle root over the chunk checksums in the manifest
+        #[arg(long)]
+        merkle_root: bool,
+        /// Tag the manifest, e.g. --metadata build=14029 (repeatable)
+        #[arg(long, value_parser = parse_metadata)]
+        metadata: Vec<(String, String)>,
+        /// Leave out a relative path and everything under it (repeatable)
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
+    /// Verify an installed directory against a manifest
+    Verify { dir: PathBuf, manifest: PathBuf },
+    /// Certificate management
+    #[command(subcommand)]
+    Ssl(SslCommand),
+}
+
+#[derive(Subcommand)]
+enum SslCommand {
+    /// Generate a new root CA
+    GenerateCa {
+        #[arg(long, default_value = "ca.pem")]
+        cert: PathBuf,
+        #[arg(long, default_value = "ca.key")]
+        key: PathBuf,
+        /// Overwrite an existing certificate and key
+        #[arg(long)]
+        force: bool,
+    },
+    /// Issue a client certificate signed by an existing root CA
+    IssueClient {
+        client_id: String,
+        client_name: String,
+        #[arg(long, default_value = "ca.pem")]
+        ca_cert: PathBuf,
+        #[arg(long, default_value = "ca.key")]
+        ca_key: PathBuf,
+        #[arg(long, default_value = "client.pem")]
+        cert: PathBuf,
+        #[arg(long, default_value = "client.key")]
+        key: PathBuf,
+        /// Overwrite an existing certificate and key
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Parses `key=value`
+fn parse_metadata(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got {}", value))?;
+    if key.is_empty() {
+        return Err("metadata key can't be empty".to_string());
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parses a byte count with an optional binary suffix (K, M, G, T)
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {}", value))?;
+
+    let suffix = suffix.to_ascii_uppercase();
+    let multiplier: u64 = match suffix.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1024,
+        "M" => 1024 * 1024,
+        "G" => 1024 * 1024 * 1024,
+        "T" => 1024 * 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size suffix: {}", suffix)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size too large: {}", value))
+}
+
+/// Writes `contents` to `path`, refusing to replace an existing file unless
+/// `force`. `private` files are only readable by their owner on Unix.
+async fn write_new_file(
+    path: &Path,
+    contents: &str,
+    force: bool,
+    private: bool,
+) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    if private {
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .await
+        .with_context(|| format!("writing {}", path.display()))?;
+    // `mode` only applies to new files, so tighten one being overwritten too
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    file.write_all(contents.as_bytes())
+        .await
+        .with_context(|| format!("writing {}", path.display()))?;
+
+    Ok(())
+}
+
+async fn write_pem_pair(
+    pems: Vec<String>,
+    cert: &Path,
+    key: &Path,
+    force: bool,
+) -> anyhow::Result<()> {
+    let [cert_pem, key_pem]: [String; 2] = pems
+        .try_into()
+        .map_err(|_| anyhow!("expected certificate and private key"))?;
+    // Checked up front so a refused key doesn't leave a new, unmatched certificate
+    if !force {
+        if let Some(existing) = [cert, key].into_iter().find(|v| v.exists()) {
+            return Err(anyhow!(
+                "{} already exists, pass --force to overwrite it",
+                existing.display()
+            ));
+        }
+    }
+    write_new_file(key, &key_pem, force, true).await?;
+    write_new_file(cert, &cert_pem, force, false).await?;
+    eprintln!("wrote {} and {}", cert.display(), key.display());
+
+    Ok(())
+}
 
 #[tokio::main]
-pub async fn main() {
-    let mut args = env::args();
-    let target_dir = PathBuf::from(args.nth(1).expect("Provide target directory"));
-
-    let metrics = Handle::current().metrics();
-    println!("using {} workers", metrics.num_workers());
-
-    let _manifest = generate_manifest_rusty(
-        &target_dir,
-        |progress| println!("PROGRESS: {}", progress),
-        |message| {
-            println!("{}", message);
-        },
-        None,
-    )
-    .await
-    .unwrap();
+pub async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Generate {
+            source,
+            output,
+            chunk_size,
+            target_chunks,
+            hash,
+            chunks_dir,
+            encrypt,
+            compression,
+            include_hidden,
+            min_file_size,
+            max_file_size,
+            strict_filenames,
+            fail_on_empty,
+            chunking,
+            packing,
+            keep_whole_under,
+            keep_whole,
+            max_chunk_bytes,
+            merkle_root,
+            metadata,
+            exclude,
+        } => {
+            let options = ManifestOptions {
+                chunk_size,
+                target_chunks,
+                hash,
+                output_dir: chunks_dir,
+                encrypt,
+                compression,
+                include_hidden,
+                min_file_size,
+                max_file_size,
+                strict_filenames,
+                fail_on_empty,
+                chunking,
+                packing,
+                keep_whole_under,
+                keep_whole,
+                max_chunk_bytes,
+                merkle_root,
+                metadata: metadata.into_iter().collect(),
+                exclude,
+                ..Default::default()
+            };
+            let log = |message| eprintln!("{}", message);
+            let (manifest, stats) = if source == Path::new("-") {
+                generate_manifest_from_reader(std::io::stdin(), log, options).await?
+            } else {
+                generate_manifest_with_stats(
+                    &source,
+                    |progress| eprintln!("PROGRESS: {:.1}%", progress),
+                    log,
+                    options,
+                )
+                .await?
+            };
+
+            std::fs::File::create(&output)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| Ok(manifest.write_json(file)?))
+                .with_context(|| format!("writing {}", output.display()))?;
+            eprintln!("wrote manifest to {}", output.display());
+            eprintln!(
+                "hashed {} in {} chunks in {:.1?} ({}/s, peak {} chunks at once on {} workers)",
+                format_size(stats.total_read_bytes, BINARY),
+                stats.chunks,
+                stats.duration,
+                format_size(stats.throughput() as u64, BINARY),
+                stats.peak_concurrent_chunks,
+                stats.workers
+            );
+        }
+        Command::Verify { dir, manifest } => {
+            let file = std::fs::File::open(&manifest)
+                .with_context(|| format!("reading {}", manifest.display()))?;
+            let manifest = Manifest::from_json_reader(file)?;
+
+            let concurrency = std::thread::available_parallelism().map_or(1, |v| v.get());
+            let results = verify_manifest_with_progress(
+                &dir,
+                &manifest,
+                concurrency,
+                |progress| eprintln!("PROGRESS: {:.1}%", progress),
+                |message| eprintln!("{}", message),
+            )
+            .await?;
+            let mut failed: Vec<&String> = results
+                .iter()
+                .filter(|(_, valid)| !**valid)
+                .map(|(chunk_id, _)| chunk_id)
+                .collect();
+            failed.sort();
+            if !failed.is_empty() {
+                for chunk_id in &failed {
+                    eprintln!("chunk {} failed verification", chunk_id);
+                }
+                return Err(anyhow!(
+                    "{} of {} chunks failed verification",
+                    failed.len(),
+                    manifest.chunks.len()
+                ));
+            }
+            eprintln!("all {} chunks verified", manifest.chunks.len());
+        }
+        Command::Ssl(SslCommand::GenerateCa { cert, key, force }) => {
+            write_pem_pair(generate_root_ca()?, &cert, &key, force).await?;
+        }
+        Command::Ssl(SslCommand::IssueClient {
+            client_id,
+            client_name,
+            ca_cert,
+            ca_key,
+            cert,
+            key,
+            force,
+        }) => {
+            let client = issue_client_from_files(&ca_cert, &ca_key, client_id, client_name).await?;
+            write_pem_pair(
+                vec![client.certificate, client.private_key],
+                &cert,
+                &key,
+                force,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
 }
@@ -1,78 +1,1208 @@
 use std::{
-    collections::HashMap,
-    mem,
-    path::Path,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
-use anyhow::anyhow;
+#[cfg(not(target_arch = "wasm32"))]
+use anyhow::{anyhow, Context as _};
+use futures::{Future, Stream};
+#[cfg(not(target_arch = "wasm32"))]
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use hex::ToHex as _;
+#[cfg(not(target_arch = "wasm32"))]
 use humansize::{format_size, BINARY};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest as _, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt as _, ReadBuf, Take};
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::{
-    io::AsyncReadExt as _,
     join,
     sync::{Mutex, Semaphore},
     task::JoinSet,
 };
 
 #[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct FileEntry {
     pub filename: String,
-    pub start: usize,
-    pub length: usize,
+    pub start: u64,
+    pub length: u64,
     pub permissions: u32,
+    /// Target of a symlink, in which case `length` is 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+    /// The source file was sparse (`VersionFile::is_sparse`), so zeroed
+    /// ranges can be left as holes when installing
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sparse: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ChunkData {
     pub files: Vec<FileEntry>,
+    /// Checksum of the chunk's uncompressed, unencrypted contents
     pub checksum: String,
     pub iv: [u8; 16],
+    /// Uncompressed length of the chunk's contents
+    #[serde(default)]
+    pub length: u64,
+    /// Compression applied to the stored chunk body
+    #[serde(default)]
+    pub compression: Compression,
+    /// Length of the chunk body as written to storage, if generation wrote one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_length: Option<u64>,
+    /// Checksum of the chunk body exactly as stored (compressed and/or encrypted)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_checksum: Option<String>,
+}
+
+/// Where one fragment of a file lives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLocation {
+    pub chunk_id: String,
+    /// Offset of the fragment within the chunk's contents
+    pub offset: u64,
+    /// Offset of the fragment within the file
+    pub start: u64,
+    pub length: u64,
+}
+
+/// Fragments of every file in a manifest, from `Manifest::build_index`
+#[derive(Debug, Clone, Default)]
+pub struct ManifestIndex {
+    files: HashMap<String, Vec<FileLocation>>,
+}
+
+impl ManifestIndex {
+    /// `filename`'s fragments, in file order
+    pub fn get(&self, filename: &str) -> Option<&[FileLocation]> {
+        self.files.get(filename).map(|v| v.as_slice())
+    }
+
+    pub fn filenames(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(|v| v.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Manifest {
     pub version: String,
     pub chunks: HashMap<String, ChunkData>,
     pub size: u64,
-    pub key: [u8; 16],
+    /// Encrypts chunks and keys `HashAlgorithm::HmacSha256` checksums, so is
+    /// only present when one of those is used. Older manifests always carry
+    /// one, whether or not it's used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<[u8; 16]>,
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// Whether the source could be read at arbitrary offsets. Manifests of
+    /// non-seekable sources (like tar archives) only ever hold whole files
+    /// per chunk, and their chunks are best consumed in order. Older
+    /// manifests don't record this, and are treated as non-seekable.
+    #[serde(default)]
+    pub seekable: bool,
+    /// Publisher-defined tags, like a build number or branch, carried along
+    /// with the manifest. Not interpreted by droplet.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    /// `compute_merkle_root`, if generation was asked for it, committing to
+    /// every chunk's checksum in one value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StructuralError {
+    /// A file's fragment starts before the previous fragment ended
+    Overlap {
+        filename: String,
+        previous_end: u64,
+        start: u64,
+    },
+    /// A file's fragment starts after the previous fragment ended (or after 0)
+    Gap {
+        filename: String,
+        expected_start: u64,
+        start: u64,
+    },
+    /// A chunk's `length` isn't the sum of its files' lengths
+    ChunkLengthMismatch {
+        chunk_id: String,
+        length: u64,
+        files_length: u64,
+    },
+    /// The chunk contributions don't add up to `Manifest.size`
+    SizeMismatch { expected: u64, actual: u64 },
+    /// `Manifest.merkle_root` isn't the root of the chunks' checksums
+    MerkleRootMismatch { expected: String, actual: String },
 }
 
-const CHUNK_SIZE: u64 = 1024 * 1024 * 64;
+impl fmt::Display for StructuralError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StructuralError::Overlap {
+                filename,
+                previous_end,
+                start,
+            } => write!(
+                f,
+                "{}: fragment at {} overlaps previous fragment ending at {}",
+                filename, start, previous_end
+            ),
+            StructuralError::Gap {
+                filename,
+                expected_start,
+                start,
+            } => write!(
+                f,
+                "{}: gap between {} and fragment at {}",
+                filename, expected_start, start
+            ),
+            StructuralError::ChunkLengthMismatch {
+                chunk_id,
+                length,
+                files_length,
+            } => write!(
+                f,
+                "chunk {} has length {} but its files contain {} bytes",
+                chunk_id, length, files_length
+            ),
+            StructuralError::SizeMismatch { expected, actual } => write!(
+                f,
+                "manifest size is {} but chunks contain {} bytes",
+                expected, actual
+            ),
+            StructuralError::MerkleRootMismatch { expected, actual } => write!(
+                f,
+                "manifest merkle root is {} but its chunks' is {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StructuralError {}
+
+/// The manifest format version generation writes
+pub const MANIFEST_VERSION: &str = "2";
+
+/// What unkeyed checksums are computed with in place of a key, which they
+/// ignore. Used wherever a manifest has no `key`.
+pub(crate) const UNKEYED: [u8; 16] = [0; 16];
+
+#[derive(Debug)]
+pub enum ManifestVersionError {
+    /// The manifest has no `version` field
+    Missing,
+    /// Written by a newer (or unrecognised) version of droplet
+    Unsupported(String),
+    /// Not valid JSON, or not a valid manifest of its version
+    Invalid(serde_json::Error),
+}
+
+impl fmt::Display for ManifestVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestVersionError::Missing => write!(f, "manifest has no version"),
+            ManifestVersionError::Unsupported(version) => write!(
+                f,
+                "unsupported manifest version {:?} (expected at most {})",
+                version, MANIFEST_VERSION
+            ),
+            ManifestVersionError::Invalid(err) => write!(f, "invalid manifest: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ManifestVersionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ManifestVersionError::Invalid(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Manifest {
+    /// Parses a manifest, checking its version first so unknown versions get a
+    /// clear error, and upgrading older ones to `MANIFEST_VERSION`
+    pub fn from_json_checked(json: &str) -> Result<Manifest, ManifestVersionError> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: Option<serde_json::Value>,
+        }
+
+        let versioned: Versioned =
+            serde_json::from_str(json).map_err(ManifestVersionError::Invalid)?;
+        let version = match versioned.version {
+            Some(serde_json::Value::String(version)) => version,
+            Some(version) => return Err(ManifestVersionError::Unsupported(version.to_string())),
+            None => return Err(ManifestVersionError::Missing),
+        };
+        if version != "1" && version != MANIFEST_VERSION {
+            return Err(ManifestVersionError::Unsupported(version));
+        }
+
+        let manifest: Manifest =
+            serde_json::from_str(json).map_err(ManifestVersionError::Invalid)?;
+        manifest.upgraded()
+    }
+
+    /// Like `from_json_checked`, but parses straight from `reader` without
+    /// holding the JSON in memory. The reader is only read once, so a
+    /// missing or non-string version is reported as `Invalid`.
+    pub fn from_json_reader<R: std::io::Read>(reader: R) -> Result<Manifest, ManifestVersionError> {
+        let manifest: Manifest = serde_json::from_reader(std::io::BufReader::new(reader))
+            .map_err(ManifestVersionError::Invalid)?;
+        if manifest.version != "1" && manifest.version != MANIFEST_VERSION {
+            return Err(ManifestVersionError::Unsupported(manifest.version));
+        }
+
+        manifest.upgraded()
+    }
+
+    /// Writes the manifest as JSON straight to `writer`, without building the
+    /// whole string in memory first
+    pub fn write_json<W: std::io::Write>(&self, writer: W) -> serde_json::Result<()> {
+        let mut writer = std::io::BufWriter::new(writer);
+        serde_json::to_writer(&mut writer, self)?;
+        std::io::Write::flush(&mut writer).map_err(serde_json::Error::io)
+    }
+
+    /// A parsed manifest of a supported version, brought up to `MANIFEST_VERSION`
+    fn upgraded(mut self) -> Result<Manifest, ManifestVersionError> {
+        // Fields added since v1 are defaulted by serde, except chunk lengths
+        // which are derived from the files
+        for chunk in self.chunks.values_mut() {
+            if chunk.length == 0 {
+                chunk.length = chunk.files.iter().map(|v| v.length).sum();
+            }
+        }
+        self.version = MANIFEST_VERSION.to_string();
+        if self.hash == HashAlgorithm::HmacSha256 && self.key.is_none() {
+            return Err(ManifestVersionError::Invalid(serde::de::Error::custom(
+                "hmac-sha256 manifests need a key",
+            )));
+        }
+
+        Ok(self)
+    }
+
+    /// The key checksums were computed with, which is `UNKEYED` when the
+    /// manifest has none, as unkeyed algorithms ignore it. It comes from the
+    /// manifest itself, so checks keyed with it don't show that the publisher
+    /// made the manifest.
+    pub fn hash_key(&self) -> [u8; 16] {
+        self.key.unwrap_or(UNKEYED)
+    }
+
+    /// Bytes written to disk when installed
+    pub fn installed_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes a client downloads: each chunk's stored (compressed) length, with
+    /// chunks sharing a stored checksum counted once. Chunks without a stored
+    /// body count their uncompressed length.
+    pub fn download_size(&self) -> u64 {
+        let mut seen = HashSet::new();
+        self.chunks
+            .values()
+            .filter(|chunk| match &chunk.stored_checksum {
+                Some(stored_checksum) => seen.insert(stored_checksum.as_str()),
+                None => true,
+            })
+            .map(|chunk| chunk.stored_length.unwrap_or(chunk.length))
+            .sum()
+    }
+
+    /// Every chunk's (uncompressed) checksum, once each: the bodies a
+    /// content-addressed store has to keep for this manifest
+    pub fn unique_checksums(&self) -> HashSet<&str> {
+        self.chunks.values().map(|v| v.checksum.as_str()).collect()
+    }
+
+    /// Every chunk with its id, in no particular order, for feeding into
+    /// `StreamExt::buffer_unordered` and the like
+    pub fn chunk_stream(&self) -> impl Stream<Item = (&str, &ChunkData)> {
+        futures::stream::iter(self.chunks.iter().map(|(id, chunk)| (id.as_str(), chunk)))
+    }
+
+    /// Chunk checksums in Merkle tree order: sorted, duplicates included
+    fn merkle_leaves(&self) -> Vec<&str> {
+        let mut checksums: Vec<&str> = self.chunks.values().map(|v| v.checksum.as_str()).collect();
+        checksums.sort_unstable();
+        checksums
+    }
+
+    /// Merkle root (see `checksum::merkle_root`) over the sorted checksums
+    /// of every chunk, so it's independent of chunk ids and order
+    pub fn compute_merkle_root(&self) -> String {
+        merkle_root(&self.merkle_leaves())
+    }
+
+    /// Proof that chunk `chunk_id`'s checksum is under `compute_merkle_root`,
+    /// for `checksum::verify_merkle_proof`
+    pub fn merkle_proof(&self, chunk_id: &str) -> Option<Vec<MerkleStep>> {
+        let checksum = &self.chunks.get(chunk_id)?.checksum;
+        let leaves = self.merkle_leaves();
+        let index = leaves.binary_search(&checksum.as_str()).ok()?;
+
+        merkle_proof(&leaves, index)
+    }
+
+    /// Ids of the chunks holding any of `filename`'s bytes, sorted
+    pub fn chunks_for_file(&self, filename: &str) -> Vec<&str> {
+        self.chunks_matching(|v| v == filename)
+    }
+
+    /// Ids of the chunks holding any bytes of files at or below `prefix` (a
+    /// relative directory or file path), matched by whole path components,
+    /// sorted. An empty prefix matches every chunk.
+    pub fn chunks_for_prefix(&self, prefix: &str) -> Vec<&str> {
+        let prefix = prefix.trim_end_matches('/');
+        self.chunks_matching(|v| {
+            prefix.is_empty()
+                || v.strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    /// Builds a filename to fragment index, for repeated lookups without
+    /// scanning every chunk
+    pub fn build_index(&self) -> ManifestIndex {
+        let mut files: HashMap<String, Vec<FileLocation>> = HashMap::new();
+        for (chunk_id, chunk) in &self.chunks {
+            let mut offset = 0u64;
+            for entry in &chunk.files {
+                files
+                    .entry(entry.filename.clone())
+                    .or_default()
+                    .push(FileLocation {
+                        chunk_id: chunk_id.clone(),
+                        offset,
+                        start: entry.start,
+                        length: entry.length,
+                    });
+                offset += entry.length;
+            }
+        }
+        for locations in files.values_mut() {
+            locations.sort_by(|a, b| (a.start, &a.chunk_id).cmp(&(b.start, &b.chunk_id)));
+        }
+
+        ManifestIndex { files }
+    }
+
+    /// Hex SHA-256 of the manifest serialized as JSON with every object's keys
+    /// sorted, so equal manifests hash the same however their maps iterate.
+    /// Stable for a given manifest, for use as an ETag; unlike `to_bom` it
+    /// covers everything, chunk ids included.
+    pub fn content_hash(&self) -> String {
+        fn sorted(value: serde_json::Value) -> serde_json::Value {
+            match value {
+                serde_json::Value::Object(map) => {
+                    let mut entries: Vec<_> = map.into_iter().collect();
+                    entries.sort_by(|a, b| a.0.cmp(&b.0));
+                    serde_json::Value::Object(
+                        entries
+                            .into_iter()
+                            .map(|(key, value)| (key, sorted(value)))
+                            .collect(),
+                    )
+                }
+                serde_json::Value::Array(values) => {
+                    serde_json::Value::Array(values.into_iter().map(sorted).collect())
+                }
+                value => value,
+            }
+        }
+
+        // Maps are built in key order, which holds even if serde_json keeps
+        // insertion order rather than sorting
+        let value = serde_json::to_value(self).expect("manifests are always serializable");
+        Sha256::digest(sorted(value).to_string()).encode_hex()
+    }
+
+    /// A "bill of materials": one line per file, sorted by path, of
+    /// `path<TAB>size<TAB>permissions (octal)<TAB>checksums`, where checksums
+    /// are those of the chunks holding the file, in file order and joined by
+    /// commas. Symlinks have `-> target` in place of checksums. Chunk ids
+    /// aren't included, so the listing is stable across regenerations.
+    pub fn to_bom(&self) -> String {
+        let index = self.build_index();
+        let mut entries: HashMap<&str, &FileEntry> = HashMap::new();
+        for chunk in self.chunks.values() {
+            for entry in &chunk.files {
+                entries.entry(&entry.filename).or_insert(entry);
+            }
+        }
+
+        let mut filenames: Vec<&str> = index.filenames().collect();
+        filenames.sort();
+
+        let mut bom = String::new();
+        for filename in filenames {
+            let locations = index.get(filename).unwrap_or_default();
+            let entry = entries[filename];
+            let size: u64 = locations.iter().map(|v| v.length).sum();
+            let contents = match &entry.symlink {
+                Some(target) => format!("-> {}", target),
+                None => locations
+                    .iter()
+                    .map(|v| self.chunks[&v.chunk_id].checksum.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            };
+            bom.push_str(&format!(
+                "{}\t{}\t{:o}\t{}\n",
+                filename, size, entry.permissions, contents
+            ));
+        }
+
+        bom
+    }
+
+    /// The files the manifest covers, as a backend would list them, sorted by
+    /// path. Sizes add up each file's fragments.
+    pub fn version_files(&self) -> Vec<VersionFile> {
+        let mut files: HashMap<&str, VersionFile> = HashMap::new();
+        for entry in self.chunks.values().flat_map(|v| &v.files) {
+            files
+                .entry(&entry.filename)
+                .or_insert_with(|| VersionFile {
+                    relative_filename: entry.filename.clone(),
+                    permission: entry.permissions,
+                    size: 0,
+                    symlink: entry.symlink.clone(),
+                    is_sparse: entry.sparse,
+                })
+                .size += entry.length;
+        }
+
+        let mut files: Vec<VersionFile> = files.into_values().collect();
+        files.sort_by(|a, b| a.relative_filename.cmp(&b.relative_filename));
+        files
+    }
+
+    fn chunks_matching(&self, matches: impl Fn(&str) -> bool) -> Vec<&str> {
+        let mut chunk_ids: Vec<&str> = self
+            .chunks
+            .iter()
+            .filter(|(_, chunk)| chunk.files.iter().any(|v| matches(&v.filename)))
+            .map(|(chunk_id, _)| chunk_id.as_str())
+            .collect();
+        chunk_ids.sort();
+
+        chunk_ids
+    }
+
+    /// Checks the manifest is internally consistent, without touching disk:
+    /// every file's fragments must tile `[0, size)` with no gaps or overlaps,
+    /// each chunk's `length` must be the sum of its files' lengths, and the
+    /// chunk contributions must add up to `size`.
+    pub fn validate_structure(&self) -> Result<(), Vec<StructuralError>> {
+        let mut errors = Vec::new();
+
+        // Filename to (start, length) fragments, across all chunks
+        let mut fragments: HashMap<&str, Vec<(u64, u64)>> = HashMap::new();
+        let mut total_length = 0u64;
+        for chunk in self.chunks.values() {
+            for file in &chunk.files {
+                fragments
+                    .entry(&file.filename)
+                    .or_default()
+                    .push((file.start, file.length));
+                total_length += file.length;
+            }
+        }
+
+        let mut filenames: Vec<&&str> = fragments.keys().collect();
+        filenames.sort();
+        for filename in filenames {
+            let mut ranges = fragments[*filename].clone();
+            ranges.sort();
+
+            let mut expected_start = 0;
+            for (start, length) in ranges {
+                if start < expected_start {
+                    errors.push(StructuralError::Overlap {
+                        filename: filename.to_string(),
+                        previous_end: expected_start,
+                        start,
+                    });
+                } else if start > expected_start {
+                    errors.push(StructuralError::Gap {
+                        filename: filename.to_string(),
+                        expected_start,
+                        start,
+                    });
+                }
+                expected_start = expected_start.max(start + length);
+            }
+        }
+
+        let mut chunk_ids: Vec<&String> = self.chunks.keys().collect();
+        chunk_ids.sort();
+        for chunk_id in chunk_ids {
+            let chunk = &self.chunks[chunk_id];
+            let files_length = chunk.files.iter().map(|v| v.length).sum();
+            if chunk.length != files_length {
+                errors.push(StructuralError::ChunkLengthMismatch {
+                    chunk_id: chunk_id.clone(),
+                    length: chunk.length,
+                    files_length,
+                });
+            }
+        }
+
+        if total_length != self.size {
+            errors.push(StructuralError::SizeMismatch {
+                expected: self.size,
+                actual: total_length,
+            });
+        }
+
+        if let Some(expected) = &self.merkle_root {
+            let actual = self.compute_merkle_root();
+            if *expected != actual {
+                errors.push(StructuralError::MerkleRootMismatch {
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// JSON Schema document describing the serialized `Manifest`, for clients in other languages
+#[cfg(feature = "schema")]
+pub fn manifest_json_schema() -> serde_json::Value {
+    let schema = schemars::schema_for!(Manifest);
+    serde_json::to_value(schema).expect("schema is always serializable")
+}
+
+pub const CHUNK_SIZE: u64 = 1024 * 1024 * 64;
+/// How far past `CHUNK_SIZE` a chunk may grow to avoid spilling a sliver of a
+/// file into the next chunk
+pub const WIGGLE: u64 = 1024 * 1024;
+/// Smallest chunk size `ManifestOptions::target_chunks` will derive
+pub const MIN_TARGET_CHUNK_SIZE: u64 = 1024 * 1024;
+/// Default `ManifestOptions::read_buffer_size`
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 1024 * 1024;
+#[cfg(not(target_arch = "wasm32"))]
 const MAX_FILE_COUNT: usize = 512;
 
-use crate::versions::{
-    create_backend_constructor,
-    types::{VersionBackend, VersionFile},
+use crate::{
+    checksum::{merkle_proof, merkle_root, HashAlgorithm, MerkleStep},
+    compression::Compression,
+    versions::types::{MinimumFileObject, VersionBackend, VersionFile},
+};
+// Generation reads sources from the filesystem, so isn't available on wasm32
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    checksum::{ChunkHasher, HashingReader},
+    chunking::{ChunkingStrategy, ContentDefinedCutter, PackingStrategy},
+    random::{random_uuid, system_random, RandomSource},
+    storage::{chunk_cipher, Aes128Ctr, ChunkSink, ChunkWriter, ShardedLayout},
+    versions::{
+        archive_backend::{stream_archive, ArchiveLimits, StreamEntry},
+        create_backend_constructor_with_limits, has_dotfile_component, portable_path, BackendError,
+    },
 };
 
-pub async fn generate_manifest_rusty<T: Fn(String), V: Fn(f32)>(
-    dir: &Path,
-    progress_sfn: V,
-    log_sfn: T,
-    reader_semaphore: Option<Arc<Semaphore>>,
-) -> anyhow::Result<Manifest> {
-    let backend =
-        create_backend_constructor(dir).ok_or(anyhow!("Could not create backend for path."))?()?;
+type OpenFuture<'a> =
+    Pin<Box<dyn Future<Output = anyhow::Result<Box<dyn MinimumFileObject>>> + Send + 'a>>;
+
+/// Presents a chunk's file ranges, in order, as one continuous stream. Files
+/// are opened one at a time as the previous range is used up, and a file that
+/// ends before its range does fails the read with `UnexpectedEof`.
+pub struct ChunkReader<'a> {
+    backend: &'a (dyn VersionBackend + Send + Sync),
+    files: VecDeque<FileEntry>,
+    opening: Option<(OpenFuture<'a>, FileEntry)>,
+    current: Option<(Take<Box<dyn MinimumFileObject>>, String)>,
+}
+
+impl<'a> ChunkReader<'a> {
+    pub fn new(backend: &'a (dyn VersionBackend + Send + Sync), chunk: &ChunkData) -> Self {
+        Self {
+            backend,
+            files: chunk.files.iter().cloned().collect(),
+            opening: None,
+            current: None,
+        }
+    }
+}
+
+impl<'a> AsyncRead for ChunkReader<'a> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let this = &mut *self;
+        loop {
+            if let Some((reader, filename)) = &mut this.current {
+                let before = buf.filled().len();
+                ready!(Pin::new(&mut *reader).poll_read(cx, buf))?;
+                if buf.filled().len() > before {
+                    return Poll::Ready(Ok(()));
+                }
+                if reader.limit() != 0 {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("{} ended {} bytes early", filename, reader.limit()),
+                    )));
+                }
+                this.current = None;
+                continue;
+            }
+
+            if let Some((opening, entry)) = &mut this.opening {
+                let reader = ready!(opening.as_mut().poll(cx)).map_err(std::io::Error::other)?;
+                this.current = Some((reader.take(entry.length), entry.filename.clone()));
+                this.opening = None;
+                continue;
+            }
+
+            let Some(entry) = this.files.pop_front() else {
+                return Poll::Ready(Ok(()));
+            };
+            // Symlinks have no contents to read
+            if entry.symlink.is_some() {
+                continue;
+            }
+            let backend = this.backend;
+            let file = VersionFile {
+                relative_filename: entry.filename.clone(),
+                permission: entry.permissions,
+                size: entry.start + entry.length,
+                symlink: None,
+                is_sparse: entry.sparse,
+            };
+            let (start, end) = (entry.start, entry.start + entry.length);
+            let opening: OpenFuture<'a> =
+                Box::pin(async move { backend.reader(&file, start, Some(end)).await });
+            this.opening = Some((opening, entry));
+        }
+    }
+}
+
+/// What a `ManifestOptions::chunk_id` callback knows about a finished chunk
+#[cfg(not(target_arch = "wasm32"))]
+pub struct ChunkContext<'a> {
+    /// Position in the order chunks were laid out in
+    pub index: usize,
+    pub checksum: &'a str,
+    /// Uncompressed length, in bytes
+    pub length: u64,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub type ChunkIdFn = Arc<dyn Fn(&ChunkContext) -> String + Send + Sync>;
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone)]
+pub struct ManifestOptions {
+    /// Target size of each chunk, in bytes
+    pub chunk_size: u64,
+    pub hash: HashAlgorithm,
+    /// Bounds how many files are read at once across all chunks
+    pub reader_semaphore: Option<Arc<Semaphore>>,
+    /// If set, each chunk's bytes are written to `output_dir/<chunk id>` as they're hashed
+    pub output_dir: Option<PathBuf>,
+    /// Instead of `output_dir`, put each finished chunk into this sink. Chunks
+    /// are buffered in memory until they're finished.
+    pub sink: Option<Arc<dyn ChunkSink>>,
+    /// Names each chunk once it's hashed, instead of a random UUID. Ids must
+    /// be unique, and usable as file names in `output_dir`.
+    pub chunk_id: Option<ChunkIdFn>,
+    /// Encrypt written chunks with AES-128-CTR, using the manifest key and the chunk's IV.
+    /// Checksums always cover the unencrypted bytes.
+    pub encrypt: bool,
+    /// Store written chunks in a `ShardedLayout` under `output_dir`, keyed by
+    /// checksum, instead of flat by chunk id. Can't be combined with `encrypt`,
+    /// as identical chunks would otherwise be stored under different IVs.
+    pub content_addressed: bool,
+    /// Compression applied to written chunks, before encryption
+    pub compression: Compression,
+    /// Tolerance past `chunk_size` before a file spills into the next chunk.
+    /// Defaults to `WIGGLE` scaled to the chunk size (1 MiB for 64 MiB chunks).
+    pub wiggle: Option<u64>,
+    /// Aim for roughly this many chunks by deriving the chunk size from the
+    /// total size (never below `MIN_TARGET_CHUNK_SIZE`), overriding `chunk_size`.
+    /// Whole-file backends and the per-chunk file limit can still produce more.
+    pub target_chunks: Option<usize>,
+    /// Only manifest files at or below this relative directory, for partial manifests
+    pub prefix: Option<String>,
+    /// Size of each chunk task's read buffer. Independent of `chunk_size`, as
+    /// reads are hashed incrementally.
+    pub read_buffer_size: usize,
+    /// Include files the backend considers hidden, like dotfiles or Windows
+    /// hidden/system files (`Thumbs.db`, `desktop.ini`)
+    pub include_hidden: bool,
+    /// Leave out files smaller than this many bytes
+    pub min_file_size: Option<u64>,
+    /// Leave out files larger than this many bytes
+    pub max_file_size: Option<u64>,
+    /// Relative paths to leave out of the listing, along with everything
+    /// under them. `output_dir` is always left out when it's inside the source.
+    pub exclude: Vec<String>,
+    /// Where the key, IVs and chunk ids come from. Chunks draw from it in
+    /// order, so a seeded source gives reproducible manifests.
+    pub random: Arc<dyn RandomSource>,
+    /// Fail generation on paths that can't be installed on Windows, rather
+    /// than logging a warning for each
+    pub strict_filenames: bool,
+    /// Fail generation with `BackendError::EmptySource` when no files are left
+    /// to manifest, rather than logging a warning
+    pub fail_on_empty: bool,
+    /// Bounds on what archive sources may declare, checked while listing
+    pub archive_limits: ArchiveLimits,
+    /// Fixed-size chunks by default. `chunk_size`, `wiggle` and `target_chunks`
+    /// only apply to fixed-size chunking.
+    pub chunking: ChunkingStrategy,
+    /// The order fixed-size chunking packs files in
+    pub packing: PackingStrategy,
+    /// Never split files smaller than this many bytes across chunks, even for
+    /// backends that could read them in pieces
+    pub keep_whole_under: Option<u64>,
+    /// Never split files whose relative paths match one of these glob
+    /// patterns, whatever their size. `*` matches across directories too, so
+    /// `*.pak` matches every `.pak` file.
+    pub keep_whole: Vec<String>,
+    /// Fail generation if any chunk would be larger than this many bytes, for
+    /// consumers that buffer whole chunks. Whole-file sources put each large
+    /// file in a chunk of its own, however big.
+    pub max_chunk_bytes: Option<u64>,
+    /// Copied into `Manifest::metadata`
+    pub metadata: HashMap<String, String>,
+    /// Store the Merkle root of the chunks' checksums in `Manifest::merkle_root`
+    pub merkle_root: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for ManifestOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: CHUNK_SIZE,
+            hash: HashAlgorithm::default(),
+            reader_semaphore: None,
+            output_dir: None,
+            sink: None,
+            chunk_id: None,
+            encrypt: false,
+            content_addressed: false,
+            compression: Compression::None,
+            wiggle: None,
+            target_chunks: None,
+            prefix: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            include_hidden: false,
+            min_file_size: None,
+            max_file_size: None,
+            exclude: Vec::new(),
+            random: system_random(),
+            strict_filenames: false,
+            fail_on_empty: false,
+            archive_limits: ArchiveLimits::default(),
+            chunking: ChunkingStrategy::Fixed,
+            packing: PackingStrategy::SizeDescending,
+            keep_whole_under: None,
+            keep_whole: Vec::new(),
+            max_chunk_bytes: None,
+            metadata: HashMap::new(),
+            merkle_root: false,
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ManifestOptions {
+    fn effective_chunk_size(&self, total_size: u64) -> u64 {
+        match self.target_chunks {
+            Some(target_chunks) => total_size
+                .div_ceil(target_chunks.max(1) as u64)
+                .max(MIN_TARGET_CHUNK_SIZE),
+            None => self.chunk_size,
+        }
+    }
+
+    fn effective_wiggle(&self, chunk_size: u64) -> u64 {
+        self.wiggle.unwrap_or(chunk_size / (CHUNK_SIZE / WIGGLE))
+    }
+
+    /// Checks the options that decide how files are laid out into chunks,
+    /// which planning needs as much as generation
+    fn validate_layout(&self) -> anyhow::Result<()> {
+        if self.chunk_size == 0 {
+            return Err(anyhow!("chunk size must be greater than zero"));
+        }
+        if self.target_chunks == Some(0) {
+            return Err(anyhow!("target chunk count must be greater than zero"));
+        }
+        self.chunking.validate()?;
+        if self.max_chunk_bytes == Some(0) {
+            return Err(anyhow!("maximum chunk size must be greater than zero"));
+        }
+
+        Ok(())
+    }
+
+    fn validate_for_generation(&self) -> anyhow::Result<()> {
+        self.validate_layout()?;
+        if self.read_buffer_size == 0 {
+            return Err(anyhow!("read buffer size must be greater than zero"));
+        }
+        let writes_chunks =
+            self.encrypt || self.content_addressed || self.compression != Compression::None;
+        if writes_chunks && self.output_dir.is_none() && self.sink.is_none() {
+            return Err(anyhow!(
+                "encryption, compression and content addressing require an output directory or sink"
+            ));
+        }
+        if self.output_dir.is_some() && self.sink.is_some() {
+            return Err(anyhow!(
+                "chunks can go to an output directory or a sink, not both"
+            ));
+        }
+        if self.content_addressed && self.sink.is_some() {
+            return Err(anyhow!(
+                "content-addressed chunks can only be stored in an output directory"
+            ));
+        }
+        if self.encrypt && self.content_addressed {
+            return Err(anyhow!(
+                "encrypted chunks can't be stored content-addressed"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether `filename` is at or under one of the `exclude` paths
+    fn excludes(&self, filename: &str) -> bool {
+        self.exclude
+            .iter()
+            .any(|excluded| Path::new(filename).starts_with(excluded))
+    }
+
+    /// `keep_whole`, compiled
+    fn keep_whole_patterns(&self) -> anyhow::Result<GlobSet> {
+        let mut patterns = GlobSetBuilder::new();
+        for pattern in &self.keep_whole {
+            patterns.add(
+                Glob::new(pattern)
+                    .with_context(|| format!("invalid keep-whole pattern {:?}", pattern))?,
+            );
+        }
+
+        Ok(patterns.build()?)
+    }
+
+    /// Whether `file` should never be split, by `keep_whole_under` or
+    /// `keep_whole` (as compiled into `patterns`)
+    fn keeps_whole(&self, patterns: &GlobSet, file: &VersionFile) -> bool {
+        self.keep_whole_under.is_some_and(|v| file.size < v)
+            || patterns.is_match(&file.relative_filename)
+    }
 
+    /// A manifest key, if anything it generates uses one
+    fn draw_key(&self) -> anyhow::Result<Option<[u8; 16]>> {
+        if !self.encrypt && self.hash != HashAlgorithm::HmacSha256 {
+            return Ok(None);
+        }
+        let mut key = [0u8; 16];
+        self.random.fill(&mut key)?;
+
+        Ok(Some(key))
+    }
+}
+
+/// Pairs of paths in `files` that differ only by case, and so would overwrite
+/// each other when installed on a case-insensitive filesystem
+pub fn case_insensitive_collisions(files: &[VersionFile]) -> Vec<(String, String)> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut collisions = Vec::new();
+    for file in files {
+        let folded = file.relative_filename.to_lowercase();
+        match seen.get(&folded) {
+            Some(existing) => {
+                collisions.push((existing.to_string(), file.relative_filename.clone()))
+            }
+            None => {
+                seen.insert(folded, &file.relative_filename);
+            }
+        }
+    }
+    collisions.sort();
+
+    collisions
+}
+
+/// Why a path component can't be created on Windows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilenameProblem {
+    /// One of `< > : " \ | ? *`
+    ReservedCharacter(char),
+    /// A character below 0x20
+    ControlCharacter(char),
+    /// Windows silently strips trailing dots and spaces
+    TrailingDotOrSpace,
+    /// A device name like `CON` or `LPT1`, with or without an extension
+    ReservedName(String),
+}
+
+impl fmt::Display for FilenameProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilenameProblem::ReservedCharacter(v) => {
+                write!(f, "contains reserved character '{}'", v)
+            }
+            FilenameProblem::ControlCharacter(v) => {
+                write!(f, "contains control character {:#04x}", *v as u32)
+            }
+            FilenameProblem::TrailingDotOrSpace => write!(f, "ends with a dot or space"),
+            FilenameProblem::ReservedName(v) => write!(f, "uses reserved device name {}", v),
+        }
+    }
+}
+
+const RESERVED_FILENAME_CHARACTERS: [char; 8] = ['<', '>', ':', '"', '\\', '|', '?', '*'];
+const RESERVED_DEVICE_NAMES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+/// Whether a single path component can be created on Windows
+pub fn filename_problem(component: &str) -> Option<FilenameProblem> {
+    for v in component.chars() {
+        if RESERVED_FILENAME_CHARACTERS.contains(&v) {
+            return Some(FilenameProblem::ReservedCharacter(v));
+        }
+        if (v as u32) < 0x20 {
+            return Some(FilenameProblem::ControlCharacter(v));
+        }
+    }
+    if component.ends_with(['.', ' ']) && component != "." && component != ".." {
+        return Some(FilenameProblem::TrailingDotOrSpace);
+    }
+
+    // `NUL.txt` (and `nul .txt`) is just as reserved as `NUL`
+    let stem = component.split('.').next().unwrap_or_default();
+    let stem = stem.trim_end_matches(' ').to_ascii_uppercase();
+    let is_numbered_device = |prefix: &str| {
+        stem.strip_prefix(prefix)
+            .is_some_and(|v| v.len() == 1 && matches!(v.as_bytes()[0], b'1'..=b'9'))
+    };
+    if RESERVED_DEVICE_NAMES.contains(&stem.as_str())
+        || is_numbered_device("COM")
+        || is_numbered_device("LPT")
+    {
+        return Some(FilenameProblem::ReservedName(stem));
+    }
+
+    None
+}
+
+/// Paths in `files` with a component that can't be created on Windows, with
+/// the first problem found in each
+pub fn unportable_filenames(files: &[VersionFile]) -> Vec<(String, FilenameProblem)> {
+    files
+        .iter()
+        .filter_map(|file| {
+            file.relative_filename
+                .split('/')
+                .find_map(filename_problem)
+                .map(|problem| (file.relative_filename.clone(), problem))
+        })
+        .collect()
+}
+
+/// Splits `files` into chunks the way `options.chunking` says to. Content-defined
+/// chunking reads every file to find its boundaries, and falls back to fixed
+/// chunks for backends that require whole files.
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(files = files.len(), chunking = %options.chunking))
+)]
+async fn organize_source_chunks<T: Fn(String)>(
+    backend: &(dyn VersionBackend + Send + Sync),
+    files: Vec<VersionFile>,
+    options: &ManifestOptions,
+    log_sfn: &T,
+) -> anyhow::Result<Vec<Vec<(VersionFile, u64, u64)>>> {
     let required_single_file = backend.require_whole_files();
+    // Checked here so organize_chunks can't be handed a pattern that doesn't compile
+    let keep_whole = options.keep_whole_patterns()?;
+    let chunks = match options.chunking {
+        ChunkingStrategy::ContentDefined { min, avg, max } if !required_single_file => {
+            content_defined_chunks(backend, files, min, avg, max, &keep_whole, options).await?
+        }
+        ChunkingStrategy::ContentDefined { .. } => {
+            log_sfn(
+                "warning: this source is read as whole files, using fixed-size chunks instead of content-defined ones"
+                    .to_string(),
+            );
+            organize_chunks(files, true, options)
+        }
+        ChunkingStrategy::Fixed => organize_chunks(files, required_single_file, options),
+    };
+    if let Some(max_chunk_bytes) = options.max_chunk_bytes {
+        for chunk in &chunks {
+            check_chunk_length(
+                chunk.iter().map(|v| v.2).sum(),
+                max_chunk_bytes,
+                chunk.iter().map(|v| v.0.relative_filename.as_str()),
+            )?;
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Errors if a chunk of `length` bytes, holding `files`, is over `max_chunk_bytes`
+#[cfg(not(target_arch = "wasm32"))]
+fn check_chunk_length<'a>(
+    length: u64,
+    max_chunk_bytes: u64,
+    mut files: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<()> {
+    if length <= max_chunk_bytes {
+        return Ok(());
+    }
+    let first = files.next().unwrap_or_default();
+    let others = files.count();
+    Err(anyhow!(
+        "chunk of {}{} would be {} bytes, over the maximum of {}",
+        first,
+        if others > 0 {
+            format!(" and {} other files", others)
+        } else {
+            String::new()
+        },
+        length,
+        max_chunk_bytes
+    ))
+}
+
+/// Cuts each of `files` at content-defined boundaries, one chunk per range
+#[cfg(not(target_arch = "wasm32"))]
+async fn content_defined_chunks(
+    backend: &(dyn VersionBackend + Send + Sync),
+    files: Vec<VersionFile>,
+    min: u64,
+    avg: u64,
+    max: u64,
+    keep_whole: &GlobSet,
+    options: &ManifestOptions,
+) -> anyhow::Result<Vec<Vec<(VersionFile, u64, u64)>>> {
+    let mut chunks = Vec::new();
+    let mut read_buf = vec![0u8; options.read_buffer_size.max(1)];
+
+    for file in files {
+        if file.symlink.is_some() {
+            chunks.push(vec![(file, 0, 0)]);
+            continue;
+        }
+        if options.keeps_whole(keep_whole, &file) {
+            let size = file.size;
+            chunks.push(vec![(file, 0, size)]);
+            continue;
+        }
+
+        let mut cutter = ContentDefinedCutter::new(min, avg, max);
+        let mut reader = backend.reader(&file, 0, Some(file.size)).await?;
+        let mut start = 0u64;
+        let mut position = 0u64;
+        loop {
+            let amount = reader.read(&mut read_buf).await?;
+            if amount == 0 {
+                break;
+            }
 
-    let mut files = backend.list_files().await?;
-    files.sort_by_key(|b| std::cmp::Reverse(b.size));
+            let mut data = &read_buf[..amount];
+            while let Some(cut) = cutter.push(data) {
+                let end = position + cut as u64;
+                chunks.push(vec![(file.clone(), start, end - start)]);
+                start = end;
+                position = end;
+                data = &data[cut..];
+            }
+            position += data.len() as u64;
+        }
+
+        if position != file.size {
+            return Err(anyhow!(
+                "{} changed size while chunking: expected {} bytes, read {}",
+                file.relative_filename,
+                file.size,
+                position
+            ));
+        }
+        // The tail, or the whole of an empty file
+        if start < position || position == 0 {
+            chunks.push(vec![(file.clone(), start, position - start)]);
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Splits `files` into chunks of `(file, start, length)` ranges. Backends that
+/// require whole files get packed, rather than split, into chunks.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn organize_chunks(
+    mut files: Vec<VersionFile>,
+    required_single_file: bool,
+    options: &ManifestOptions,
+) -> Vec<Vec<(VersionFile, u64, u64)>> {
+    let total_size = files.iter().map(|v| v.size).sum::<u64>();
+    let chunk_size = options.effective_chunk_size(total_size);
+    let wiggle = options.effective_wiggle(chunk_size);
+    // organize_source_chunks has already rejected patterns that don't compile
+    let keep_whole = options.keep_whole_patterns().unwrap_or_default();
+
+    options.packing.sort(&mut files);
     // Filepath to chunk data
     let mut chunks: Vec<Vec<(VersionFile, u64, u64)>> = Vec::new();
     let mut current_chunk: Vec<(VersionFile, u64, u64)> = Vec::new();
 
-    log_sfn("organizing files into chunks...".to_string());
-
     if required_single_file {
         for version_file in files {
-            if version_file.size >= CHUNK_SIZE {
+            if version_file.size >= chunk_size {
                 let size = version_file.size;
                 chunks.push(vec![(version_file, 0, size)]);
 
@@ -82,11 +1212,18 @@ pub async fn generate_manifest_rusty<T: Fn(String), V: Fn(f32)>(
             let mut current_size = current_chunk.iter().map(|v| v.2).sum::<u64>();
 
             let size = version_file.size;
+
+            // Don't overfill past the wiggle room, start a new chunk instead
+            if !current_chunk.is_empty() && current_size + size > chunk_size + wiggle {
+                chunks.push(std::mem::take(&mut current_chunk));
+                current_size = 0;
+            }
+
             current_chunk.push((version_file, 0, size));
 
             current_size += size;
 
-            if current_size >= CHUNK_SIZE {
+            if current_size >= chunk_size {
                 // Pop current and add, then reset
                 let new_chunk = std::mem::take(&mut current_chunk);
                 chunks.push(new_chunk);
@@ -106,23 +1243,52 @@ pub async fn generate_manifest_rusty<T: Fn(String), V: Fn(f32)>(
 
             let current_size = current_chunk.iter().map(|v| v.2).sum::<u64>();
 
-            if version_file.size + current_size < CHUNK_SIZE {
+            if version_file.size + current_size < chunk_size {
                 let size = version_file.size;
                 current_chunk.push((version_file, 0, size));
 
                 continue;
             }
 
+            // Close enough to full, take the whole file rather than spilling a sliver
+            if version_file.size + current_size <= chunk_size + wiggle {
+                let size = version_file.size;
+                current_chunk.push((version_file, 0, size));
+                chunks.push(std::mem::take(&mut current_chunk));
+
+                continue;
+            }
+
+            // Kept whole: start the next chunk with it instead
+            if options.keeps_whole(&keep_whole, &version_file) {
+                if !current_chunk.is_empty() {
+                    chunks.push(std::mem::take(&mut current_chunk));
+                }
+                let size = version_file.size;
+                if size >= chunk_size {
+                    chunks.push(vec![(version_file, 0, size)]);
+                } else {
+                    current_chunk.push((version_file, 0, size));
+                }
+
+                continue;
+            }
+
             // Fill up current chunk
-            let remaining = CHUNK_SIZE - current_size;
+            let remaining = chunk_size - current_size;
             current_chunk.push((version_file.clone(), 0, remaining));
             chunks.push(std::mem::take(&mut current_chunk));
 
             // This is our offset in our current file
             let mut offset = remaining;
             while offset < version_file.size {
-                let length = CHUNK_SIZE.min(version_file.size - offset);
-                if length == CHUNK_SIZE {
+                let rest = version_file.size - offset;
+                let length = if rest <= chunk_size + wiggle {
+                    rest
+                } else {
+                    chunk_size
+                };
+                if length >= chunk_size {
                     chunks.push(vec![(version_file.clone(), offset, length)]);
                 } else {
                     current_chunk.push((version_file.clone(), offset, length));
@@ -136,6 +1302,381 @@ pub async fn generate_manifest_rusty<T: Fn(String), V: Fn(f32)>(
         chunks.push(current_chunk);
     }
 
+    chunks
+}
+
+/// Warns about an empty source and paths that collide by case or can't be
+/// installed on Windows, erroring instead with `options.fail_on_empty` and
+/// `options.strict_filenames`
+#[cfg(not(target_arch = "wasm32"))]
+fn check_files<T: Fn(String)>(
+    files: &[VersionFile],
+    options: &ManifestOptions,
+    log_sfn: &T,
+) -> anyhow::Result<()> {
+    if files.is_empty() {
+        if options.fail_on_empty {
+            return Err(BackendError::EmptySource.into());
+        }
+        log_sfn("warning: no files to manifest, the manifest will be empty".to_string());
+    }
+
+    for (first, second) in case_insensitive_collisions(files) {
+        log_sfn(format!(
+            "warning: {} and {} differ only by case, and will collide on case-insensitive filesystems",
+            first, second
+        ));
+    }
+
+    let unportable = unportable_filenames(files);
+    if options.strict_filenames {
+        if let Some((filename, problem)) = unportable.first() {
+            return Err(anyhow!(
+                "{} {}, and can't be installed on Windows ({} unportable paths in total)",
+                filename,
+                problem,
+                unportable.len()
+            ));
+        }
+    }
+    for (filename, problem) in unportable {
+        log_sfn(format!(
+            "warning: {} {}, and can't be installed on Windows",
+            filename, problem
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip(options)))]
+fn create_source_backend(
+    dir: &Path,
+    options: &ManifestOptions,
+) -> anyhow::Result<Box<dyn VersionBackend + Send + Sync>> {
+    create_backend_constructor_with_limits(dir, options.archive_limits)?()
+}
+
+/// Adds `options.output_dir` to `options.exclude` if it's inside `dir`, so
+/// chunks written by a previous run aren't manifested themselves
+#[cfg(not(target_arch = "wasm32"))]
+async fn exclude_output_dir(dir: &Path, mut options: ManifestOptions) -> ManifestOptions {
+    let Some(output_dir) = &options.output_dir else {
+        return options;
+    };
+    // Archives can't contain it, and a missing output directory has nothing in it
+    let (Ok(dir), Ok(output_dir)) = (
+        tokio::fs::canonicalize(dir).await,
+        tokio::fs::canonicalize(output_dir).await,
+    ) else {
+        return options;
+    };
+
+    if let Some(relative) = output_dir
+        .strip_prefix(&dir)
+        .ok()
+        .and_then(|v| v.to_str())
+        .filter(|v| !v.is_empty())
+    {
+        options
+            .exclude
+            .push(portable_path(relative, std::path::MAIN_SEPARATOR));
+    }
+
+    options
+}
+
+/// The files `options` select from `backend`
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+async fn list_source_files(
+    backend: &(dyn VersionBackend + Send + Sync),
+    options: &ManifestOptions,
+) -> anyhow::Result<Vec<VersionFile>> {
+    let files = match &options.prefix {
+        Some(prefix) => backend.list_files_under(prefix).await?,
+        None => backend.list_files().await?,
+    };
+    let files: Vec<VersionFile> = files
+        .into_iter()
+        .filter(|v| {
+            options.min_file_size.is_none_or(|min| v.size >= min)
+                && options.max_file_size.is_none_or(|max| v.size <= max)
+                && !options.excludes(&v.relative_filename)
+        })
+        .collect();
+    if options.include_hidden {
+        return Ok(files);
+    }
+
+    let mut visible = Vec::with_capacity(files.len());
+    for file in files {
+        if !backend.is_hidden(&file).await? {
+            visible.push(file);
+        }
+    }
+
+    Ok(visible)
+}
+
+/// Lays out the chunks `generate_manifest_with_options` would produce for `dir`,
+/// without reading any file contents. Chunks have their `files` and `length`
+/// populated, but an empty `checksum` and zeroed `iv`, and the manifest has no
+/// `key`, whatever `encrypt` and `hash` ask for. Output-related options are
+/// ignored.
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(dir = %dir.display()))
+)]
+pub async fn plan_manifest(dir: &Path, options: &ManifestOptions) -> anyhow::Result<Manifest> {
+    options.validate_layout()?;
+
+    let options = &exclude_output_dir(dir, options.clone()).await;
+    let backend = create_source_backend(dir, options)?;
+    let files = list_source_files(&*backend, options).await?;
+
+    let required_single_file = backend.require_whole_files();
+    let chunks = organize_source_chunks(&*backend, files, options, &|_| {}).await?;
+    backend.close().await?;
+    let chunks = chunks
+        .into_iter()
+        .map(|chunk| {
+            let files: Vec<FileEntry> = chunk
+                .into_iter()
+                .map(|(file, start, length)| FileEntry {
+                    filename: file.relative_filename,
+                    start,
+                    length,
+                    permissions: file.permission,
+                    symlink: file.symlink,
+                    sparse: file.is_sparse,
+                })
+                .collect();
+            let chunk_data = ChunkData {
+                length: files.iter().map(|v| v.length).sum(),
+                files,
+                checksum: String::new(),
+                iv: [0u8; 16],
+                compression: options.compression,
+                stored_length: None,
+                stored_checksum: None,
+            };
+            Ok((random_uuid(&*options.random)?.to_string(), chunk_data))
+        })
+        .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+    Ok(Manifest {
+        version: MANIFEST_VERSION.to_string(),
+        size: chunks.values().map(|v| v.length).sum(),
+        chunks,
+        key: None,
+        hash: options.hash,
+        seekable: !required_single_file,
+        metadata: options.metadata.clone(),
+        // Nothing's hashed, so there's no root to commit to
+        merkle_root: None,
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn generate_manifest_rusty<T: Fn(String), V: Fn(f32)>(
+    dir: &Path,
+    progress_sfn: V,
+    log_sfn: T,
+    reader_semaphore: Option<Arc<Semaphore>>,
+) -> anyhow::Result<Manifest> {
+    generate_manifest_with_options(
+        dir,
+        progress_sfn,
+        log_sfn,
+        ManifestOptions {
+            reader_semaphore,
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Diagnostics from a manifest generation run
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone)]
+pub struct GenerationStats {
+    /// Tokio worker threads available to generation
+    pub workers: usize,
+    /// Most chunks being read at the same time
+    pub peak_concurrent_chunks: u64,
+    /// Bytes read from the source, all of which are hashed
+    pub total_read_bytes: u64,
+    pub chunks: usize,
+    /// Wall-clock time generation took
+    pub duration: Duration,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl GenerationStats {
+    /// Average bytes read per second
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.total_read_bytes as f64 / seconds
+    }
+}
+
+/// Counts a chunk as in progress until dropped
+#[cfg(not(target_arch = "wasm32"))]
+struct ActiveChunk(Arc<AtomicU64>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ActiveChunk {
+    fn enter(active: &Arc<AtomicU64>, peak: &AtomicU64) -> Self {
+        let now = active.fetch_add(1, Ordering::Relaxed) + 1;
+        peak.fetch_max(now, Ordering::Relaxed);
+        Self(active.clone())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for ActiveChunk {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn generate_manifest_with_options<T: Fn(String), V: Fn(f32)>(
+    dir: &Path,
+    progress_sfn: V,
+    log_sfn: T,
+    options: ManifestOptions,
+) -> anyhow::Result<Manifest> {
+    let (manifest, _) = generate_manifest_with_stats(dir, progress_sfn, log_sfn, options).await?;
+    Ok(manifest)
+}
+
+/// `generate_manifest_with_options`, also returning statistics about the run
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(dir = %dir.display()))
+)]
+pub async fn generate_manifest_with_stats<T: Fn(String), V: Fn(f32)>(
+    dir: &Path,
+    progress_sfn: V,
+    log_sfn: T,
+    options: ManifestOptions,
+) -> anyhow::Result<(Manifest, GenerationStats)> {
+    let options = exclude_output_dir(dir, options).await;
+    let backend = create_source_backend(dir, &options)?;
+    generate_manifest_with_backend(backend, progress_sfn, log_sfn, options).await
+}
+
+/// `generate_manifest_with_stats` over an already open backend, which is
+/// closed once generation finishes
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn generate_manifest_with_backend<T: Fn(String), V: Fn(f32)>(
+    backend: Box<dyn VersionBackend + Send + Sync>,
+    progress_sfn: V,
+    log_sfn: T,
+    options: ManifestOptions,
+) -> anyhow::Result<(Manifest, GenerationStats)> {
+    let files = list_source_files(&*backend, &options).await?;
+    for skipped in backend.skipped_files() {
+        log_sfn(format!(
+            "warning: skipping {}, which isn't a regular file",
+            skipped
+        ));
+    }
+    generate_manifest_from_files(backend, files, progress_sfn, log_sfn, options).await
+}
+
+/// Generates a manifest of exactly `files` from `backend`, instead of listing
+/// it, for callers that already know what to include. The listing options
+/// (`prefix`, `include_hidden`, `min_file_size`, `max_file_size` and
+/// `exclude`) aren't applied. Sizes must be current, as reading fewer bytes than a file's `size`
+/// is an error. `backend` is closed once generation finishes.
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(skip_all, fields(files = files.len()))
+)]
+pub async fn generate_manifest_from_files<T: Fn(String), V: Fn(f32)>(
+    backend: Box<dyn VersionBackend + Send + Sync>,
+    files: Vec<VersionFile>,
+    progress_sfn: V,
+    log_sfn: T,
+    options: ManifestOptions,
+) -> anyhow::Result<(Manifest, GenerationStats)> {
+    generate_chunks(backend, files, progress_sfn, log_sfn, options, None).await
+}
+
+/// Generates `dir`'s chunks like `generate_manifest_with_options`, yielding
+/// each with its id as soon as it's finished (and written, if `output_dir` or
+/// `sink` is set) rather than once all of them are. A failed generation ends
+/// the stream with its error. Generation only runs while the stream is
+/// polled.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn chunk_result_stream(
+    dir: &Path,
+    options: ManifestOptions,
+) -> impl Stream<Item = anyhow::Result<(String, ChunkData)>> + '_ {
+    use futures::StreamExt as _;
+
+    let (send_chunk, mut recieve_chunk) = tokio::sync::mpsc::unbounded_channel();
+    let generation = async move {
+        let options = exclude_output_dir(dir, options).await;
+        let backend = create_source_backend(dir, &options)?;
+        let files = list_source_files(&*backend, &options).await?;
+        generate_chunks(backend, files, |_| {}, |_| {}, options, Some(send_chunk)).await
+    };
+    let failure = futures::stream::once(generation)
+        .filter_map(|result| futures::future::ready(result.err().map(Err)));
+    let chunks = futures::stream::poll_fn(move |cx| recieve_chunk.poll_recv(cx)).map(Ok);
+
+    futures::stream::select(chunks, failure)
+}
+
+/// `generate_manifest_from_files`, also sending each chunk to `on_chunk` once
+/// it's finished
+#[cfg(not(target_arch = "wasm32"))]
+async fn generate_chunks<T: Fn(String), V: Fn(f32)>(
+    backend: Box<dyn VersionBackend + Send + Sync>,
+    files: Vec<VersionFile>,
+    progress_sfn: V,
+    log_sfn: T,
+    options: ManifestOptions,
+    on_chunk: Option<tokio::sync::mpsc::UnboundedSender<(String, ChunkData)>>,
+) -> anyhow::Result<(Manifest, GenerationStats)> {
+    let started = Instant::now();
+    options.validate_for_generation()?;
+    if let Some(output_dir) = &options.output_dir {
+        tokio::fs::create_dir_all(output_dir).await?;
+    }
+
+    let workers = tokio::runtime::Handle::current().metrics().num_workers();
+    log_sfn(format!(
+        "using {} workers, reading {} files at once",
+        workers,
+        match &options.reader_semaphore {
+            Some(reader_semaphore) => format!("up to {}", reader_semaphore.available_permits()),
+            None => "unlimited".to_string(),
+        }
+    ));
+
+    let manifest_key = options.draw_key()?;
+    let key = manifest_key.unwrap_or(UNKEYED);
+
+    let required_single_file = backend.require_whole_files();
+
+    check_files(&files, &options, &log_sfn)?;
+
+    log_sfn("organizing files into chunks...".to_string());
+
+    let chunks = organize_source_chunks(&*backend, files, &options, &log_sfn).await?;
+
     log_sfn(format!(
         "organized into {} chunks, generating checksums...",
         chunks.len()
@@ -143,116 +1684,836 @@ pub async fn generate_manifest_rusty<T: Fn(String), V: Fn(f32)>(
 
     let manifest: Arc<Mutex<HashMap<String, ChunkData>>> = Arc::new(Mutex::new(HashMap::new()));
     let total_manifest_length = Arc::new(AtomicU64::new(0));
+    let total_read_bytes = Arc::new(AtomicU64::new(0));
+    let active_chunks = Arc::new(AtomicU64::new(0));
+    let peak_concurrent_chunks = Arc::new(AtomicU64::new(0));
 
-    // SAFETY: we .join_all() the futures using this
-    let backend: &'static (dyn VersionBackend + Send + Sync) = unsafe { mem::transmute(&*backend) };
+    // Shared with every task, so the backend outlives them even if this future
+    // is dropped (like a `chunk_result_stream` that isn't read to the end)
+    // while they're still running
+    let shared_backend = Arc::new(backend);
 
     let mut futures: JoinSet<Result<(), anyhow::Error>> = JoinSet::new();
-    let (send_log, mut recieve_log) = tokio::sync::mpsc::channel(16);
+    // Unbounded so chunks never wait on a slow log or progress callback. Each
+    // chunk sends one of each, so they're bounded by the chunk count anyway.
+    let (send_log, mut recieve_log) = tokio::sync::mpsc::unbounded_channel();
+    // Bytes of each finished chunk, kept apart from logs so progress doesn't
+    // depend on how many lines get logged
+    let (send_progress, mut recieve_progress) = tokio::sync::mpsc::unbounded_channel::<u64>();
     let chunks_length = chunks.len();
-    for (index, chunk) in chunks.into_iter().enumerate() {
+    let total_bytes: u64 = chunks.iter().flatten().map(|v| v.2).sum();
+    // Drawn before anything is spawned, in chunk order so a seeded source gives
+    // reproducible manifests, and so a failing source can't leave tasks running
+    let mut identities = Vec::with_capacity(chunks_length);
+    for _ in 0..chunks_length {
+        let uuid = random_uuid(&*options.random)?.to_string();
+        let mut iv = [0u8; 16];
+        options.random.fill(&mut iv)?;
+        identities.push((uuid, iv));
+    }
+    for (index, (chunk, (uuid, iv))) in chunks.into_iter().zip(identities).enumerate() {
+        let backend = shared_backend.clone();
         let send_log = send_log.clone();
+        let send_progress = send_progress.clone();
+        let on_chunk = on_chunk.clone();
         let total_manifest_length = total_manifest_length.clone();
         let manifest = manifest.clone();
-        let reader_semaphore = reader_semaphore.clone();
-        futures.spawn(async move {
-            let mut read_buf = vec![0u8; 1024 * 1024 * 8];
+        let reader_semaphore = options.reader_semaphore.clone();
+        let hash_algorithm = options.hash;
+        let output_dir = options.output_dir.clone();
+        let sink = options.sink.clone();
+        let chunk_id_fn = options.chunk_id.clone();
+        let encrypt = options.encrypt;
+        let content_addressed = options.content_addressed;
+        let compression = options.compression;
+        let read_buffer_size = options.read_buffer_size;
+        let total_read_bytes = total_read_bytes.clone();
+        let active_chunks = active_chunks.clone();
+        let peak_concurrent_chunks = peak_concurrent_chunks.clone();
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "chunk",
+            index,
+            files = chunk.len(),
+            size = chunk.iter().map(|v| v.2).sum::<u64>()
+        );
+        let future = async move {
+            let _active = ActiveChunk::enter(&active_chunks, &peak_concurrent_chunks);
+            let mut read_buf = vec![0u8; read_buffer_size];
 
-            let uuid = uuid::Uuid::new_v4().to_string();
-            let mut hasher = Sha256::new();
+            let mut hasher = ChunkHasher::new(hash_algorithm, &key);
 
-            let mut iv = [0u8; 16];
-            getrandom::fill(&mut iv).map_err(|err| anyhow!("failed to generate IV: {:?}", err))?;
             let mut chunk_data = ChunkData {
                 files: Vec::new(),
                 checksum: String::new(),
                 iv,
+                length: 0,
+                compression,
+                stored_length: None,
+                stored_checksum: None,
             };
 
+            // Moved into place once their checksum (and so id) is known
+            let output_path = output_dir.as_ref().map(|output_dir| {
+                if content_addressed || chunk_id_fn.is_some() {
+                    output_dir.join(format!("{}.partial", uuid))
+                } else {
+                    output_dir.join(&uuid)
+                }
+            });
+            let mut writer = open_chunk_writer(
+                output_path.as_deref(),
+                sink.as_ref(),
+                compression,
+                encrypt.then(|| chunk_cipher(&key, &iv)),
+                hash_algorithm,
+                &key,
+            )
+            .await
+            .with_context(|| format!("creating chunk {}", index))?;
+
             let mut chunk_length = 0;
 
             for (file, start, length) in chunk {
+                if file.symlink.is_some() {
+                    chunk_data.files.push(FileEntry {
+                        filename: file.relative_filename,
+                        start: 0,
+                        length: 0,
+                        permissions: file.permission,
+                        symlink: file.symlink,
+                        sparse: false,
+                    });
+                    continue;
+                }
+
                 let permit = if let Some(reader_semaphore) = &reader_semaphore {
                     Some(reader_semaphore.acquire().await?)
                 } else {
                     None
                 };
 
-                let mut reader = backend.reader(&file, start, start + length).await?;
+                let mut reader = HashingReader::new(
+                    backend
+                        .reader(&file, start, Some(start + length))
+                        .await
+                        .with_context(|| {
+                            format!(
+                                "opening {} at {} for chunk {}",
+                                file.relative_filename, start, index
+                            )
+                        })?,
+                    hasher,
+                );
 
-                let mut total = 0;
+                let mut total = 0u64;
 
                 loop {
-                    let amount = reader.read(&mut read_buf).await?;
+                    let amount = reader.read(&mut read_buf).await.with_context(|| {
+                        format!(
+                            "reading {} at {} for chunk {}",
+                            file.relative_filename,
+                            start + total,
+                            index
+                        )
+                    })?;
                     if amount == 0 {
                         break;
                     }
-                    total += amount;
-                    hasher.update(&read_buf[0..amount]);
+                    total += amount as u64;
+                    total_read_bytes.fetch_add(amount as u64, Ordering::Relaxed);
+
+                    if let Some(writer) = &mut writer {
+                        writer
+                            .write(&mut read_buf[0..amount])
+                            .await
+                            .with_context(|| {
+                                format!(
+                                    "writing chunk {} from {} at {}",
+                                    index,
+                                    file.relative_filename,
+                                    start + total - amount as u64
+                                )
+                            })?;
+                    }
                 }
+                hasher = reader.into_hasher();
 
-                if total as u64 > length {
-                    panic!("read too much: target {}, got {}", length, total);
+                // The file changed size since it was listed, so the checksum
+                // wouldn't cover the bytes the manifest records
+                if total < length {
+                    return Err(anyhow!(
+                        "{} shrank while generating: expected {} bytes from offset {}, read {}",
+                        file.relative_filename,
+                        length,
+                        start,
+                        total
+                    ));
+                }
+                if total > length {
+                    return Err(anyhow!(
+                        "{} grew while generating: expected {} bytes from offset {}, read {}",
+                        file.relative_filename,
+                        length,
+                        start,
+                        total
+                    ));
                 }
 
                 chunk_length += length;
 
                 chunk_data.files.push(FileEntry {
                     filename: file.relative_filename,
-                    start: start.try_into().unwrap(),
-                    length: length.try_into().unwrap(),
+                    start,
+                    length,
                     permissions: file.permission,
+                    symlink: None,
+                    sparse: file.is_sparse,
                 });
 
                 drop(permit);
             }
 
-            send_log
-                .send(format!(
-                    "created chunk of size {} ({}b) from {} files (index {})",
-                    format_size(chunk_length, BINARY),
-                    chunk_length,
-                    chunk_data.files.len(),
-                    index
-                ))
-                .await?;
+            chunk_data.length = chunk_length;
+            chunk_data.checksum = hasher.finalize();
+            let chunk_id = name_chunk(chunk_id_fn.as_ref(), uuid, index, &chunk_data)?;
+
+            if let Some(writer) = writer {
+                let (stored_length, stored_checksum) = writer
+                    .finish(&chunk_id)
+                    .await
+                    .with_context(|| format!("finishing chunk {}", index))?;
+                chunk_data.stored_length = Some(stored_length);
+                chunk_data.stored_checksum = Some(stored_checksum);
+            }
+
+            send_log.send(format!(
+                "created chunk of size {} ({}b) from {} files (index {})",
+                format_size(chunk_length, BINARY),
+                chunk_length,
+                chunk_data.files.len(),
+                index
+            ))?;
 
             total_manifest_length.fetch_add(chunk_length, Ordering::Relaxed);
 
-            let hash: String = hasher.finalize().encode_hex();
-            chunk_data.checksum = hash;
+            if let (Some(output_dir), Some(output_path)) = (&output_dir, &output_path) {
+                place_chunk(
+                    output_dir,
+                    output_path,
+                    content_addressed,
+                    &chunk_id,
+                    &chunk_data,
+                )
+                .await
+                .with_context(|| format!("storing chunk {} as {}", index, chunk_id))?;
+            }
+
             {
                 let mut manifest_lock = manifest.lock().await;
-                manifest_lock.insert(uuid, chunk_data);
+                if manifest_lock.contains_key(&chunk_id) {
+                    return Err(anyhow!("chunk id {} was given to two chunks", chunk_id));
+                }
+                if let Some(on_chunk) = &on_chunk {
+                    on_chunk.send((chunk_id.clone(), chunk_data.clone()))?;
+                }
+                manifest_lock.insert(chunk_id, chunk_data);
             };
+            send_progress.send(chunk_length)?;
 
             Ok(())
-        });
+        };
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(future, span);
+        futures.spawn(future);
     }
     drop(send_log);
-    join!(
+    drop(send_progress);
+    drop(on_chunk);
+    let (_, _, results) = join!(
         async move {
-            let mut current_progress = 0f32;
-            let total_progress = chunks_length as f32;
             while let Some(message) = recieve_log.recv().await {
                 log_sfn(message);
-                current_progress += 1.0f32;
-                progress_sfn((current_progress / total_progress) * 100.0f32)
+            }
+        },
+        async move {
+            let (mut finished_bytes, mut finished_chunks) = (0u64, 0usize);
+            while let Some(chunk_bytes) = recieve_progress.recv().await {
+                finished_bytes += chunk_bytes;
+                finished_chunks += 1;
+                // Weighted by bytes, or by chunks if they're all empty
+                progress_sfn(if total_bytes == 0 {
+                    finished_chunks as f32 / chunks_length as f32 * 100.0f32
+                } else {
+                    finished_bytes as f32 / total_bytes as f32 * 100.0f32
+                });
             }
         },
         futures.join_all()
     );
+    // Every task has been joined, so holds no clone any more
+    let closed = match Arc::into_inner(shared_backend) {
+        Some(backend) => backend.close().await,
+        None => Err(anyhow!("backend still in use after generation")),
+    };
+    for result in results {
+        result?;
+    }
+    closed?;
 
     let manifest = manifest.lock().await;
     let manifest = manifest.clone();
 
-    let mut key = [0u8; 16];
-    getrandom::fill(&mut key).map_err(|err| anyhow!("failed to generate key: {:?}", err))?;
-
-    Ok(Manifest {
-        version: "2".to_string(),
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION.to_string(),
         chunks: manifest,
         size: total_manifest_length.fetch_add(0, Ordering::Relaxed),
-        key,
+        key: manifest_key,
+        hash: options.hash,
+        seekable: !required_single_file,
+        metadata: options.metadata.clone(),
+        merkle_root: None,
+    };
+    if options.merkle_root {
+        manifest.merkle_root = Some(manifest.compute_merkle_root());
+    }
+    let stats = GenerationStats {
+        workers,
+        peak_concurrent_chunks: peak_concurrent_chunks.load(Ordering::Relaxed),
+        total_read_bytes: total_read_bytes.load(Ordering::Relaxed),
+        chunks: chunks_length,
+        duration: started.elapsed(),
+    };
+
+    Ok((manifest, stats))
+}
+
+/// The id from `chunk_id_fn` for a hashed chunk, or `uuid` without one
+#[cfg(not(target_arch = "wasm32"))]
+fn name_chunk(
+    chunk_id_fn: Option<&ChunkIdFn>,
+    uuid: String,
+    index: usize,
+    chunk: &ChunkData,
+) -> anyhow::Result<String> {
+    let Some(chunk_id_fn) = chunk_id_fn else {
+        return Ok(uuid);
+    };
+    let chunk_id = chunk_id_fn(&ChunkContext {
+        index,
+        checksum: &chunk.checksum,
+        length: chunk.length,
+    });
+    // Ids name files in `output_dir`
+    if chunk_id.is_empty() || chunk_id == "." || chunk_id == ".." || chunk_id.contains(['/', '\\'])
+    {
+        return Err(anyhow!("invalid chunk id: {:?}", chunk_id));
+    }
+
+    Ok(chunk_id)
+}
+
+/// Moves a chunk written to a `.partial` file to where it belongs: its
+/// checksum's place in a `ShardedLayout`, or `output_dir/<chunk id>`
+#[cfg(not(target_arch = "wasm32"))]
+async fn place_chunk(
+    output_dir: &Path,
+    output_path: &Path,
+    content_addressed: bool,
+    chunk_id: &str,
+    chunk: &ChunkData,
+) -> anyhow::Result<()> {
+    if content_addressed {
+        ShardedLayout::new(output_dir)
+            .store(output_path, &chunk.checksum)
+            .await?;
+    } else if output_path != output_dir.join(chunk_id) {
+        tokio::fs::rename(output_path, output_dir.join(chunk_id)).await?;
+    }
+
+    Ok(())
+}
+
+/// A writer for a chunk's stored bytes, if they're stored anywhere
+#[cfg(not(target_arch = "wasm32"))]
+async fn open_chunk_writer(
+    output_path: Option<&Path>,
+    sink: Option<&Arc<dyn ChunkSink>>,
+    compression: Compression,
+    cipher: Option<Aes128Ctr>,
+    hash: HashAlgorithm,
+    key: &[u8; 16],
+) -> anyhow::Result<Option<ChunkWriter>> {
+    if let Some(output_path) = output_path {
+        return Ok(Some(
+            ChunkWriter::create(output_path, compression, cipher, hash, key)
+                .await
+                .with_context(|| format!("creating {}", output_path.display()))?,
+        ));
+    }
+    match sink {
+        Some(sink) => Ok(Some(ChunkWriter::to_sink(
+            sink.clone(),
+            compression,
+            cipher,
+            hash,
+            key,
+        )?)),
+        None => Ok(None),
+    }
+}
+
+/// Regenerates `manifest` with `chunk_size` chunks, reading the files it
+/// lists from `backend` without listing it again. The source must still be
+/// available and unchanged, as every chunk is re-hashed. Keeps the manifest's
+/// hash algorithm and metadata; use `generate_manifest_from_files` with
+/// `Manifest::version_files` for other options.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn rechunk_manifest(
+    manifest: &Manifest,
+    backend: Box<dyn VersionBackend + Send + Sync>,
+    chunk_size: u64,
+) -> anyhow::Result<Manifest> {
+    let (manifest, _) = generate_manifest_from_files(
+        backend,
+        manifest.version_files(),
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            chunk_size,
+            hash: manifest.hash,
+            metadata: manifest.metadata.clone(),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    Ok(manifest)
+}
+
+/// A manifest of a new version, split into the chunks a client with the old
+/// version has to download and the ones it already has
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PatchManifest {
+    pub manifest: Manifest,
+    /// Ids of `manifest`'s chunks with contents the old version has no chunk
+    /// of, sorted
+    pub new_chunks: Vec<String>,
+    /// Each of `manifest`'s other chunks, by id, with the id of the old
+    /// version's chunk with the same checksum
+    pub existing_chunks: HashMap<String, String>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl PatchManifest {
+    /// Bytes a client with the old version downloads, counted like
+    /// `Manifest::download_size`
+    pub fn download_size(&self) -> u64 {
+        self.new_chunks
+            .iter()
+            .filter_map(|id| self.manifest.chunks.get(id))
+            .map(|chunk| chunk.stored_length.unwrap_or(chunk.length))
+            .sum()
+    }
+}
+
+/// Generates a manifest of `new_dir` like `generate_manifest_with_options`,
+/// marking which of its chunks `old_manifest` already has a chunk with the
+/// same checksum of. Chunks are hashed with `old_manifest`'s algorithm so
+/// checksums compare, which can't be keyed. `old_dir` is the old version
+/// `old_manifest` was generated from, and is only listed, to check every file
+/// it records is still there at the same size.
+///
+/// Chunks only match when their contents line up exactly, so
+/// `ChunkingStrategy::ContentDefined` finds far more than fixed-size chunking,
+/// where one changed file moves the boundaries of the chunks packed after it.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn generate_patch_manifest<T: Fn(String), V: Fn(f32)>(
+    old_dir: &Path,
+    new_dir: &Path,
+    old_manifest: &Manifest,
+    progress_sfn: V,
+    log_sfn: T,
+    options: ManifestOptions,
+) -> anyhow::Result<PatchManifest> {
+    if old_manifest.hash == HashAlgorithm::HmacSha256 {
+        return Err(anyhow!(
+            "keyed checksums can't be compared across manifests, so the old manifest can't be patched"
+        ));
+    }
+
+    let old_backend = create_source_backend(old_dir, &options)?;
+    let listed = old_backend.list_files().await;
+    old_backend.close().await?;
+    let listed: HashMap<String, u64> = listed?
+        .into_iter()
+        .map(|v| (v.relative_filename, v.size))
+        .collect();
+    for file in old_manifest.version_files() {
+        match listed.get(&file.relative_filename) {
+            Some(size) if *size == file.size => {}
+            Some(size) => {
+                return Err(anyhow!(
+                    "old manifest doesn't match {}: {} is {} bytes, but {} in the manifest",
+                    old_dir.display(),
+                    file.relative_filename,
+                    size,
+                    file.size
+                ))
+            }
+            None => {
+                return Err(anyhow!(
+                    "old manifest doesn't match {}: {} is missing",
+                    old_dir.display(),
+                    file.relative_filename
+                ))
+            }
+        }
+    }
+
+    let options = ManifestOptions {
+        hash: old_manifest.hash,
+        ..options
+    };
+    let manifest = generate_manifest_with_options(new_dir, progress_sfn, &log_sfn, options).await?;
+
+    let old_chunks: HashMap<&str, &str> = old_manifest
+        .chunks
+        .iter()
+        .map(|(id, chunk)| (chunk.checksum.as_str(), id.as_str()))
+        .collect();
+    let mut new_chunks = Vec::new();
+    let mut existing_chunks = HashMap::new();
+    for (id, chunk) in &manifest.chunks {
+        match old_chunks.get(chunk.checksum.as_str()) {
+            Some(old_id) => {
+                existing_chunks.insert(id.clone(), old_id.to_string());
+            }
+            None => new_chunks.push(id.clone()),
+        }
+    }
+    new_chunks.sort();
+    log_sfn(format!(
+        "{} of {} chunks are new",
+        new_chunks.len(),
+        manifest.chunks.len()
+    ));
+
+    Ok(PatchManifest {
+        manifest,
+        new_chunks,
+        existing_chunks,
     })
 }
+
+/// A chunk being filled by `generate_manifest_from_reader`
+#[cfg(not(target_arch = "wasm32"))]
+struct StreamChunk {
+    id: String,
+    data: ChunkData,
+    hasher: ChunkHasher,
+    writer: Option<ChunkWriter>,
+    output_path: Option<PathBuf>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl StreamChunk {
+    async fn create(options: &ManifestOptions, key: &[u8; 16]) -> anyhow::Result<Self> {
+        let id = random_uuid(&*options.random)?.to_string();
+        let mut iv = [0u8; 16];
+        options.random.fill(&mut iv)?;
+
+        // Moved into place once their checksum (and so id) is known
+        let output_path = options.output_dir.as_ref().map(|output_dir| {
+            if options.content_addressed || options.chunk_id.is_some() {
+                output_dir.join(format!("{}.partial", id))
+            } else {
+                output_dir.join(&id)
+            }
+        });
+        let writer = open_chunk_writer(
+            output_path.as_deref(),
+            options.sink.as_ref(),
+            options.compression,
+            options.encrypt.then(|| chunk_cipher(key, &iv)),
+            options.hash,
+            key,
+        )
+        .await?;
+
+        Ok(Self {
+            id,
+            data: ChunkData {
+                files: Vec::new(),
+                checksum: String::new(),
+                iv,
+                length: 0,
+                compression: options.compression,
+                stored_length: None,
+                stored_checksum: None,
+            },
+            hasher: ChunkHasher::new(options.hash, key),
+            writer,
+            output_path,
+        })
+    }
+
+    /// `data` is clobbered if encrypting
+    async fn write(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        self.hasher.update(data);
+        self.data.length += data.len() as u64;
+        if let Some(writer) = &mut self.writer {
+            writer
+                .write(data)
+                .await
+                .with_context(|| format!("writing chunk {}", self.id))?;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(
+        mut self,
+        options: &ManifestOptions,
+        index: usize,
+    ) -> anyhow::Result<(String, ChunkData)> {
+        self.data.checksum = self.hasher.finalize();
+        let chunk_id = name_chunk(options.chunk_id.as_ref(), self.id, index, &self.data)?;
+        if let Some(writer) = self.writer {
+            let (stored_length, stored_checksum) = writer.finish(&chunk_id).await?;
+            self.data.stored_length = Some(stored_length);
+            self.data.stored_checksum = Some(stored_checksum);
+        }
+
+        if let (Some(output_dir), Some(output_path)) = (&options.output_dir, &self.output_path) {
+            place_chunk(
+                output_dir,
+                output_path,
+                options.content_addressed,
+                &chunk_id,
+                &self.data,
+            )
+            .await?;
+        }
+
+        Ok((chunk_id, self.data))
+    }
+}
+
+/// Errors if a streamed file's contents didn't match the size in its header
+#[cfg(not(target_arch = "wasm32"))]
+fn check_streamed_length(file: &VersionFile, read: u64) -> anyhow::Result<()> {
+    if read != file.size {
+        return Err(anyhow!(
+            "{} {} while streaming: its header claims {} bytes, read {}",
+            file.relative_filename,
+            if read < file.size { "shrank" } else { "grew" },
+            file.size,
+            read
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn insert_streamed_chunk(
+    chunks: &mut HashMap<String, ChunkData>,
+    chunk_id: String,
+    chunk: ChunkData,
+) -> anyhow::Result<()> {
+    if chunks.contains_key(&chunk_id) {
+        return Err(anyhow!("chunk id {} was given to two chunks", chunk_id));
+    }
+    chunks.insert(chunk_id, chunk);
+
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn log_stream_chunk<T: Fn(String)>(log_sfn: &T, chunk: &ChunkData, index: usize) {
+    log_sfn(format!(
+        "created chunk of size {} ({}b) from {} files (index {})",
+        format_size(chunk.length, BINARY),
+        chunk.length,
+        chunk.files.len(),
+        index
+    ));
+}
+
+/// Generates a manifest of an archive (tar, zip, ...) read once from `source`,
+/// like stdin in `build | droplet generate -`, without staging it to disk.
+/// Files are packed whole into chunks in archive order, so `packing`,
+/// `target_chunks`, `keep_whole_under`, `keep_whole` and content-defined
+/// chunking don't apply. Streams have no file attributes, so without
+/// `include_hidden` entries are left out as hidden by the Unix rule, a
+/// dotfile anywhere in their path, as when listing a directory there. With
+/// `strict_filenames`, generation stops at the first unportable path, before
+/// any of its contents are written, and `output_dir` is only created once
+/// there's a chunk to write to it.
+/// Returns the manifest and run statistics like `generate_manifest_with_stats`.
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+pub async fn generate_manifest_from_reader<R, T>(
+    source: R,
+    log_sfn: T,
+    options: ManifestOptions,
+) -> anyhow::Result<(Manifest, GenerationStats)>
+where
+    R: std::io::Read + Send + 'static,
+    T: Fn(String),
+{
+    let started = Instant::now();
+    options.validate_for_generation()?;
+    if options.target_chunks.is_some() {
+        return Err(anyhow!(
+            "target chunk counts need the total size up front, which a stream doesn't have"
+        ));
+    }
+    if let ChunkingStrategy::ContentDefined { .. } = options.chunking {
+        log_sfn(
+            "warning: streamed archives are read as whole files, using fixed-size chunks instead of content-defined ones"
+                .to_string(),
+        );
+    }
+
+    let manifest_key = options.draw_key()?;
+    let key = manifest_key.unwrap_or(UNKEYED);
+    let chunk_size = options.chunk_size;
+    let wiggle = options.effective_wiggle(chunk_size);
+
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+    let limits = options.archive_limits;
+    let producer = tokio::task::spawn_blocking(move || stream_archive(source, limits, sender));
+
+    let mut chunks = HashMap::new();
+    let mut files = Vec::new();
+    let mut current: Option<StreamChunk> = None;
+    // The included file being read, and how many of its bytes have been
+    let mut reading: Option<(VersionFile, u64)> = None;
+    let mut total_read_bytes = 0u64;
+    while let Some(entry) = receiver.recv().await {
+        match entry {
+            StreamEntry::File(file) => {
+                if let Some((previous, read)) = reading.take() {
+                    check_streamed_length(&previous, read)?;
+                }
+                let included = options
+                    .prefix
+                    .as_ref()
+                    .is_none_or(|prefix| Path::new(&file.relative_filename).starts_with(prefix))
+                    && options.min_file_size.is_none_or(|min| file.size >= min)
+                    && options.max_file_size.is_none_or(|max| file.size <= max)
+                    && !options.excludes(&file.relative_filename)
+                    && (options.include_hidden || !has_dotfile_component(&file.relative_filename));
+                if !included {
+                    continue;
+                }
+                // Checked as each file arrives, as chunks are written as they go
+                if options.strict_filenames {
+                    if let Some((filename, problem)) =
+                        unportable_filenames(std::slice::from_ref(&file)).pop()
+                    {
+                        return Err(anyhow!(
+                            "{} {}, and can't be installed on Windows",
+                            filename,
+                            problem
+                        ));
+                    }
+                }
+
+                // Whole-file packing: don't overfill past the wiggle room
+                let full = current.as_ref().is_some_and(|chunk| {
+                    chunk.data.length >= chunk_size
+                        || chunk.data.length + file.size > chunk_size + wiggle
+                        || chunk.data.files.len() >= MAX_FILE_COUNT
+                });
+                if full {
+                    let (chunk_id, chunk_data) = current
+                        .take()
+                        .unwrap()
+                        .finish(&options, chunks.len())
+                        .await?;
+                    log_stream_chunk(&log_sfn, &chunk_data, chunks.len());
+                    insert_streamed_chunk(&mut chunks, chunk_id, chunk_data)?;
+                }
+                let chunk = match &mut current {
+                    Some(chunk) => chunk,
+                    None => {
+                        // Only created for the first chunk, so empty sources leave nothing behind
+                        if let (Some(output_dir), true) = (&options.output_dir, chunks.is_empty()) {
+                            tokio::fs::create_dir_all(output_dir).await?;
+                        }
+                        current.insert(StreamChunk::create(&options, &key).await?)
+                    }
+                };
+                if let Some(max_chunk_bytes) = options.max_chunk_bytes {
+                    check_chunk_length(
+                        chunk.data.length + file.size,
+                        max_chunk_bytes,
+                        std::iter::once(file.relative_filename.as_str()),
+                    )?;
+                }
+
+                chunk.data.files.push(FileEntry {
+                    filename: file.relative_filename.clone(),
+                    start: 0,
+                    length: file.size,
+                    permissions: file.permission,
+                    symlink: file.symlink.clone(),
+                    sparse: file.is_sparse,
+                });
+                files.push(file.clone());
+                reading = Some((file, 0));
+            }
+            StreamEntry::Data(mut data) => {
+                // Contents of a left out file
+                let (Some((file, read)), Some(chunk)) = (&mut reading, &mut current) else {
+                    continue;
+                };
+                *read += data.len() as u64;
+                if *read > file.size {
+                    check_streamed_length(file, *read)?;
+                }
+                total_read_bytes += data.len() as u64;
+                chunk.write(&mut data).await?;
+            }
+        }
+    }
+    producer.await??;
+    if let Some((file, read)) = reading.take() {
+        check_streamed_length(&file, read)?;
+    }
+    check_files(&files, &options, &log_sfn)?;
+    if let Some(chunk) = current.take() {
+        let (chunk_id, chunk_data) = chunk.finish(&options, chunks.len()).await?;
+        log_stream_chunk(&log_sfn, &chunk_data, chunks.len());
+        insert_streamed_chunk(&mut chunks, chunk_id, chunk_data)?;
+    }
+
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION.to_string(),
+        size: chunks.values().map(|v| v.length).sum(),
+        chunks,
+        key: manifest_key,
+        hash: options.hash,
+        seekable: false,
+        metadata: options.metadata.clone(),
+        merkle_root: None,
+    };
+    if options.merkle_root {
+        manifest.merkle_root = Some(manifest.compute_merkle_root());
+    }
+    let stats = GenerationStats {
+        // Chunks are read one after another, as the stream arrives
+        workers: 1,
+        peak_concurrent_chunks: manifest.chunks.len().min(1) as u64,
+        total_read_bytes,
+        chunks: manifest.chunks.len(),
+        duration: started.elapsed(),
+    };
+
+    Ok((manifest, stats))
+}
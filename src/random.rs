@@ -0,0 +1,55 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+
+/// Source of the random bytes used for manifest keys, IVs, chunk ids and
+/// certificate serials. Swappable so tests can be reproducible.
+pub trait RandomSource: Send + Sync {
+    fn fill(&self, dest: &mut [u8]) -> anyhow::Result<()>;
+}
+
+/// The operating system's RNG
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemRandomSource;
+
+impl RandomSource for SystemRandomSource {
+    fn fill(&self, dest: &mut [u8]) -> anyhow::Result<()> {
+        getrandom::fill(dest).map_err(|err| anyhow!("failed to generate random bytes: {:?}", err))
+    }
+}
+
+/// Deterministic bytes expanded from a seed. Not for production use.
+pub struct SeededRandomSource {
+    output: Mutex<blake3::OutputReader>,
+}
+
+impl SeededRandomSource {
+    pub fn new(seed: u64) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&seed.to_le_bytes());
+        Self {
+            output: Mutex::new(hasher.finalize_xof()),
+        }
+    }
+}
+
+impl RandomSource for SeededRandomSource {
+    fn fill(&self, dest: &mut [u8]) -> anyhow::Result<()> {
+        self.output
+            .lock()
+            .map_err(|_| anyhow!("random source poisoned"))?
+            .fill(dest);
+        Ok(())
+    }
+}
+
+pub fn system_random() -> Arc<dyn RandomSource> {
+    Arc::new(SystemRandomSource)
+}
+
+/// A random v4 UUID, as used for chunk ids
+pub fn random_uuid(random: &dyn RandomSource) -> anyhow::Result<uuid::Uuid> {
+    let mut bytes = [0u8; 16];
+    random.fill(&mut bytes)?;
+    Ok(uuid::Builder::from_random_bytes(bytes).into_uuid())
+}
@@ -1,21 +1,94 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{anyhow, Context as _};
 use rcgen::{
-    CertificateParams, DistinguishedName, Error, IsCa, KeyPair, KeyUsagePurpose, PublicKeyData,
-    SubjectPublicKeyInfo,
+    Certificate, CertificateParams, DistinguishedName, Error, IsCa, KeyPair, KeyUsagePurpose,
+    PublicKeyData, SerialNumber, SubjectPublicKeyInfo,
 };
 use ring::rand::SystemRandom;
 use ring::signature::{EcdsaKeyPair, VerificationAlgorithm};
 use time::{Duration, OffsetDateTime};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::ParsedExtension;
 use x509_parser::parse_x509_certificate;
 use x509_parser::pem::Pem;
 
+use crate::random::{RandomSource, SystemRandomSource};
+
+/// Subject fields for generated certificates
+#[derive(Clone, Debug)]
+pub struct DistinguishedNameParams {
+    /// Defaults to "Drop Root Server" for root CAs, and the client id for clients
+    pub common_name: Option<String>,
+    pub organization: String,
+    pub organizational_unit: Option<String>,
+    pub country: Option<String>,
+    pub locality: Option<String>,
+}
+
+impl Default for DistinguishedNameParams {
+    fn default() -> Self {
+        Self {
+            common_name: None,
+            organization: "Drop".to_string(),
+            organizational_unit: None,
+            country: None,
+            locality: None,
+        }
+    }
+}
+
+impl DistinguishedNameParams {
+    fn to_distinguished_name(&self, default_common_name: &str) -> DistinguishedName {
+        let mut name = DistinguishedName::new();
+        name.push(
+            rcgen::DnType::CommonName,
+            self.common_name.as_deref().unwrap_or(default_common_name),
+        );
+        name.push(rcgen::DnType::OrganizationName, self.organization.as_str());
+        if let Some(organizational_unit) = &self.organizational_unit {
+            name.push(
+                rcgen::DnType::OrganizationalUnitName,
+                organizational_unit.as_str(),
+            );
+        }
+        if let Some(country) = &self.country {
+            name.push(rcgen::DnType::CountryName, country.as_str());
+        }
+        if let Some(locality) = &self.locality {
+            name.push(rcgen::DnType::LocalityName, locality.as_str());
+        }
+
+        name
+    }
+}
+
 pub fn generate_root_ca() -> Result<Vec<String>, rcgen::Error> {
-    let mut params = CertificateParams::default();
+    generate_root_ca_with_name(&DistinguishedNameParams::default())
+}
+
+pub fn generate_root_ca_with_name(
+    name: &DistinguishedNameParams,
+) -> Result<Vec<String>, rcgen::Error> {
+    let (certificate, key_pair) = build_root_ca(name)?;
+
+    // Returns certificate, then private key
+    Ok(vec![certificate.pem(), key_pair.serialize_pem()])
+}
+
+/// Like `generate_root_ca_with_name`, but returns the certificate and PKCS #8
+/// private key as DER
+pub fn generate_root_ca_der(name: &DistinguishedNameParams) -> Result<Vec<Vec<u8>>, rcgen::Error> {
+    let (certificate, key_pair) = build_root_ca(name)?;
+
+    // Returns certificate, then private key
+    Ok(vec![certificate.der().to_vec(), key_pair.serialize_der()])
+}
 
-    let mut name = DistinguishedName::new();
-    name.push(rcgen::DnType::CommonName, "Drop Root Server");
-    name.push(rcgen::DnType::OrganizationName, "Drop");
+fn build_root_ca(name: &DistinguishedNameParams) -> Result<(Certificate, KeyPair), Error> {
+    let mut params = CertificateParams::default();
 
-    params.distinguished_name = name;
+    params.distinguished_name = name.to_distinguished_name("Drop Root Server");
 
     params.not_before = OffsetDateTime::now_utc();
     params.not_after = OffsetDateTime::now_utc()
@@ -33,37 +106,401 @@ pub fn generate_root_ca() -> Result<Vec<String>, rcgen::Error> {
     let key_pair = KeyPair::generate()?;
     let certificate = CertificateParams::self_signed(params, &key_pair)?;
 
-    // Returns certificate, then private key
-    Ok(vec![certificate.pem(), key_pair.serialize_pem()])
+    Ok((certificate, key_pair))
 }
 
-pub fn generate_client_certificate(
+/// A fresh root CA and a client certificate issued by it, everything a Drop
+/// server and its first client need
+pub struct PkiBundle {
+    pub ca_certificate: String,
+    pub ca_private_key: String,
+    pub client: ClientCert,
+}
+
+/// Generates a root CA with the default name, and issues `client_id` a
+/// client certificate signed by it
+pub fn bootstrap_pki(client_id: String, _client_name: String) -> Result<PkiBundle, Error> {
+    let (root_ca, root_key_pair) = build_root_ca(&DistinguishedNameParams::default())?;
+    let client = issue_client_certificate(
+        client_id,
+        &DistinguishedNameParams::default(),
+        &root_ca,
+        &root_key_pair,
+        &SystemRandomSource,
+    )?;
+
+    Ok(PkiBundle {
+        ca_certificate: root_ca.pem(),
+        ca_private_key: root_key_pair.serialize_pem(),
+        client,
+    })
+}
+
+/// A client certificate issued by `generate_client_certificates`
+pub struct ClientCert {
+    pub client_id: String,
+    pub certificate: String,
+    pub private_key: String,
+    /// Hex-encoded serial number
+    pub serial: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertStatus {
+    Good,
+    Revoked,
+    /// Never issued by this store
+    Unknown,
+}
+
+/// In-memory record of issued and revoked client certificates, by serial, for
+/// answering status queries without distributing a revocation list
+#[derive(Debug, Default, Clone)]
+pub struct CertStatusStore {
+    issued: HashSet<String>,
+    revoked: HashSet<String>,
+}
+
+impl CertStatusStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serials are compared as case-insensitive hex, like `ClientCert::serial`
+    fn normalize(serial: &str) -> String {
+        serial.to_ascii_lowercase()
+    }
+
+    pub fn record_issued(&mut self, serial: &str) {
+        self.issued.insert(Self::normalize(serial));
+    }
+
+    /// Returns false if `serial` was never issued
+    pub fn revoke(&mut self, serial: &str) -> bool {
+        let serial = Self::normalize(serial);
+        if !self.issued.contains(&serial) {
+            return false;
+        }
+        self.revoked.insert(serial);
+
+        true
+    }
+
+    pub fn check_status(&self, serial: &str) -> CertStatus {
+        let serial = Self::normalize(serial);
+        if self.revoked.contains(&serial) {
+            CertStatus::Revoked
+        } else if self.issued.contains(&serial) {
+            CertStatus::Good
+        } else {
+            CertStatus::Unknown
+        }
+    }
+}
+
+/// Random positive 128-bit serial number
+fn random_serial(random: &dyn RandomSource) -> Result<SerialNumber, Error> {
+    let mut serial = [0u8; 16];
+    random
+        .fill(&mut serial)
+        .map_err(|_| Error::RingUnspecified)?;
+    // Positive, and without a leading zero byte DER would strip
+    serial[0] = (serial[0] & 0x7f).max(1);
+
+    Ok(SerialNumber::from_slice(&serial))
+}
+
+/// Errors unless `params` describe a CA whose key usages (if restricted)
+/// allow signing certificates, as no client would trust what it issues otherwise
+fn check_can_issue(params: &CertificateParams) -> Result<(), Error> {
+    if !matches!(params.is_ca, IsCa::Ca(_)) {
+        return Err(Error::X509(
+            "root CA certificate is not a certificate authority".to_string(),
+        ));
+    }
+    if !params.key_usages.is_empty() && !params.key_usages.contains(&KeyUsagePurpose::KeyCertSign) {
+        return Err(Error::X509(
+            "root CA certificate's key usages don't permit signing certificates".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The subject key identifier extension, if present
+fn subject_key_identifier<'a>(certificate: &'a X509Certificate) -> Option<&'a [u8]> {
+    certificate
+        .extensions()
+        .iter()
+        .find_map(|extension| match extension.parsed_extension() {
+            ParsedExtension::SubjectKeyIdentifier(identifier) => Some(identifier.0),
+            _ => None,
+        })
+}
+
+fn authority_key_identifier<'a>(certificate: &'a X509Certificate) -> Option<&'a [u8]> {
+    certificate
+        .extensions()
+        .iter()
+        .find_map(|extension| match extension.parsed_extension() {
+            ParsedExtension::AuthorityKeyIdentifier(identifier) => {
+                identifier.key_identifier.as_ref().map(|v| v.0)
+            }
+            _ => None,
+        })
+}
+
+/// rcgen can only sign with a `Certificate`, so the root CA is rebuilt from its
+/// PEM and self-signed again. Certificates it issues name the rebuilt copy as
+/// their issuer, so this errors unless its subject and subject key identifier
+/// are byte-for-byte those of the original, or clients would fail to chain
+/// them to the root they trust.
+fn parse_root_ca(root_ca: &str, root_ca_private: &str) -> Result<(Certificate, KeyPair), Error> {
+    let root_key_pair = KeyPair::from_pem(root_ca_private)?;
+    let certificate_params = CertificateParams::from_ca_cert_pem(root_ca)?;
+    check_can_issue(&certificate_params)?;
+    let derived = CertificateParams::self_signed(certificate_params, &root_key_pair)?;
+
+    let (_, pem) = x509_parser::pem::parse_x509_pem(root_ca.as_bytes())
+        .map_err(|_| Error::CouldNotParseCertificate)?;
+    let (_, original) =
+        parse_x509_certificate(&pem.contents).map_err(|_| Error::CouldNotParseCertificate)?;
+    let (_, rebuilt) =
+        parse_x509_certificate(derived.der()).map_err(|_| Error::CouldNotParseCertificate)?;
+    if original.subject().as_raw() != rebuilt.subject().as_raw() {
+        return Err(Error::X509(
+            "root CA certificate's subject can't be reproduced for signing".to_string(),
+        ));
+    }
+    if subject_key_identifier(&original) != subject_key_identifier(&rebuilt) {
+        return Err(Error::X509(
+            "root CA certificate's subject key identifier can't be reproduced for signing"
+                .to_string(),
+        ));
+    }
+    if original.public_key().raw != rebuilt.public_key().raw {
+        return Err(Error::X509(
+            "root CA private key doesn't match its certificate".to_string(),
+        ));
+    }
+
+    Ok((derived, root_key_pair))
+}
+
+fn issue_client_certificate(
     client_id: String,
-    _client_name: String,
-    root_ca: String,
-    root_ca_private: String,
-) -> Result<Vec<String>, rcgen::Error> {
-    let root_key_pair = KeyPair::from_pem(&root_ca_private)?;
-    let certificate_params = CertificateParams::from_ca_cert_pem(&root_ca)?;
-    let root_ca = CertificateParams::self_signed(certificate_params, &root_key_pair)?;
+    name: &DistinguishedNameParams,
+    root_ca: &Certificate,
+    root_key_pair: &KeyPair,
+    random: &dyn RandomSource,
+) -> Result<ClientCert, Error> {
+    let (certificate, key_pair, serial) =
+        issue_client(&client_id, name, root_ca, root_key_pair, random)?;
+
+    Ok(ClientCert {
+        client_id,
+        certificate: certificate.pem(),
+        private_key: key_pair.serialize_pem(),
+        serial: hex::encode(serial.as_ref()),
+    })
+}
 
+fn issue_client(
+    client_id: &str,
+    name: &DistinguishedNameParams,
+    root_ca: &Certificate,
+    root_key_pair: &KeyPair,
+    random: &dyn RandomSource,
+) -> Result<(Certificate, KeyPair, SerialNumber), Error> {
     let mut params = CertificateParams::default();
+    let serial = random_serial(random)?;
+    params.serial_number = Some(serial.clone());
 
-    let mut name = DistinguishedName::new();
-    name.push(rcgen::DnType::CommonName, client_id);
-    name.push(rcgen::DnType::OrganizationName, "Drop");
-    params.distinguished_name = name;
+    params.distinguished_name = name.to_distinguished_name(client_id);
 
     params.key_usages = vec![
         KeyUsagePurpose::DigitalSignature,
         KeyUsagePurpose::DataEncipherment,
     ];
+    // Points chain builders at the root CA by its subject key identifier
+    params.use_authority_key_identifier_extension = true;
 
     let key_pair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384)?;
-    let certificate = CertificateParams::signed_by(params, &key_pair, &root_ca, &root_key_pair)?;
+    let certificate = CertificateParams::signed_by(params, &key_pair, root_ca, root_key_pair)?;
+
+    Ok((certificate, key_pair, serial))
+}
+
+pub fn generate_client_certificate(
+    client_id: String,
+    client_name: String,
+    root_ca: String,
+    root_ca_private: String,
+) -> Result<Vec<String>, rcgen::Error> {
+    generate_client_certificate_with_name(
+        client_id,
+        client_name,
+        root_ca,
+        root_ca_private,
+        &DistinguishedNameParams::default(),
+    )
+}
+
+pub fn generate_client_certificate_with_name(
+    client_id: String,
+    _client_name: String,
+    root_ca: String,
+    root_ca_private: String,
+    name: &DistinguishedNameParams,
+) -> Result<Vec<String>, rcgen::Error> {
+    let (root_ca, root_key_pair) = parse_root_ca(&root_ca, &root_ca_private)?;
+    let client = issue_client_certificate(
+        client_id,
+        name,
+        &root_ca,
+        &root_key_pair,
+        &SystemRandomSource,
+    )?;
 
     // Returns certificate, then private key
-    Ok(vec![certificate.pem(), key_pair.serialize_pem()])
+    Ok(vec![client.certificate, client.private_key])
+}
+
+/// Reads the root CA certificate and key from disk and issues a client
+/// certificate with them
+pub async fn issue_client_from_files(
+    ca_cert_path: &Path,
+    ca_key_path: &Path,
+    client_id: String,
+    _client_name: String,
+) -> anyhow::Result<ClientCert> {
+    let root_ca = tokio::fs::read_to_string(ca_cert_path)
+        .await
+        .with_context(|| format!("reading {}", ca_cert_path.display()))?;
+    let root_ca_private = tokio::fs::read_to_string(ca_key_path)
+        .await
+        .with_context(|| format!("reading {}", ca_key_path.display()))?;
+
+    let (root_ca, root_key_pair) = parse_root_ca(&root_ca, &root_ca_private)?;
+    Ok(issue_client_certificate(
+        client_id,
+        &DistinguishedNameParams::default(),
+        &root_ca,
+        &root_key_pair,
+        &SystemRandomSource,
+    )?)
+}
+
+/// Like `generate_client_certificate_with_name`, but returns the certificate
+/// and PKCS #8 private key as DER. The root CA is still taken as PEM.
+pub fn generate_client_certificate_der(
+    client_id: String,
+    _client_name: String,
+    root_ca: String,
+    root_ca_private: String,
+    name: &DistinguishedNameParams,
+) -> Result<Vec<Vec<u8>>, rcgen::Error> {
+    let (root_ca, root_key_pair) = parse_root_ca(&root_ca, &root_ca_private)?;
+    let (certificate, key_pair, _) = issue_client(
+        &client_id,
+        name,
+        &root_ca,
+        &root_key_pair,
+        &SystemRandomSource,
+    )?;
+
+    // Returns certificate, then private key
+    Ok(vec![certificate.der().to_vec(), key_pair.serialize_der()])
+}
+
+/// Issues a certificate for each `(client_id, client_name)`, parsing the root
+/// CA only once
+pub fn generate_client_certificates(
+    clients: &[(String, String)],
+    root_ca: String,
+    root_ca_private: String,
+    name: &DistinguishedNameParams,
+) -> Result<Vec<ClientCert>, rcgen::Error> {
+    generate_client_certificates_with_random(
+        clients,
+        root_ca,
+        root_ca_private,
+        name,
+        &SystemRandomSource,
+    )
+}
+
+/// `generate_client_certificates`, drawing serials from `random`. Keypairs
+/// always come from the system RNG, as ring doesn't allow supplying another.
+pub fn generate_client_certificates_with_random(
+    clients: &[(String, String)],
+    root_ca: String,
+    root_ca_private: String,
+    name: &DistinguishedNameParams,
+    random: &dyn RandomSource,
+) -> Result<Vec<ClientCert>, rcgen::Error> {
+    let (root_ca, root_key_pair) = parse_root_ca(&root_ca, &root_ca_private)?;
+
+    clients
+        .iter()
+        .map(|(client_id, _client_name)| {
+            issue_client_certificate(client_id.clone(), name, &root_ca, &root_key_pair, random)
+        })
+        .collect()
+}
+
+/// Issues a new certificate for the subject and public key of
+/// `existing_cert_pem`, valid for `validity` (`(not_before, not_after)`) and
+/// with a fresh serial, so clients keep their keypair across renewals.
+/// `client_public_key` is the client's PEM public key, and must match the
+/// existing certificate's. The existing certificate must have been issued by
+/// `root_ca`, so a certificate from another CA can't be carried over to it.
+pub fn renew_client_certificate(
+    existing_cert_pem: &str,
+    client_public_key: &str,
+    root_ca_pem: &str,
+    root_ca_private: &str,
+    validity: (OffsetDateTime, OffsetDateTime),
+) -> anyhow::Result<String> {
+    let (root_ca, root_key_pair) = parse_root_ca(root_ca_pem, root_ca_private)?;
+    let (_, root_pem) = x509_parser::pem::parse_x509_pem(root_ca_pem.as_bytes())
+        .map_err(|err| anyhow!("invalid PEM: {}", err))?;
+    let root = root_pem
+        .parse_x509()
+        .map_err(|err| anyhow!("invalid certificate: {}", err))?;
+
+    let public_key = SubjectPublicKeyInfo::from_pem(client_public_key)?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(existing_cert_pem.as_bytes())
+        .map_err(|err| anyhow!("invalid PEM: {}", err))?;
+    let (_, existing) = parse_x509_certificate(&pem.contents)
+        .map_err(|err| anyhow!("invalid certificate: {}", err))?;
+    if existing.public_key().subject_public_key.data.as_ref() != public_key.der_bytes() {
+        return Err(anyhow!(
+            "public key doesn't match the certificate being renewed"
+        ));
+    }
+    let identifies_root = authority_key_identifier(&existing)
+        .is_none_or(|v| subject_key_identifier(&root) == Some(v));
+    if existing.issuer().as_raw() != root.subject().as_raw()
+        || !identifies_root
+        || existing.verify_signature(Some(root.public_key())).is_err()
+    {
+        return Err(anyhow!(
+            "the certificate being renewed wasn't issued by this root CA"
+        ));
+    }
+
+    // Keeps the subject, key usages and extensions of the existing certificate
+    let mut params = CertificateParams::from_ca_cert_der(&pem.contents.clone().into())?;
+    (params.not_before, params.not_after) = validity;
+
+    params.serial_number = Some(random_serial(&SystemRandomSource)?);
+    // Points chain builders at the root CA by its subject key identifier
+    params.use_authority_key_identifier_extension = true;
+
+    let certificate = CertificateParams::signed_by(params, &public_key, &root_ca, &root_key_pair)?;
+
+    Ok(certificate.pem())
 }
 
 pub fn verify_client_certificate(client_cert: String, root_ca: String) -> Result<bool, Error> {
@@ -79,9 +516,10 @@ pub fn verify_client_certificate(client_cert: String, root_ca: String) -> Result
         .unwrap();
     let client_cert = client_cert.parse_x509().unwrap();
 
-    let valid = root_ca
-        .verify_signature(Some(client_cert.public_key()))
-        .is_ok();
+    let valid = client_cert.issuer().as_raw() == root_ca.subject().as_raw()
+        && client_cert
+            .verify_signature(Some(root_ca.public_key()))
+            .is_ok();
 
     Ok(valid)
 }
@@ -104,12 +542,55 @@ pub fn sign_nonce(private_key: String, nonce: String) -> Result<String, Error> {
     Ok(hex_signature)
 }
 
+/// Why `verify_nonce_detailed` did or didn't accept a signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NonceVerification {
+    Valid,
+    /// Well-formed, but not the certificate key's signature of this nonce
+    BadSignature,
+    /// Not hex, or not the length of a P-384 signature
+    MalformedSignature,
+    /// The certificate, or a P-384 public key in it, couldn't be read
+    KeyParseError(String),
+}
+
 pub fn verify_nonce(public_cert: String, nonce: String, signature: String) -> Result<bool, Error> {
-    let (_, pem) = x509_parser::pem::parse_x509_pem(public_cert.as_bytes()).unwrap();
-    let (_, spki) = parse_x509_certificate(&pem.contents).unwrap();
-    let public_key = SubjectPublicKeyInfo::from_der(spki.public_key().raw).unwrap();
+    Ok(verify_nonce_detailed(&public_cert, &nonce, &signature)? == NonceVerification::Valid)
+}
 
-    let raw_signature = hex::decode(signature).unwrap();
+/// `verify_nonce`, saying why a signature was rejected. Every rejection is a
+/// `NonceVerification` rather than an error, so callers can log and respond to
+/// each.
+pub fn verify_nonce_detailed(
+    public_cert: &str,
+    nonce: &str,
+    signature: &str,
+) -> Result<NonceVerification, Error> {
+    let key_error = |reason: String| Ok(NonceVerification::KeyParseError(reason));
+    let pem = match x509_parser::pem::parse_x509_pem(public_cert.as_bytes()) {
+        Ok((_, pem)) => pem,
+        Err(err) => return key_error(format!("invalid PEM: {}", err)),
+    };
+    let certificate = match parse_x509_certificate(&pem.contents) {
+        Ok((_, certificate)) => certificate,
+        Err(err) => return key_error(format!("invalid certificate: {}", err)),
+    };
+    let public_key = match SubjectPublicKeyInfo::from_der(certificate.public_key().raw) {
+        Ok(public_key) => public_key,
+        Err(err) => return key_error(format!("invalid public key: {}", err)),
+    };
+    if public_key.algorithm() != &rcgen::PKCS_ECDSA_P384_SHA384 {
+        return key_error("certificate key isn't a P-384 ECDSA key".to_string());
+    }
+
+    let raw_signature = match hex::decode(signature) {
+        Ok(raw_signature) => raw_signature,
+        Err(_) => return Ok(NonceVerification::MalformedSignature),
+    };
+    // Fixed-length P-384 signatures are two 48-byte integers
+    if raw_signature.len() != 96 {
+        return Ok(NonceVerification::MalformedSignature);
+    }
 
     let valid = ring::signature::ECDSA_P384_SHA384_FIXED
         .verify(
@@ -119,5 +600,23 @@ pub fn verify_nonce(public_cert: String, nonce: String, signature: String) -> Re
         )
         .is_ok();
 
-    Ok(valid)
+    Ok(if valid {
+        NonceVerification::Valid
+    } else {
+        NonceVerification::BadSignature
+    })
+}
+
+/// Returns the `(not_before, not_after)` validity period of a PEM certificate
+pub fn certificate_validity(cert_pem: &str) -> anyhow::Result<(OffsetDateTime, OffsetDateTime)> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(cert_pem.as_bytes())
+        .map_err(|err| anyhow!("invalid PEM: {}", err))?;
+    let (_, certificate) = parse_x509_certificate(&pem.contents)
+        .map_err(|err| anyhow!("invalid certificate: {}", err))?;
+    let validity = certificate.validity();
+
+    Ok((
+        validity.not_before.to_datetime(),
+        validity.not_after.to_datetime(),
+    ))
 }
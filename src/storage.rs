@@ -0,0 +1,266 @@
+use std::path::{Path, PathBuf};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+
+use aes::cipher::KeyIvInit as _;
+#[cfg(not(target_arch = "wasm32"))]
+use aes::cipher::StreamCipher as _;
+use anyhow::anyhow;
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt as _, BufWriter},
+};
+
+use crate::manifest::Manifest;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    checksum::{ChunkHasher, HashAlgorithm},
+    compression::{ChunkCompressor, Compression},
+};
+
+pub type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// The cipher chunk bodies are encrypted with
+pub fn chunk_cipher(key: &[u8; 16], iv: &[u8; 16]) -> Aes128Ctr {
+    Aes128Ctr::new(key.into(), iv.into())
+}
+
+/// Content-addressed layout for chunk bodies, sharded by checksum like git's
+/// object store: `<root>/ab/cd/abcdef...`. Identical chunks share one file.
+#[derive(Clone, Debug)]
+pub struct ShardedLayout {
+    root: PathBuf,
+}
+
+impl ShardedLayout {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn path_for_checksum(&self, checksum: &str) -> anyhow::Result<PathBuf> {
+        if checksum.len() < 4 || !checksum.bytes().all(|v| v.is_ascii_hexdigit()) {
+            return Err(anyhow!("invalid chunk checksum: {:?}", checksum));
+        }
+
+        Ok(self
+            .root
+            .join(&checksum[0..2])
+            .join(&checksum[2..4])
+            .join(checksum))
+    }
+
+    /// Resolves a chunk id from `manifest` to where its body lives in this layout
+    pub fn path_for_chunk(&self, manifest: &Manifest, chunk_id: &str) -> anyhow::Result<PathBuf> {
+        let chunk = manifest
+            .chunks
+            .get(chunk_id)
+            .ok_or(anyhow!("chunk not in manifest: {}", chunk_id))?;
+        self.path_for_checksum(&chunk.checksum)
+    }
+
+    /// Moves a finished chunk file into the layout. If a chunk with the same
+    /// checksum is already stored, `source` is removed instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn store(&self, source: &Path, checksum: &str) -> anyhow::Result<PathBuf> {
+        let target = self.path_for_checksum(checksum)?;
+        if tokio::fs::try_exists(&target).await? {
+            tokio::fs::remove_file(source).await?;
+            return Ok(target);
+        }
+
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(source, &target).await?;
+
+        Ok(target)
+    }
+}
+
+/// Somewhere finished chunk bodies (as stored: compressed, then encrypted) are
+/// put, by chunk id. The write-side counterpart of `VersionBackend`.
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+pub trait ChunkSink: Send + Sync {
+    async fn put(&self, chunk_id: &str, bytes: &[u8]) -> anyhow::Result<()>;
+}
+
+/// Stores each chunk as `<root>/<chunk id>`, creating `root` if needed
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Debug)]
+pub struct DirectorySink {
+    root: PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DirectorySink {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl ChunkSink for DirectorySink {
+    async fn put(&self, chunk_id: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.root).await?;
+        tokio::fs::write(self.root.join(chunk_id), bytes).await?;
+
+        Ok(())
+    }
+}
+
+/// Stores each chunk as the object `<prefix><chunk id>` in an S3 bucket
+#[cfg(all(feature = "s3", not(target_arch = "wasm32")))]
+pub struct S3Sink {
+    store: object_store::aws::AmazonS3,
+    prefix: String,
+}
+
+#[cfg(all(feature = "s3", not(target_arch = "wasm32")))]
+impl S3Sink {
+    /// Credentials, region and endpoint come from the usual `AWS_*`
+    /// environment variables
+    pub fn from_env(bucket: &str, prefix: impl Into<String>) -> anyhow::Result<Self> {
+        let store = object_store::aws::AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()?;
+
+        Ok(Self::new(store, prefix))
+    }
+
+    pub fn new(store: object_store::aws::AmazonS3, prefix: impl Into<String>) -> Self {
+        Self {
+            store,
+            prefix: prefix.into(),
+        }
+    }
+}
+
+#[cfg(all(feature = "s3", not(target_arch = "wasm32")))]
+#[async_trait]
+impl ChunkSink for S3Sink {
+    async fn put(&self, chunk_id: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        use object_store::ObjectStoreExt as _;
+
+        let location = object_store::path::Path::from(format!("{}{}", self.prefix, chunk_id));
+        self.store.put(&location, bytes.to_vec().into()).await?;
+
+        Ok(())
+    }
+}
+
+/// Where a `ChunkWriter` puts the stored bytes
+#[cfg(not(target_arch = "wasm32"))]
+enum ChunkTarget {
+    File(BufWriter<File>),
+    /// Buffered, then put whole once finished
+    Sink {
+        sink: Arc<dyn ChunkSink>,
+        buffer: Vec<u8>,
+    },
+}
+
+/// Writes a chunk body as its contents are read: compressed, then encrypted,
+/// then hashed again so the stored bytes can be verified in transit.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct ChunkWriter {
+    target: ChunkTarget,
+    compressor: Option<ChunkCompressor>,
+    cipher: Option<Aes128Ctr>,
+    hasher: ChunkHasher,
+    length: u64,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ChunkWriter {
+    pub async fn create(
+        path: &Path,
+        compression: Compression,
+        cipher: Option<Aes128Ctr>,
+        hash: HashAlgorithm,
+        key: &[u8; 16],
+    ) -> anyhow::Result<Self> {
+        let target = ChunkTarget::File(BufWriter::new(File::create(path).await?));
+        Self::with_target(target, compression, cipher, hash, key)
+    }
+
+    /// Writes into memory, putting the chunk into `sink` once finished
+    pub fn to_sink(
+        sink: Arc<dyn ChunkSink>,
+        compression: Compression,
+        cipher: Option<Aes128Ctr>,
+        hash: HashAlgorithm,
+        key: &[u8; 16],
+    ) -> anyhow::Result<Self> {
+        let target = ChunkTarget::Sink {
+            sink,
+            buffer: Vec::new(),
+        };
+        Self::with_target(target, compression, cipher, hash, key)
+    }
+
+    fn with_target(
+        target: ChunkTarget,
+        compression: Compression,
+        cipher: Option<Aes128Ctr>,
+        hash: HashAlgorithm,
+        key: &[u8; 16],
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            target,
+            compressor: ChunkCompressor::new(compression)?,
+            cipher,
+            hasher: ChunkHasher::new(hash, key),
+            length: 0,
+        })
+    }
+
+    /// `data` is used as scratch space, and is clobbered if encrypting
+    pub async fn write(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        let mut compressed = match &mut self.compressor {
+            Some(compressor) => {
+                compressor.write(data)?;
+                compressor.take_output()
+            }
+            None => return self.write_stored(data).await,
+        };
+        self.write_stored(&mut compressed).await
+    }
+
+    async fn write_stored(&mut self, data: &mut [u8]) -> anyhow::Result<()> {
+        if let Some(cipher) = &mut self.cipher {
+            cipher.apply_keystream(data);
+        }
+        self.hasher.update(data);
+        self.length += data.len() as u64;
+        match &mut self.target {
+            ChunkTarget::File(writer) => writer.write_all(data).await?,
+            ChunkTarget::Sink { buffer, .. } => buffer.extend_from_slice(data),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the stored length and checksum. `chunk_id` is what a sink
+    /// stores the chunk as.
+    pub async fn finish(mut self, chunk_id: &str) -> anyhow::Result<(u64, String)> {
+        if let Some(compressor) = self.compressor.take() {
+            let mut remaining = compressor.finish()?;
+            self.write_stored(&mut remaining).await?;
+        }
+        match &mut self.target {
+            ChunkTarget::File(writer) => writer.flush().await?,
+            ChunkTarget::Sink { sink, buffer } => sink.put(chunk_id, buffer).await?,
+        }
+
+        Ok((self.length, self.hasher.finalize()))
+    }
+}
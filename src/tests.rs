@@ -1,11 +1,58 @@
 #![cfg(test)]
 extern crate test_generator;
 
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use aes::cipher::StreamCipher as _;
 use test_generator::test_resources;
+use tokio::io::AsyncReadExt as _;
 
-use crate::manifest::generate_manifest_rusty;
+use crate::{
+    checksum::{
+        checksums_match, hash_chunk, merkle_root, verify_merkle_proof, ChunkHasher, HashAlgorithm,
+        HashingReader,
+    },
+    chunking::{ChunkingStrategy, PackingStrategy},
+    compression::Compression,
+    manifest::{
+        case_insensitive_collisions, chunk_result_stream, filename_problem,
+        generate_manifest_from_files, generate_manifest_from_reader, generate_manifest_rusty,
+        generate_manifest_with_backend, generate_manifest_with_options,
+        generate_manifest_with_stats, generate_patch_manifest, organize_chunks, plan_manifest,
+        rechunk_manifest, unportable_filenames, ChunkContext, ChunkData, ChunkIdFn, ChunkReader,
+        FileEntry, FilenameProblem, Manifest, ManifestOptions, ManifestVersionError,
+        StructuralError, MANIFEST_VERSION,
+    },
+    random::{RandomSource, SeededRandomSource},
+    ssl::{
+        bootstrap_pki, certificate_validity, generate_client_certificate,
+        generate_client_certificate_der, generate_client_certificate_with_name,
+        generate_client_certificates, generate_client_certificates_with_random, generate_root_ca,
+        generate_root_ca_der, generate_root_ca_with_name, issue_client_from_files,
+        renew_client_certificate, sign_nonce, verify_client_certificate, verify_nonce,
+        verify_nonce_detailed, CertStatus, CertStatusStore, DistinguishedNameParams,
+        NonceVerification,
+    },
+    storage::{chunk_cipher, ChunkSink, DirectorySink, ShardedLayout},
+    verify::{
+        reconstruct_file_hash, repair_install, verify_chunk_bytes, verify_file, verify_manifest,
+        verify_manifest_against_backend, verify_manifest_parallel, verify_manifest_with_key,
+        verify_manifest_with_progress,
+    },
+    versions::{
+        _list_files,
+        archive_backend::{ArchiveLimits, VolumeReader, ZipVersionBackend},
+        archive_support_available, create_backend_constructor, has_dotfile_component, native_path,
+        path_backend::{synthesized_permission, PathVersionBackend},
+        portable_path, split_volume_set, supported_extensions,
+        types::{MinimumFileObject, VersionBackend, VersionFile},
+        walk_files, BackendError, WALK_CONCURRENCY,
+    },
+};
 
 #[test_resources("testfiles/**/*.7z")]
 fn manifest_gen(resource: &str) {
@@ -44,3 +91,4018 @@ fn manifest_gen(resource: &str) {
         }
     });
 }
+
+/// (filename, start, length)
+type Fragment<'a> = (&'a str, u64, u64);
+
+fn structure_manifest(fragments: &[(&str, &[Fragment])]) -> Manifest {
+    let mut chunks = HashMap::new();
+    let mut size = 0;
+    for (chunk_id, files) in fragments {
+        let files = files
+            .iter()
+            .map(|(filename, start, length)| {
+                size += *length;
+                FileEntry {
+                    filename: filename.to_string(),
+                    start: *start,
+                    length: *length,
+                    permissions: 0o644,
+                    symlink: None,
+                    sparse: false,
+                }
+            })
+            .collect::<Vec<_>>();
+        chunks.insert(
+            chunk_id.to_string(),
+            ChunkData {
+                length: files.iter().map(|v| v.length).sum(),
+                files,
+                checksum: String::new(),
+                iv: [0u8; 16],
+                compression: Compression::None,
+                stored_length: None,
+                stored_checksum: None,
+            },
+        );
+    }
+
+    Manifest {
+        version: "2".to_string(),
+        chunks,
+        size,
+        key: None,
+        hash: HashAlgorithm::Sha256,
+        seekable: true,
+        metadata: HashMap::new(),
+        merkle_root: None,
+    }
+}
+
+#[test]
+fn validate_structure_accepts_split_file() {
+    let manifest = structure_manifest(&[
+        ("a", &[("big.bin", 0, 100), ("small.txt", 0, 10)]),
+        ("b", &[("big.bin", 100, 50)]),
+    ]);
+    assert_eq!(manifest.validate_structure(), Ok(()));
+}
+
+#[test]
+fn validate_structure_detects_overlap() {
+    let manifest =
+        structure_manifest(&[("a", &[("big.bin", 0, 100)]), ("b", &[("big.bin", 90, 60)])]);
+    assert_eq!(
+        manifest.validate_structure(),
+        Err(vec![StructuralError::Overlap {
+            filename: "big.bin".to_string(),
+            previous_end: 100,
+            start: 90,
+        }])
+    );
+}
+
+#[test]
+fn validate_structure_detects_gap() {
+    let manifest = structure_manifest(&[
+        ("a", &[("big.bin", 0, 100)]),
+        ("b", &[("big.bin", 110, 40), ("late.bin", 5, 5)]),
+    ]);
+    assert_eq!(
+        manifest.validate_structure(),
+        Err(vec![
+            StructuralError::Gap {
+                filename: "big.bin".to_string(),
+                expected_start: 100,
+                start: 110,
+            },
+            StructuralError::Gap {
+                filename: "late.bin".to_string(),
+                expected_start: 0,
+                start: 5,
+            },
+        ])
+    );
+}
+
+#[test]
+fn validate_structure_detects_chunk_length_mismatch() {
+    let mut manifest = structure_manifest(&[
+        ("a", &[("file.bin", 0, 100)]),
+        ("b", &[("file.bin", 100, 50), ("other.bin", 0, 10)]),
+    ]);
+    manifest.chunks.get_mut("b").unwrap().length = 70;
+    assert_eq!(
+        manifest.validate_structure(),
+        Err(vec![StructuralError::ChunkLengthMismatch {
+            chunk_id: "b".to_string(),
+            length: 70,
+            files_length: 60,
+        }])
+    );
+}
+
+#[test]
+fn validate_structure_detects_size_mismatch() {
+    let mut manifest = structure_manifest(&[("a", &[("file.bin", 0, 100)])]);
+    manifest.size = 50;
+    assert_eq!(
+        manifest.validate_structure(),
+        Err(vec![StructuralError::SizeMismatch {
+            expected: 50,
+            actual: 100,
+        }])
+    );
+}
+
+/// Generates a manifest of the archive at `resource`, as `manifest_gen` does
+fn archive_manifest(resource: &str) -> Manifest {
+    block_on(generate_manifest_rusty(
+        Path::new(resource),
+        |_| {},
+        |_| {},
+        None,
+    ))
+    .unwrap_or_else(|err| panic!("failed to generate manifest for {}: {:?}", resource, err))
+}
+
+#[test_resources("testfiles/**/*.7z")]
+fn archive_listing_manifests_have_valid_structure(resource: &str) {
+    if let Err(errors) = archive_manifest(resource).validate_structure() {
+        panic!("{} has an invalid manifest: {:?}", resource, errors);
+    }
+}
+
+#[cfg(feature = "schema")]
+#[test]
+fn manifest_schema_describes_fixed_arrays() {
+    let schema = crate::manifest::manifest_json_schema();
+
+    let key = &schema["properties"]["key"];
+    assert_eq!(key["type"], serde_json::json!(["array", "null"]));
+    assert_eq!(key["minItems"], 16);
+    assert_eq!(key["maxItems"], 16);
+
+    for definition in ["ChunkData", "FileEntry"] {
+        assert!(
+            schema["$defs"].get(definition).is_some(),
+            "schema is missing {}",
+            definition
+        );
+    }
+}
+
+fn version_file(name: &str, size: u64) -> VersionFile {
+    VersionFile {
+        relative_filename: name.to_string(),
+        permission: 0o644,
+        size,
+        symlink: None,
+        is_sparse: false,
+    }
+}
+
+/// Builds a manifest straight from a chunk plan, without reading any data
+fn planned_manifest(plan: Vec<Vec<(VersionFile, u64, u64)>>) -> Manifest {
+    let mut chunks = HashMap::new();
+    let mut size = 0;
+    for (index, chunk) in plan.into_iter().enumerate() {
+        let files = chunk
+            .into_iter()
+            .map(|(file, start, length)| {
+                size += length;
+                FileEntry {
+                    filename: file.relative_filename,
+                    start,
+                    length,
+                    permissions: file.permission,
+                    symlink: None,
+                    sparse: false,
+                }
+            })
+            .collect::<Vec<_>>();
+        chunks.insert(
+            index.to_string(),
+            ChunkData {
+                length: files.iter().map(|v| v.length).sum(),
+                files,
+                checksum: String::new(),
+                iv: [0u8; 16],
+                compression: Compression::None,
+                stored_length: None,
+                stored_checksum: None,
+            },
+        );
+    }
+
+    Manifest {
+        version: "2".to_string(),
+        chunks,
+        size,
+        key: None,
+        hash: HashAlgorithm::Sha256,
+        seekable: true,
+        metadata: HashMap::new(),
+        merkle_root: None,
+    }
+}
+
+#[test]
+fn case_insensitive_collisions_are_reported() {
+    let files = vec![
+        version_file("Assets/Logo.png", 10),
+        version_file("assets/logo.png", 12),
+        version_file("assets/icon.png", 8),
+    ];
+    assert_eq!(
+        case_insensitive_collisions(&files),
+        vec![("Assets/Logo.png".to_string(), "assets/logo.png".to_string())]
+    );
+    assert!(case_insensitive_collisions(&files[1..]).is_empty());
+}
+
+#[test]
+fn organize_chunks_handles_offsets_past_4gb() {
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let files = vec![
+        version_file("huge.pak", 6 * GIB + 17),
+        version_file("large.pak", 5 * GIB),
+        version_file("small.txt", 10),
+    ];
+
+    let plan = organize_chunks(files, false, &ManifestOptions::default());
+    assert!(plan
+        .iter()
+        .flatten()
+        .any(|(file, start, _)| file.relative_filename == "huge.pak" && *start > u32::MAX as u64));
+
+    let manifest = planned_manifest(plan);
+    assert_eq!(manifest.size, 11 * GIB + 27);
+    assert_eq!(manifest.validate_structure(), Ok(()));
+}
+
+#[test]
+fn path_backend_reads_ranges_past_4gb() {
+    use std::io::{Seek as _, Write as _};
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+    let dir = temp_dir("past-4gb");
+    let marker_offset = 4 * GIB + 100;
+    // Sparse, so no real disk space is used
+    let mut huge = std::fs::File::create(dir.join("huge.pak")).unwrap();
+    huge.set_len(5 * GIB).unwrap();
+    huge.seek(std::io::SeekFrom::Start(marker_offset)).unwrap();
+    huge.write_all(b"past 4 GiB").unwrap();
+    huge.seek(std::io::SeekFrom::Start(u32::MAX as u64 - 1))
+        .unwrap();
+    huge.write_all(b"edge").unwrap();
+    drop(huge);
+    let backend = PathVersionBackend::new(dir.clone());
+
+    block_on(async {
+        let file = backend.peek_file("huge.pak".to_string()).await.unwrap();
+        assert_eq!(file.size, 5 * GIB);
+        let read = |start: u64, length: u64| {
+            let (backend, file) = (&backend, &file);
+            async move {
+                let mut contents = Vec::new();
+                backend
+                    .reader(file, start, Some(start + length))
+                    .await
+                    .unwrap()
+                    .read_to_end(&mut contents)
+                    .await
+                    .unwrap();
+                contents
+            }
+        };
+
+        assert_eq!(read(marker_offset, 10).await, b"past 4 GiB");
+        // Across the 32-bit boundary
+        assert_eq!(read(u32::MAX as u64 - 1, 4).await, b"edge");
+        // Up to the end of the file, which is past it too
+        assert_eq!(read(5 * GIB - 4, 4).await, [0; 4]);
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Creates a fresh, empty directory under the system temp dir
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("droplet-{}-{}", name, uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("failed to create temp dir");
+    dir
+}
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to create tokio runtime")
+        .block_on(future)
+}
+
+#[test]
+fn verify_detects_corrupted_chunk() {
+    let dir = temp_dir("verify");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("data/large.bin"), vec![7u8; 300 * 1024]).unwrap();
+    std::fs::write(dir.join("config.ini"), b"[game]\nfullscreen=1\n").unwrap();
+
+    block_on(async {
+        let manifest = generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 128 * 1024,
+                hash: HashAlgorithm::Blake3,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(manifest.chunks.len(), 3);
+        assert_eq!(manifest.validate_structure(), Ok(()));
+        assert!(verify_manifest(&dir, &manifest).await.unwrap().is_empty());
+
+        // Flip a byte in the last chunk's range of the large file
+        let mut contents = std::fs::read(dir.join("data/large.bin")).unwrap();
+        let last = contents.len() - 1;
+        contents[last] = 0;
+        std::fs::write(dir.join("data/large.bin"), contents).unwrap();
+
+        let failed = verify_manifest(&dir, &manifest).await.unwrap();
+        assert_eq!(failed.len(), 1);
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn hmac_checksums_depend_on_the_key() {
+    let dir = temp_dir("hmac");
+    std::fs::write(dir.join("game.bin"), vec![3u8; 100 * 1024]).unwrap();
+
+    block_on(async {
+        let mut manifest = generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                hash: HashAlgorithm::HmacSha256,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(verify_manifest(&dir, &manifest).await.unwrap().is_empty());
+
+        // The checksum isn't a plain SHA-256 of the contents
+        let mut plain = ChunkHasher::new(HashAlgorithm::Sha256, &manifest.hash_key());
+        plain.update(&vec![3u8; 100 * 1024]);
+        let chunk = manifest.chunks.values().next().unwrap();
+        assert_ne!(chunk.checksum, plain.finalize());
+
+        // Nor an HMAC under the raw manifest key, which also encrypts chunks
+        let raw = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &manifest.key.unwrap());
+        let raw_checksum = ring::hmac::sign(&raw, &vec![3u8; 100 * 1024]);
+        assert_ne!(chunk.checksum, hex::encode(raw_checksum));
+
+        // Checksums keyed differently from the manifest's key no longer verify
+        let publisher_key = manifest.key.unwrap();
+        manifest.key.as_mut().unwrap()[0] ^= 1;
+        assert_eq!(verify_manifest(&dir, &manifest).await.unwrap().len(), 1);
+
+        // A manifest re-signed under another key passes with its own key, but
+        // not with the publisher's
+        let forged_key = manifest.hash_key();
+        for chunk in manifest.chunks.values_mut() {
+            chunk.checksum = hash_chunk(
+                &vec![3u8; 100 * 1024],
+                HashAlgorithm::HmacSha256,
+                &forged_key,
+            );
+        }
+        assert!(verify_manifest(&dir, &manifest).await.unwrap().is_empty());
+        let failed = verify_manifest_with_key(&dir, &manifest, &publisher_key).await;
+        assert_eq!(failed.unwrap().len(), 1);
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn generation_writes_encrypted_chunks() {
+    let dir = temp_dir("output-source");
+    let output_dir = temp_dir("output-chunks");
+    std::fs::write(
+        dir.join("game.bin"),
+        (0..200_000u32).map(|v| v as u8).collect::<Vec<_>>(),
+    )
+    .unwrap();
+    std::fs::write(dir.join("readme.txt"), b"hello").unwrap();
+
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            chunk_size: 64 * 1024,
+            output_dir: Some(output_dir.clone()),
+            encrypt: true,
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+
+    for (chunk_id, chunk) in &manifest.chunks {
+        let mut contents = std::fs::read(output_dir.join(chunk_id)).unwrap();
+        let length: u64 = chunk.files.iter().map(|file| file.length).sum();
+        assert_eq!(contents.len() as u64, length);
+
+        chunk_cipher(&manifest.key.unwrap(), &chunk.iv).apply_keystream(&mut contents);
+
+        let mut hasher = ChunkHasher::new(manifest.hash, &manifest.hash_key());
+        hasher.update(&contents);
+        assert_eq!(hasher.finalize(), chunk.checksum);
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(output_dir).unwrap();
+}
+
+#[test]
+fn output_dirs_inside_the_source_are_excluded() {
+    let dir = temp_dir("nested-output");
+    std::fs::write(dir.join("game.exe"), [1u8; 2048]).unwrap();
+    std::fs::create_dir_all(dir.join("logs")).unwrap();
+    std::fs::write(dir.join("logs/build.log"), b"built").unwrap();
+    let options = ManifestOptions {
+        output_dir: Some(dir.join("out").join("chunks")),
+        exclude: vec!["logs".to_string()],
+        ..Default::default()
+    };
+
+    // The second run would otherwise pick up the first run's chunks
+    for _ in 0..2 {
+        let manifest = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            options.clone(),
+        ))
+        .unwrap();
+        let filenames: Vec<&str> = manifest
+            .chunks
+            .values()
+            .flat_map(|v| v.files.iter().map(|v| v.filename.as_str()))
+            .collect();
+        assert_eq!(filenames, vec!["game.exe"]);
+
+        let planned = block_on(plan_manifest(&dir, &options)).unwrap();
+        assert_eq!(planned.size, 2048);
+    }
+    assert_eq!(
+        std::fs::read_dir(dir.join("out/chunks")).unwrap().count(),
+        2
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn chunks_are_streamed_as_they_finish() {
+    use futures::StreamExt as _;
+
+    let dir = temp_dir("chunk-stream");
+    std::fs::write(dir.join("game.exe"), [1u8; 40 * 1024]).unwrap();
+    std::fs::write(dir.join("readme.txt"), b"hello").unwrap();
+    let output_dir = temp_dir("chunk-stream-out");
+    let options = ManifestOptions {
+        chunk_size: 16 * 1024,
+        output_dir: Some(output_dir.clone()),
+        ..Default::default()
+    };
+
+    let streamed: Vec<(String, ChunkData)> = block_on(
+        chunk_result_stream(&dir, options.clone())
+            .map(|result| {
+                let (chunk_id, chunk) = result.unwrap();
+                // Written before it's yielded, so it can be uploaded straight away
+                assert!(output_dir.join(&chunk_id).exists());
+                (chunk_id, chunk)
+            })
+            .collect(),
+    );
+    assert_eq!(streamed.len(), 3);
+    let length: u64 = streamed.iter().map(|(_, chunk)| chunk.length).sum();
+    assert_eq!(length, 40 * 1024 + 5);
+
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        options,
+    ))
+    .unwrap();
+    let mut checksums: Vec<&str> = block_on(
+        manifest
+            .chunk_stream()
+            .map(|(_, chunk)| chunk.checksum.as_str())
+            .collect(),
+    );
+    let mut streamed_checksums: Vec<&str> = streamed
+        .iter()
+        .map(|(_, chunk)| chunk.checksum.as_str())
+        .collect();
+    checksums.sort();
+    streamed_checksums.sort();
+    assert_eq!(checksums, streamed_checksums);
+
+    let results: Vec<_> =
+        block_on(chunk_result_stream(&dir.join("missing"), ManifestOptions::default()).collect());
+    assert_eq!(results.len(), 1);
+    assert!(results[0].is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(output_dir).unwrap();
+}
+
+#[test]
+fn patch_manifests_reuse_unchanged_chunks() {
+    let old_dir = temp_dir("patch-old");
+    let new_dir = temp_dir("patch-new");
+    std::fs::write(old_dir.join("a.pak"), [1u8; 8 * 1024]).unwrap();
+    std::fs::write(old_dir.join("b.pak"), [2u8; 8 * 1024]).unwrap();
+    std::fs::write(new_dir.join("a.pak"), [1u8; 8 * 1024]).unwrap();
+    std::fs::write(new_dir.join("b.pak"), [3u8; 8 * 1024]).unwrap();
+    std::fs::write(new_dir.join("c.pak"), [4u8; 8 * 1024]).unwrap();
+    // A chunk per file
+    let options = ManifestOptions {
+        chunk_size: 8 * 1024,
+        wiggle: Some(0),
+        packing: PackingStrategy::Alphabetical,
+        ..Default::default()
+    };
+
+    let old_manifest = block_on(generate_manifest_with_options(
+        &old_dir,
+        |_| {},
+        |_| {},
+        options.clone(),
+    ))
+    .unwrap();
+    let patch = block_on(generate_patch_manifest(
+        &old_dir,
+        &new_dir,
+        &old_manifest,
+        |_| {},
+        |_| {},
+        options.clone(),
+    ))
+    .unwrap();
+    assert_eq!(patch.manifest.chunks.len(), 3);
+    assert_eq!(patch.new_chunks.len(), 2);
+    assert_eq!(patch.download_size(), 16 * 1024);
+    let (new_id, old_id) = patch.existing_chunks.iter().next().unwrap();
+    assert_eq!(patch.manifest.chunks[new_id].files[0].filename, "a.pak");
+    assert_eq!(old_manifest.chunks[old_id].files[0].filename, "a.pak");
+
+    std::fs::remove_file(old_dir.join("b.pak")).unwrap();
+    assert!(block_on(generate_patch_manifest(
+        &old_dir,
+        &new_dir,
+        &old_manifest,
+        |_| {},
+        |_| {},
+        options,
+    ))
+    .is_err());
+
+    std::fs::remove_dir_all(old_dir).unwrap();
+    std::fs::remove_dir_all(new_dir).unwrap();
+}
+
+#[test]
+fn content_addressed_output_dedupes_chunks() {
+    let dir = temp_dir("sharded-source");
+    let output_dir = temp_dir("sharded-chunks");
+    // Two identical files, each exactly one chunk
+    std::fs::write(dir.join("a.bin"), vec![1u8; 64 * 1024]).unwrap();
+    std::fs::write(dir.join("b.bin"), vec![1u8; 64 * 1024]).unwrap();
+
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            chunk_size: 64 * 1024,
+            output_dir: Some(output_dir.clone()),
+            content_addressed: true,
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+    assert_eq!(manifest.chunks.len(), 2);
+
+    let layout = ShardedLayout::new(&output_dir);
+    let paths: Vec<PathBuf> = manifest
+        .chunks
+        .keys()
+        .map(|chunk_id| layout.path_for_chunk(&manifest, chunk_id).unwrap())
+        .collect();
+    assert_eq!(paths[0], paths[1]);
+
+    let checksum = &manifest.chunks.values().next().unwrap().checksum;
+    assert_eq!(
+        paths[0],
+        output_dir
+            .join(&checksum[0..2])
+            .join(&checksum[2..4])
+            .join(checksum)
+    );
+    assert_eq!(std::fs::read(&paths[0]).unwrap(), vec![1u8; 64 * 1024]);
+    assert_eq!(
+        manifest.unique_checksums(),
+        HashSet::from([checksum.as_str()])
+    );
+    // Nothing but the shard directory is left behind
+    assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 1);
+
+    // The shared body is only downloaded once
+    assert_eq!(manifest.installed_size(), 128 * 1024);
+    assert_eq!(manifest.download_size(), 64 * 1024);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(output_dir).unwrap();
+}
+
+#[test]
+fn generation_compresses_chunks() {
+    let dir = temp_dir("compressed-source");
+    let output_dir = temp_dir("compressed-chunks");
+    std::fs::write(dir.join("zeros.bin"), vec![0u8; 500_000]).unwrap();
+    std::fs::write(dir.join("text.txt"), "lorem ipsum ".repeat(1000)).unwrap();
+
+    for compression in [Compression::Zstd { level: 3 }, Compression::Gzip] {
+        let manifest = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 128 * 1024,
+                output_dir: Some(output_dir.clone()),
+                encrypt: true,
+                compression,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+
+        for (chunk_id, chunk) in &manifest.chunks {
+            assert_eq!(chunk.compression, compression);
+            let mut stored = std::fs::read(output_dir.join(chunk_id)).unwrap();
+            assert_eq!(Some(stored.len() as u64), chunk.stored_length);
+            assert!(chunk.stored_length.unwrap() < chunk.length);
+
+            let mut hasher = ChunkHasher::new(manifest.hash, &manifest.hash_key());
+            hasher.update(&stored);
+            assert_eq!(Some(hasher.finalize()), chunk.stored_checksum);
+
+            chunk_cipher(&manifest.key.unwrap(), &chunk.iv).apply_keystream(&mut stored);
+            let contents = chunk.compression.decompress(&stored).unwrap();
+            assert_eq!(contents.len() as u64, chunk.length);
+
+            let mut hasher = ChunkHasher::new(manifest.hash, &manifest.hash_key());
+            hasher.update(&contents);
+            assert_eq!(hasher.finalize(), chunk.checksum);
+        }
+        assert!(manifest.download_size() < manifest.installed_size());
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(output_dir).unwrap();
+}
+
+#[test]
+fn chunk_ids_can_be_derived() {
+    let dir = temp_dir("chunk-id-source");
+    let output_dir = temp_dir("chunk-id-chunks");
+    std::fs::write(dir.join("a.bin"), vec![1u8; 64 * 1024]).unwrap();
+    std::fs::write(dir.join("b.bin"), vec![2u8; 64 * 1024]).unwrap();
+    let options = |chunk_id: ChunkIdFn| ManifestOptions {
+        chunk_size: 64 * 1024,
+        wiggle: Some(0),
+        output_dir: Some(output_dir.clone()),
+        chunk_id: Some(chunk_id),
+        ..Default::default()
+    };
+
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        options(Arc::new(|chunk: &ChunkContext| {
+            format!("{}-{}", chunk.index, &chunk.checksum[0..8])
+        })),
+    ))
+    .unwrap();
+    let mut chunk_ids: Vec<&String> = manifest.chunks.keys().collect();
+    chunk_ids.sort();
+    assert_eq!(chunk_ids.len(), 2);
+    for chunk_id in chunk_ids {
+        let (index, prefix) = chunk_id.split_once('-').unwrap();
+        assert!(index == "0" || index == "1");
+        assert!(manifest.chunks[chunk_id].checksum.starts_with(prefix));
+        assert_eq!(
+            std::fs::read(output_dir.join(chunk_id)).unwrap().len(),
+            64 * 1024
+        );
+    }
+    assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 2);
+
+    for bad in ["same", "../escape"] {
+        assert!(block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            options(Arc::new(move |_: &ChunkContext| bad.to_string())),
+        ))
+        .is_err());
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(output_dir).unwrap();
+}
+
+/// Keeps every chunk put into it
+#[derive(Default)]
+struct MemorySink {
+    chunks: std::sync::Mutex<HashMap<String, Vec<u8>>>,
+}
+
+#[async_trait::async_trait]
+impl ChunkSink for MemorySink {
+    async fn put(&self, chunk_id: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        self.chunks
+            .lock()
+            .unwrap()
+            .insert(chunk_id.to_owned(), bytes.to_vec());
+        Ok(())
+    }
+}
+
+#[test]
+fn chunks_are_put_into_sinks() {
+    let dir = temp_dir("sink-source");
+    std::fs::write(dir.join("zeros.bin"), vec![0u8; 300_000]).unwrap();
+
+    let sink = Arc::new(MemorySink::default());
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            chunk_size: 128 * 1024,
+            sink: Some(sink.clone()),
+            compression: Compression::Zstd { level: 3 },
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+
+    let stored = sink.chunks.lock().unwrap();
+    assert_eq!(stored.len(), manifest.chunks.len());
+    for (chunk_id, chunk) in &manifest.chunks {
+        let bytes = &stored[chunk_id];
+        assert_eq!(Some(bytes.len() as u64), chunk.stored_length);
+        let contents = chunk.compression.decompress(bytes).unwrap();
+        assert!(verify_chunk_bytes(chunk, &contents, manifest.hash, &manifest.hash_key()).unwrap());
+    }
+    drop(stored);
+
+    let output_dir = temp_dir("sink-chunks");
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            sink: Some(Arc::new(DirectorySink::new(output_dir.join("chunks")))),
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+    let chunk_id = manifest.chunks.keys().next().unwrap();
+    assert_eq!(
+        std::fs::read(output_dir.join("chunks").join(chunk_id)).unwrap(),
+        vec![0u8; 300_000]
+    );
+
+    assert!(block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            output_dir: Some(output_dir.clone()),
+            sink: Some(sink.clone()),
+            ..Default::default()
+        },
+    ))
+    .is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(output_dir).unwrap();
+}
+
+#[test]
+fn wiggle_avoids_tiny_trailing_chunks() {
+    const MIB: u64 = 1024 * 1024;
+    let files = || {
+        vec![
+            version_file("level1.pak", MIB + 10),
+            version_file("level2.pak", 2 * MIB + 20),
+        ]
+    };
+    let plan = |wiggle| {
+        let options = ManifestOptions {
+            chunk_size: MIB,
+            wiggle: Some(wiggle),
+            ..Default::default()
+        };
+        (
+            organize_chunks(files(), false, &options),
+            organize_chunks(files(), true, &options),
+        )
+    };
+
+    let (split, whole) = plan(0);
+    let (split_wiggle, whole_wiggle) = plan(64 * 1024);
+    assert_eq!(split.len(), 4);
+    assert_eq!(split_wiggle.len(), 3);
+    // No sliver of a file spills into another chunk
+    assert!(split_wiggle
+        .iter()
+        .flatten()
+        .all(|(_, _, length)| *length >= MIB));
+    assert_eq!(planned_manifest(split_wiggle).validate_structure(), Ok(()));
+
+    // Whole-file packing never splits regardless of wiggle
+    assert_eq!(whole.len(), 2);
+    assert_eq!(whole_wiggle.len(), 2);
+}
+
+#[test]
+fn target_chunks_derives_chunk_size() {
+    const MIB: u64 = 1024 * 1024;
+    let files: Vec<VersionFile> = (0..10)
+        .map(|index| version_file(&format!("asset{}.pak", index), 10 * MIB))
+        .collect();
+    let options = ManifestOptions {
+        target_chunks: Some(4),
+        ..Default::default()
+    };
+
+    let plan = organize_chunks(files.clone(), false, &options);
+    assert_eq!(plan.len(), 4);
+    assert_eq!(planned_manifest(plan).validate_structure(), Ok(()));
+
+    // The floor stops tiny sources being shredded into many chunks
+    let options = ManifestOptions {
+        target_chunks: Some(100),
+        ..Default::default()
+    };
+    let small = vec![version_file("a.txt", 1000), version_file("b.txt", 2000)];
+    assert_eq!(organize_chunks(small, false, &options).len(), 1);
+}
+
+#[test]
+fn plan_manifest_matches_generated_layout() {
+    let dir = temp_dir("plan");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("data/big.bin"), vec![7u8; 3 * 1024 * 1024]).unwrap();
+    std::fs::write(dir.join("readme.txt"), b"hello").unwrap();
+
+    let options = ManifestOptions {
+        chunk_size: 1024 * 1024,
+        wiggle: Some(0),
+        ..Default::default()
+    };
+    let plan = block_on(plan_manifest(&dir, &options)).unwrap();
+    let generated = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        options,
+    ))
+    .unwrap();
+
+    assert_eq!(plan.validate_structure(), Ok(()));
+    assert_eq!(plan.size, generated.size);
+    assert!(plan.chunks.values().all(|v| v.checksum.is_empty()));
+    assert!(plan.seekable && generated.seekable);
+
+    let layout = |manifest: &Manifest| {
+        let mut files: Vec<_> = manifest
+            .chunks
+            .values()
+            .map(|v| {
+                v.files
+                    .iter()
+                    .map(|v| (v.filename.clone(), v.start, v.length))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    assert_eq!(layout(&plan), layout(&generated));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn plan_manifest_rejects_what_generation_does() {
+    let dir = temp_dir("plan-options");
+    std::fs::write(dir.join("game.exe"), [1u8; 1024]).unwrap();
+
+    let invalid = [
+        ManifestOptions {
+            chunk_size: 0,
+            ..Default::default()
+        },
+        ManifestOptions {
+            target_chunks: Some(0),
+            ..Default::default()
+        },
+        ManifestOptions {
+            max_chunk_bytes: Some(0),
+            ..Default::default()
+        },
+    ];
+    for options in invalid {
+        let planned = block_on(plan_manifest(&dir, &options)).err().unwrap();
+        let generated = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            options,
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(planned.to_string(), generated.to_string());
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test_resources("testfiles/**/*.7z")]
+fn archive_listing_manifests_are_not_seekable(resource: &str) {
+    assert!(
+        !archive_manifest(resource).seekable,
+        "archives are read as streams"
+    );
+}
+
+#[test]
+fn checksums_are_independent_of_read_buffer_size() {
+    let dir = temp_dir("read-buffer");
+    std::fs::create_dir_all(&dir).unwrap();
+    let contents: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|v| (v % 251) as u8).collect();
+    std::fs::write(dir.join("asset.pak"), contents).unwrap();
+    std::fs::write(dir.join("config.ini"), b"[video]\nfullscreen=1\n").unwrap();
+
+    let checksums = |read_buffer_size| {
+        let manifest = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 1024 * 1024,
+                read_buffer_size,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        let mut checksums: Vec<String> =
+            manifest.chunks.into_values().map(|v| v.checksum).collect();
+        checksums.sort();
+        checksums
+    };
+
+    let expected = checksums(8 * 1024 * 1024);
+    assert_eq!(checksums(4096), expected);
+    assert_eq!(checksums(1000), expected);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn split_volume_sets_are_detected() {
+    let dir = temp_dir("volumes");
+    for name in [
+        "game.7z.001",
+        "game.7z.002",
+        "game.7z.003",
+        "Other.part01.rar",
+        "Other.part02.rar",
+        "notes.txt.001",
+        "solo.part1.rar",
+        "data.zip.001",
+    ] {
+        std::fs::write(dir.join(name), b"").unwrap();
+    }
+
+    assert_eq!(
+        split_volume_set(&dir.join("game.7z.001")),
+        Some(vec![
+            dir.join("game.7z.001"),
+            dir.join("game.7z.002"),
+            dir.join("game.7z.003"),
+        ])
+    );
+    assert_eq!(
+        split_volume_set(&dir.join("Other.part01.rar")),
+        Some(vec![
+            dir.join("Other.part01.rar"),
+            dir.join("Other.part02.rar")
+        ])
+    );
+    // Only the first volume of a supported archive type starts a set
+    assert_eq!(split_volume_set(&dir.join("game.7z.002")), None);
+    assert_eq!(split_volume_set(&dir.join("Other.part02.rar")), None);
+    assert_eq!(split_volume_set(&dir.join("notes.txt.001")), None);
+    // Nor does a lone first volume
+    assert_eq!(split_volume_set(&dir.join("solo.part1.rar")), None);
+    assert_eq!(split_volume_set(&dir.join("data.zip.001")), None);
+
+    // Byte-split sets are read joined, but RAR volumes can't be
+    assert!(create_backend_constructor(&dir.join("game.7z.001")).is_ok());
+    assert!(create_backend_constructor(&dir.join("data.zip.001")).is_ok());
+    assert!(create_backend_constructor(&dir.join("solo.part1.rar")).is_ok());
+    assert_eq!(
+        create_backend_constructor(&dir.join("Other.part01.rar")).err(),
+        Some(BackendError::SplitArchive {
+            first: dir.join("Other.part01.rar"),
+            volumes: 2,
+        })
+    );
+    assert_eq!(
+        create_backend_constructor(&dir.join("notes.txt.001")).err(),
+        Some(BackendError::UnsupportedFormat(dir.join("notes.txt.001")))
+    );
+    assert_eq!(
+        create_backend_constructor(&dir.join("missing.7z")).err(),
+        Some(BackendError::PathNotFound(dir.join("missing.7z")))
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn volumes_read_as_one_stream() {
+    let volumes: Vec<PathBuf> = ["game.tar.001", "game.tar.002"]
+        .iter()
+        .map(|v| Path::new("testfiles/split").join(v))
+        .collect();
+    let mut joined = Vec::new();
+    std::io::Read::read_to_end(&mut VolumeReader::new(volumes.clone()), &mut joined).unwrap();
+
+    let expected: Vec<u8> = volumes
+        .iter()
+        .flat_map(|v| std::fs::read(v).unwrap())
+        .collect();
+    assert_eq!(joined.len(), 30720);
+    assert_eq!(joined, expected);
+}
+
+#[test]
+fn archive_listing_joins_split_volumes() {
+    let first = Path::new("testfiles/split/game.tar.001");
+    let backend = create_backend_constructor(first).unwrap()().unwrap();
+
+    block_on(async {
+        let mut files = backend.list_files().await.unwrap();
+        files.sort_by(|a, b| a.relative_filename.cmp(&b.relative_filename));
+        let listing: Vec<(&str, u64)> = files
+            .iter()
+            .map(|v| (v.relative_filename.as_str(), v.size))
+            .collect();
+        assert_eq!(listing, [("data/level1.pak", 20480), ("game.exe", 4)]);
+
+        // level1.pak straddles the two volumes
+        let level = backend.read_to_end(&files[0]).await.unwrap();
+        let expected: Vec<u8> = (0..=255u8).cycle().take(20480).collect();
+        assert_eq!(level, expected);
+    });
+}
+
+/// Backend over in-memory files, for exercising trait defaults and edge cases
+struct MemoryBackend {
+    files: HashMap<String, Vec<u8>>,
+    /// Sizes to list files with instead of their real ones, as if they
+    /// changed after being listed
+    listed_sizes: HashMap<String, u64>,
+}
+
+impl MemoryBackend {
+    fn new(files: &[(&str, &[u8])]) -> Self {
+        Self {
+            files: files
+                .iter()
+                .map(|(name, data)| (name.to_string(), data.to_vec()))
+                .collect(),
+            listed_sizes: HashMap::new(),
+        }
+    }
+
+    fn with_listed_size(mut self, name: &str, size: u64) -> Self {
+        self.listed_sizes.insert(name.to_string(), size);
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl VersionBackend for MemoryBackend {
+    fn require_whole_files(&self) -> bool {
+        false
+    }
+
+    async fn list_files(&self) -> anyhow::Result<Vec<VersionFile>> {
+        let mut files = Vec::new();
+        for name in self.files.keys() {
+            files.push(self.peek_file(name.clone()).await?);
+        }
+        files.sort_by(|a, b| a.relative_filename.cmp(&b.relative_filename));
+        Ok(files)
+    }
+
+    async fn peek_file(&self, sub_path: String) -> anyhow::Result<VersionFile> {
+        let data = self
+            .files
+            .get(&sub_path)
+            .ok_or(anyhow::anyhow!("no such file: {}", sub_path))?;
+        Ok(VersionFile {
+            size: self
+                .listed_sizes
+                .get(&sub_path)
+                .copied()
+                .unwrap_or(data.len() as u64),
+            relative_filename: sub_path,
+            permission: 0o644,
+            symlink: None,
+            is_sparse: false,
+        })
+    }
+
+    async fn reader(
+        &self,
+        file: &VersionFile,
+        start: u64,
+        end: Option<u64>,
+    ) -> anyhow::Result<Box<dyn MinimumFileObject>> {
+        let data = &self.files[&file.relative_filename];
+        let end = end.unwrap_or(data.len() as u64).min(data.len() as u64);
+        let start = start.min(end);
+        Ok(Box::new(std::io::Cursor::new(
+            data[start as usize..end as usize].to_vec(),
+        )))
+    }
+}
+
+fn filenames(files: Vec<VersionFile>) -> Vec<String> {
+    let mut names: Vec<String> = files.into_iter().map(|v| v.relative_filename).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn list_files_under_matches_whole_components() {
+    let memory = MemoryBackend::new(&[
+        ("base.txt", b"base"),
+        ("DLC/map.pak", b"map"),
+        ("DLC/audio/music.ogg", b"music"),
+        ("DLC2/other.pak", b"other"),
+    ]);
+    assert_eq!(
+        filenames(block_on(memory.list_files_under("DLC")).unwrap()),
+        vec!["DLC/audio/music.ogg", "DLC/map.pak"]
+    );
+
+    let dir = temp_dir("subtree");
+    std::fs::create_dir_all(dir.join("DLC/audio")).unwrap();
+    std::fs::create_dir_all(dir.join("DLC2")).unwrap();
+    std::fs::write(dir.join("base.txt"), b"base").unwrap();
+    std::fs::write(dir.join("DLC/map.pak"), b"map").unwrap();
+    std::fs::write(dir.join("DLC/audio/music.ogg"), b"music").unwrap();
+    std::fs::write(dir.join("DLC2/other.pak"), b"other").unwrap();
+
+    let backend = PathVersionBackend::new(dir.clone());
+    let expected: Vec<String> = ["DLC/audio/music.ogg", "DLC/map.pak"]
+        .iter()
+        .map(|v| Path::new(v).to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(
+        filenames(block_on(backend.list_files_under("DLC")).unwrap()),
+        expected
+    );
+    assert!(block_on(backend.list_files_under("missing"))
+        .unwrap()
+        .is_empty());
+
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            prefix: Some("DLC".to_string()),
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+    assert_eq!(manifest.size, 8);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn walk_files_matches_recursive_listing() {
+    let dir = temp_dir("walk");
+    for index in 0..20 {
+        let nested = dir.join(format!("dir{}/sub{}", index % 4, index));
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(nested.join("file.bin"), [index as u8]).unwrap();
+        std::fs::write(dir.join(format!("top{}.txt", index)), b"top").unwrap();
+    }
+
+    let mut expected = Vec::new();
+    _list_files(&mut expected, &dir).unwrap();
+    expected.sort();
+
+    for concurrency in [1, 3, WALK_CONCURRENCY] {
+        assert_eq!(block_on(walk_files(&dir, concurrency)).unwrap(), expected);
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn read_to_end_respects_limits() {
+    let dir = temp_dir("read-to-end");
+    std::fs::write(dir.join("settings.cfg"), b"volume=11").unwrap();
+    let backend = PathVersionBackend::new(dir.clone());
+
+    block_on(async {
+        let file = backend.peek_file("settings.cfg".to_string()).await.unwrap();
+        assert_eq!(backend.read_to_end(&file).await.unwrap(), b"volume=11");
+        assert_eq!(
+            backend.read_to_end_limited(&file, 9).await.unwrap(),
+            b"volume=11"
+        );
+        assert!(backend.read_to_end_limited(&file, 8).await.is_err());
+
+        // A file that understates its size still can't be read past the limit
+        let understated = VersionFile { size: 4, ..file };
+        assert!(backend.read_to_end_limited(&understated, 8).await.is_err());
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test_resources("testfiles/**/*.7z")]
+fn archive_listing_files_read_to_end(resource: &str) {
+    block_on(async {
+        let backend = create_backend_constructor(Path::new(resource)).unwrap()().unwrap();
+        for file in backend.list_files().await.unwrap() {
+            let contents = backend.read_to_end(&file).await.unwrap();
+            assert_eq!(contents.len() as u64, file.size);
+        }
+    });
+}
+
+#[test]
+fn reader_honours_zero_length_ranges() {
+    let dir = temp_dir("ranges");
+    std::fs::write(dir.join("data.bin"), b"0123456789").unwrap();
+    let backend = PathVersionBackend::new(dir.clone());
+
+    block_on(async {
+        let file = backend.peek_file("data.bin".to_string()).await.unwrap();
+        let read = |start, end| {
+            let backend = &backend;
+            let file = &file;
+            async move {
+                let mut contents = Vec::new();
+                backend
+                    .reader(file, start, end)
+                    .await?
+                    .read_to_end(&mut contents)
+                    .await?;
+                anyhow::Ok(contents)
+            }
+        };
+
+        assert_eq!(read(0, Some(0)).await.unwrap(), b"");
+        assert_eq!(read(5, Some(5)).await.unwrap(), b"");
+        assert_eq!(read(5, None).await.unwrap(), b"56789");
+        assert_eq!(read(2, Some(4)).await.unwrap(), b"23");
+        assert!(read(4, Some(2)).await.is_err());
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn hashing_reader_matches_direct_hash() {
+    let data: Vec<u8> = (0..100_000u32).map(|v| (v * 7 % 256) as u8).collect();
+    for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+        let mut direct = ChunkHasher::new(algorithm, &[0u8; 16]);
+        direct.update(&data);
+
+        let mut reader = HashingReader::new(
+            std::io::Cursor::new(data.clone()),
+            ChunkHasher::new(algorithm, &[0u8; 16]),
+        );
+        let mut copied = Vec::new();
+        let mut buf = [0u8; 999];
+        block_on(async {
+            loop {
+                let amount = reader.read(&mut buf).await.unwrap();
+                if amount == 0 {
+                    break;
+                }
+                copied.extend_from_slice(&buf[..amount]);
+            }
+        });
+
+        assert_eq!(copied, data);
+        assert_eq!(reader.into_hasher().finalize(), direct.finalize());
+    }
+}
+
+#[test]
+fn certificate_validity_is_parsed() {
+    let before = time::OffsetDateTime::now_utc() - time::Duration::seconds(5);
+    let root_ca = generate_root_ca().unwrap();
+    let (not_before, not_after) = certificate_validity(&root_ca[0]).unwrap();
+    assert!(not_before >= before);
+    assert!(not_after - not_before > time::Duration::days(365 * 999));
+
+    assert!(certificate_validity("not a certificate").is_err());
+}
+
+#[test]
+fn renewed_certificates_keep_the_client_key() {
+    let root_ca = generate_root_ca().unwrap();
+    let client = generate_client_certificate(
+        "client-1".to_string(),
+        "Steam Deck".to_string(),
+        root_ca[0].clone(),
+        root_ca[1].clone(),
+    )
+    .unwrap();
+    let public_key = rcgen::KeyPair::from_pem(&client[1])
+        .unwrap()
+        .public_key_pem();
+
+    let not_before = time::OffsetDateTime::now_utc()
+        .replace_nanosecond(0)
+        .unwrap();
+    let not_after = not_before + time::Duration::days(30);
+    let renewed = renew_client_certificate(
+        &client[0],
+        &public_key,
+        &root_ca[0],
+        &root_ca[1],
+        (not_before, not_after),
+    )
+    .unwrap();
+
+    assert_eq!(
+        certificate_validity(&renewed).unwrap(),
+        (not_before, not_after)
+    );
+    // The existing private key still signs for the renewed certificate
+    let signature = sign_nonce(client[1].clone(), "nonce".to_string()).unwrap();
+    assert!(verify_nonce(renewed.clone(), "nonce".to_string(), signature).unwrap());
+
+    let (_, old) = x509_parser::pem::parse_x509_pem(client[0].as_bytes()).unwrap();
+    let (_, new) = x509_parser::pem::parse_x509_pem(renewed.as_bytes()).unwrap();
+    let old = old.parse_x509().unwrap();
+    let new = new.parse_x509().unwrap();
+    assert_eq!(old.subject(), new.subject());
+    assert_ne!(old.serial, new.serial);
+    let (_, root) = x509_parser::pem::parse_x509_pem(root_ca[0].as_bytes()).unwrap();
+    assert!(new
+        .verify_signature(Some(root.parse_x509().unwrap().public_key()))
+        .is_ok());
+
+    let other_key = rcgen::KeyPair::generate().unwrap().public_key_pem();
+    assert!(renew_client_certificate(
+        &client[0],
+        &other_key,
+        &root_ca[0],
+        &root_ca[1],
+        (not_before, not_after),
+    )
+    .is_err());
+    // The renewal names the root by its key identifier, like issued certificates
+    let aki = new
+        .extensions()
+        .iter()
+        .find_map(|v| match v.parsed_extension() {
+            x509_parser::extensions::ParsedExtension::AuthorityKeyIdentifier(aki) => {
+                aki.key_identifier.as_ref().map(|v| v.0.to_vec())
+            }
+            _ => None,
+        });
+    let root = root.parse_x509().unwrap();
+    let ski = root
+        .extensions()
+        .iter()
+        .find_map(|v| match v.parsed_extension() {
+            x509_parser::extensions::ParsedExtension::SubjectKeyIdentifier(ski) => {
+                Some(ski.0.to_vec())
+            }
+            _ => None,
+        });
+    assert!(aki.is_some());
+    assert_eq!(aki, ski);
+
+    // Only the root that issued a certificate can renew it
+    let other_root = generate_root_ca().unwrap();
+    let err = renew_client_certificate(
+        &client[0],
+        &public_key,
+        &other_root[0],
+        &other_root[1],
+        (not_before, not_after),
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("wasn't issued by this root CA"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn client_certificates_chain_to_the_original_root() {
+    let root_ca = generate_root_ca().unwrap();
+    let client = generate_client_certificate(
+        "client-1".to_string(),
+        "Steam Deck".to_string(),
+        root_ca[0].clone(),
+        root_ca[1].clone(),
+    )
+    .unwrap();
+    assert!(verify_client_certificate(client[0].clone(), root_ca[0].clone()).unwrap());
+
+    let (_, root) = x509_parser::pem::parse_x509_pem(root_ca[0].as_bytes()).unwrap();
+    let (_, certificate) = x509_parser::pem::parse_x509_pem(client[0].as_bytes()).unwrap();
+    let root = root.parse_x509().unwrap();
+    let certificate = certificate.parse_x509().unwrap();
+    assert_eq!(certificate.issuer().as_raw(), root.subject().as_raw());
+    let key_identifier = |certificate: &x509_parser::certificate::X509Certificate| {
+        certificate
+            .extensions()
+            .iter()
+            .find_map(|extension| match extension.parsed_extension() {
+                x509_parser::extensions::ParsedExtension::SubjectKeyIdentifier(id) => {
+                    Some(id.0.to_vec())
+                }
+                x509_parser::extensions::ParsedExtension::AuthorityKeyIdentifier(id) => {
+                    id.key_identifier.as_ref().map(|id| id.0.to_vec())
+                }
+                _ => None,
+            })
+    };
+    assert!(key_identifier(&root).is_some());
+    assert_eq!(key_identifier(&certificate), key_identifier(&root));
+
+    let other_root = generate_root_ca().unwrap();
+    assert!(!verify_client_certificate(client[0].clone(), other_root[0].clone()).unwrap());
+    // A key that isn't the root's would issue certificates nothing chains to
+    assert!(generate_client_certificate(
+        "client-2".to_string(),
+        "Steam Deck".to_string(),
+        root_ca[0].clone(),
+        other_root[1].clone(),
+    )
+    .is_err());
+}
+
+#[test]
+fn nonce_verification_says_why_it_failed() {
+    let root_ca = generate_root_ca().unwrap();
+    let client = generate_client_certificate(
+        "client-1".to_string(),
+        "Steam Deck".to_string(),
+        root_ca[0].clone(),
+        root_ca[1].clone(),
+    )
+    .unwrap();
+    let signature = sign_nonce(client[1].clone(), "nonce".to_string()).unwrap();
+
+    assert_eq!(
+        verify_nonce_detailed(&client[0], "nonce", &signature).unwrap(),
+        NonceVerification::Valid
+    );
+    assert_eq!(
+        verify_nonce_detailed(&client[0], "other nonce", &signature).unwrap(),
+        NonceVerification::BadSignature
+    );
+    assert_eq!(
+        verify_nonce_detailed(&client[0], "nonce", "not hex").unwrap(),
+        NonceVerification::MalformedSignature
+    );
+    assert_eq!(
+        verify_nonce_detailed(&client[0], "nonce", &signature[..64]).unwrap(),
+        NonceVerification::MalformedSignature
+    );
+    assert!(matches!(
+        verify_nonce_detailed("not a certificate", "nonce", &signature).unwrap(),
+        NonceVerification::KeyParseError(_)
+    ));
+    // The root's key is P-256, which nonces are never signed with
+    assert!(matches!(
+        verify_nonce_detailed(&root_ca[0], "nonce", &signature).unwrap(),
+        NonceVerification::KeyParseError(_)
+    ));
+
+    assert!(verify_nonce(client[0].clone(), "nonce".to_string(), signature.clone()).unwrap());
+    assert!(!verify_nonce("garbage".to_string(), "nonce".to_string(), signature).unwrap());
+}
+
+#[test]
+fn bootstrapped_clients_verify_against_their_ca() {
+    let bundle = bootstrap_pki("client-1".to_string(), "Steam Deck".to_string()).unwrap();
+    assert_eq!(bundle.client.client_id, "client-1");
+    assert!(verify_client_certificate(
+        bundle.client.certificate.clone(),
+        bundle.ca_certificate.clone()
+    )
+    .unwrap());
+
+    // The CA can go on to issue more clients
+    let second = generate_client_certificate(
+        "client-2".to_string(),
+        "Laptop".to_string(),
+        bundle.ca_certificate.clone(),
+        bundle.ca_private_key.clone(),
+    )
+    .unwrap();
+    assert!(verify_client_certificate(second[0].clone(), bundle.ca_certificate.clone()).unwrap());
+
+    let signature = sign_nonce(bundle.client.private_key.clone(), "nonce".to_string()).unwrap();
+    assert!(verify_nonce(bundle.client.certificate, "nonce".to_string(), signature).unwrap());
+}
+
+#[test]
+fn batch_issued_certificates_are_distinct() {
+    let root_ca = generate_root_ca().unwrap();
+    let clients: Vec<(String, String)> = (0..3)
+        .map(|index| (format!("client-{}", index), format!("Device {}", index)))
+        .collect();
+    let issued = generate_client_certificates(
+        &clients,
+        root_ca[0].clone(),
+        root_ca[1].clone(),
+        &DistinguishedNameParams::default(),
+    )
+    .unwrap();
+    assert_eq!(issued.len(), 3);
+
+    let (_, root) = x509_parser::pem::parse_x509_pem(root_ca[0].as_bytes()).unwrap();
+    let root = root.parse_x509().unwrap();
+    for (client, (client_id, _)) in issued.iter().zip(&clients) {
+        assert_eq!(&client.client_id, client_id);
+        let (_, pem) = x509_parser::pem::parse_x509_pem(client.certificate.as_bytes()).unwrap();
+        let certificate = pem.parse_x509().unwrap();
+        assert_eq!(hex::encode(certificate.raw_serial()), client.serial);
+        assert!(certificate
+            .verify_signature(Some(root.public_key()))
+            .is_ok());
+    }
+
+    let serials: std::collections::HashSet<&str> =
+        issued.iter().map(|v| v.serial.as_str()).collect();
+    assert_eq!(serials.len(), 3);
+}
+
+#[test]
+fn distinguished_names_can_be_customised() {
+    let subject = |pem: &str| {
+        let (_, pem) = x509_parser::pem::parse_x509_pem(pem.as_bytes()).unwrap();
+        pem.parse_x509().unwrap().subject().to_string()
+    };
+
+    let root_ca = generate_root_ca().unwrap();
+    assert_eq!(subject(&root_ca[0]), "CN=Drop Root Server, O=Drop");
+
+    let name = DistinguishedNameParams {
+        organization: "Example Games".to_string(),
+        organizational_unit: Some("Infrastructure".to_string()),
+        country: Some("NZ".to_string()),
+        locality: Some("Wellington".to_string()),
+        ..Default::default()
+    };
+    let root_ca = generate_root_ca_with_name(&DistinguishedNameParams {
+        common_name: Some("Example Root".to_string()),
+        ..name.clone()
+    })
+    .unwrap();
+    assert_eq!(
+        subject(&root_ca[0]),
+        "CN=Example Root, O=Example Games, OU=Infrastructure, C=NZ, L=Wellington"
+    );
+
+    let client = generate_client_certificate_with_name(
+        "client-1".to_string(),
+        "Laptop".to_string(),
+        root_ca[0].clone(),
+        root_ca[1].clone(),
+        &name,
+    )
+    .unwrap();
+    assert_eq!(
+        subject(&client[0]),
+        "CN=client-1, O=Example Games, OU=Infrastructure, C=NZ, L=Wellington"
+    );
+}
+
+#[test]
+fn der_certificates_round_trip() {
+    let name = DistinguishedNameParams::default();
+    let root_ca = generate_root_ca_der(&name).unwrap();
+    let (_, root) = x509_parser::parse_x509_certificate(&root_ca[0]).unwrap();
+    assert_eq!(root.subject().to_string(), "CN=Drop Root Server, O=Drop");
+    assert!(root.is_ca());
+    rcgen::KeyPair::try_from(root_ca[1].as_slice()).unwrap();
+
+    // Clients are still issued against a PEM root CA
+    let root_ca_pem = generate_root_ca().unwrap();
+    let client = generate_client_certificate_der(
+        "client-1".to_string(),
+        "Laptop".to_string(),
+        root_ca_pem[0].clone(),
+        root_ca_pem[1].clone(),
+        &name,
+    )
+    .unwrap();
+    let (_, certificate) = x509_parser::parse_x509_certificate(&client[0]).unwrap();
+    assert_eq!(certificate.subject().to_string(), "CN=client-1, O=Drop");
+    let (_, root) = x509_parser::pem::parse_x509_pem(root_ca_pem[0].as_bytes()).unwrap();
+    assert!(certificate
+        .verify_signature(Some(root.parse_x509().unwrap().public_key()))
+        .is_ok());
+    rcgen::KeyPair::try_from(client[1].as_slice()).unwrap();
+}
+
+#[test]
+fn cert_status_store_tracks_revocations() {
+    let root_ca = generate_root_ca().unwrap();
+    let issued = generate_client_certificates(
+        &[
+            ("client-1".to_string(), "Laptop".to_string()),
+            ("client-2".to_string(), "Desktop".to_string()),
+        ],
+        root_ca[0].clone(),
+        root_ca[1].clone(),
+        &DistinguishedNameParams::default(),
+    )
+    .unwrap();
+
+    let mut store = CertStatusStore::new();
+    for client in &issued {
+        store.record_issued(&client.serial);
+    }
+    assert!(store.revoke(&issued[1].serial.to_ascii_uppercase()));
+    assert!(!store.revoke("abcd"));
+
+    assert_eq!(store.check_status(&issued[0].serial), CertStatus::Good);
+    assert_eq!(store.check_status(&issued[1].serial), CertStatus::Revoked);
+    assert_eq!(store.check_status("abcd"), CertStatus::Unknown);
+}
+
+#[test]
+fn clients_can_be_issued_from_ca_files() {
+    let dir = temp_dir("ca-files");
+    let root_ca = generate_root_ca().unwrap();
+    std::fs::write(dir.join("ca.pem"), &root_ca[0]).unwrap();
+    std::fs::write(dir.join("ca.key"), &root_ca[1]).unwrap();
+
+    let client = block_on(issue_client_from_files(
+        &dir.join("ca.pem"),
+        &dir.join("ca.key"),
+        "client-1".to_string(),
+        "Laptop".to_string(),
+    ))
+    .unwrap();
+    assert_eq!(client.client_id, "client-1");
+    certificate_validity(&client.certificate).unwrap();
+
+    let missing = block_on(issue_client_from_files(
+        &dir.join("missing.pem"),
+        &dir.join("ca.key"),
+        "client-1".to_string(),
+        "Laptop".to_string(),
+    ));
+    assert!(format!("{:#}", missing.err().unwrap()).contains("missing.pem"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn leaf_certificates_cant_issue_clients() {
+    let root_ca = generate_root_ca().unwrap();
+    let client = generate_client_certificate(
+        "client-1".to_string(),
+        "Laptop".to_string(),
+        root_ca[0].clone(),
+        root_ca[1].clone(),
+    )
+    .unwrap();
+
+    let result = generate_client_certificate(
+        "client-2".to_string(),
+        "Desktop".to_string(),
+        client[0].clone(),
+        client[1].clone(),
+    );
+    assert!(matches!(result, Err(rcgen::Error::X509(_))));
+}
+
+#[test]
+fn verify_file_streams_whole_files() {
+    let dir = temp_dir("verify-file");
+    let contents: Vec<u8> = (0..3 * 1024 * 1024 + 5).map(|v| (v % 239) as u8).collect();
+    std::fs::write(dir.join("movie.bik"), &contents).unwrap();
+
+    let mut hasher = ChunkHasher::new(HashAlgorithm::Blake3, &[0u8; 16]);
+    hasher.update(&contents);
+    let expected = hasher.finalize();
+    let entry = FileEntry {
+        filename: "movie.bik".to_string(),
+        start: 0,
+        length: contents.len() as u64,
+        permissions: 0o644,
+        symlink: None,
+        sparse: false,
+    };
+
+    block_on(async {
+        assert!(verify_file(&dir, &entry, &expected, HashAlgorithm::Blake3)
+            .await
+            .unwrap());
+        assert!(!verify_file(&dir, &entry, &expected, HashAlgorithm::Sha256)
+            .await
+            .unwrap());
+        assert!(
+            verify_file(&dir, &entry, &expected, HashAlgorithm::HmacSha256)
+                .await
+                .is_err()
+        );
+
+        std::fs::write(dir.join("movie.bik"), &contents[..1024]).unwrap();
+        assert!(!verify_file(&dir, &entry, &expected, HashAlgorithm::Blake3)
+            .await
+            .unwrap());
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(not(windows))]
+#[test]
+fn hidden_files_are_skipped_by_default() {
+    let dir = temp_dir("hidden");
+    std::fs::create_dir_all(dir.join(".fseventsd")).unwrap();
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("game.exe"), b"game").unwrap();
+    std::fs::write(dir.join("data/.DS_Store"), b"store").unwrap();
+    std::fs::write(dir.join(".fseventsd/log"), b"log").unwrap();
+
+    let files = |include_hidden| {
+        let manifest = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                include_hidden,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        let mut names: Vec<String> = manifest
+            .chunks
+            .into_values()
+            .flat_map(|v| v.files)
+            .map(|v| v.filename)
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(files(false), vec!["game.exe"]);
+    assert_eq!(
+        files(true),
+        vec![".fseventsd/log", "data/.DS_Store", "game.exe"]
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn generation_reports_stats() {
+    let dir = temp_dir("stats");
+    std::fs::write(dir.join("a.bin"), vec![1u8; 300 * 1024]).unwrap();
+    std::fs::write(dir.join("b.bin"), vec![2u8; 100]).unwrap();
+
+    let messages = std::sync::Mutex::new(Vec::new());
+    let (manifest, stats) = block_on(generate_manifest_with_stats(
+        &dir,
+        |_| {},
+        |message| messages.lock().unwrap().push(message),
+        ManifestOptions {
+            chunk_size: 128 * 1024,
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+
+    assert_eq!(stats.total_read_bytes, manifest.size);
+    assert_eq!(stats.chunks, manifest.chunks.len());
+    assert!(stats.throughput() > 0.0);
+    assert!(stats.workers >= 1);
+    assert!(stats.peak_concurrent_chunks >= 1);
+    assert!(stats.peak_concurrent_chunks <= manifest.chunks.len() as u64);
+    assert!(messages.lock().unwrap()[0].starts_with("using "));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_outside_size_range_are_excluded() {
+    let dir = temp_dir("size-range");
+    std::fs::write(dir.join("config.ini"), b"fullscreen").unwrap();
+    std::fs::write(dir.join("textures.pak"), vec![0u8; 4096]).unwrap();
+
+    let filenames = |min_file_size, max_file_size| {
+        let manifest = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                min_file_size,
+                max_file_size,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        let names: Vec<String> = manifest
+            .chunks
+            .into_values()
+            .flat_map(|v| v.files)
+            .map(|v| v.filename)
+            .collect();
+        names
+    };
+
+    assert_eq!(filenames(Some(1024), None), vec!["textures.pak"]);
+    assert_eq!(filenames(None, Some(1024)), vec!["config.ini"]);
+    // Bounds are inclusive
+    assert_eq!(filenames(Some(10), Some(10)), vec!["config.ini"]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn manifest_versions_are_checked() {
+    let v1 = r#"{
+        "version": "1",
+        "chunks": {
+            "a": {
+                "files": [{"filename": "game.exe", "start": 0, "length": 42, "permissions": 493}],
+                "checksum": "00",
+                "iv": [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+            }
+        },
+        "size": 42,
+        "key": [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    }"#;
+    let manifest = Manifest::from_json_checked(v1).unwrap();
+    assert_eq!(manifest.version, MANIFEST_VERSION);
+    assert_eq!(manifest.chunks["a"].length, 42);
+    assert_eq!(manifest.hash, HashAlgorithm::Sha256);
+
+    let v3 = v1.replace(r#""version": "1""#, r#""version": "3""#);
+    assert!(matches!(
+        Manifest::from_json_checked(&v3),
+        Err(ManifestVersionError::Unsupported(version)) if version == "3"
+    ));
+    assert!(matches!(
+        Manifest::from_json_checked(r#"{"chunks": {}}"#),
+        Err(ManifestVersionError::Missing)
+    ));
+    assert!(matches!(
+        Manifest::from_json_checked("{"),
+        Err(ManifestVersionError::Invalid(_))
+    ));
+}
+
+#[test]
+fn seeded_random_makes_generation_reproducible() {
+    let dir = temp_dir("seeded");
+    let output_dir = temp_dir("seeded-chunks");
+    std::fs::write(dir.join("a.bin"), vec![5u8; 200 * 1024]).unwrap();
+    std::fs::write(dir.join("b.bin"), vec![6u8; 1000]).unwrap();
+
+    let generate = |seed| {
+        let manifest = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 64 * 1024,
+                output_dir: Some(output_dir.clone()),
+                encrypt: true,
+                random: Arc::new(SeededRandomSource::new(seed)),
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        serde_json::to_value(&manifest).unwrap()
+    };
+
+    assert_eq!(generate(1), generate(1));
+    assert_ne!(generate(1), generate(2));
+
+    let root_ca = generate_root_ca().unwrap();
+    let serials = |seed| {
+        generate_client_certificates_with_random(
+            &[("client-1".to_string(), "Laptop".to_string())],
+            root_ca[0].clone(),
+            root_ca[1].clone(),
+            &DistinguishedNameParams::default(),
+            &SeededRandomSource::new(seed),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|v| v.serial)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(serials(7), serials(7));
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(output_dir).unwrap();
+}
+
+#[test]
+fn parallel_verification_reports_each_chunk() {
+    let dir = temp_dir("verify-parallel");
+    for index in 0..6 {
+        std::fs::write(
+            dir.join(format!("asset{}.pak", index)),
+            vec![index as u8; 64 * 1024],
+        )
+        .unwrap();
+    }
+
+    block_on(async {
+        let manifest = generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 64 * 1024,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        std::fs::write(dir.join("asset3.pak"), vec![9u8; 64 * 1024]).unwrap();
+
+        for concurrency in [1, 4] {
+            let results = verify_manifest_parallel(&dir, &manifest, concurrency)
+                .await
+                .unwrap();
+            assert_eq!(results.len(), manifest.chunks.len());
+            let failed: Vec<&String> = results
+                .iter()
+                .filter(|(_, valid)| !**valid)
+                .map(|(chunk_id, _)| chunk_id)
+                .collect();
+            assert_eq!(failed.len(), 1);
+            assert_eq!(manifest.chunks[failed[0]].files[0].filename, "asset3.pak");
+        }
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn concurrent_ranged_reads_share_one_handle() {
+    let dir = temp_dir("positional");
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|v: u32| (v % 251) as u8).collect();
+    std::fs::write(dir.join("large.pak"), &data).unwrap();
+    let backend = PathVersionBackend::new(dir.clone());
+
+    block_on(async {
+        let file = backend.peek_file("large.pak".to_string()).await.unwrap();
+        let chunk_size = 300 * 1024;
+        let reads = (0..data.len()).step_by(chunk_size).map(|start| {
+            let (backend, file) = (&backend, &file);
+            let end = (start + chunk_size).min(data.len());
+            async move {
+                let mut contents = Vec::new();
+                backend
+                    .reader(file, start as u64, Some(end as u64))
+                    .await
+                    .unwrap()
+                    .read_to_end(&mut contents)
+                    .await
+                    .unwrap();
+                (start, end, contents)
+            }
+        });
+
+        for (start, end, contents) in futures::future::join_all(reads).await {
+            assert_eq!(contents, &data[start..end]);
+        }
+    });
+    #[cfg(unix)]
+    assert_eq!(backend.handles.len(), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn executables_get_executable_permissions() {
+    let elf = [0x7f, b'E', b'L', b'F', 2, 1, 1, 0];
+    assert_eq!(synthesized_permission("bin/game", &elf), 0o755);
+    assert_eq!(synthesized_permission("Game.EXE", b""), 0o755);
+    assert_eq!(synthesized_permission("launch.bat", b"@echo off"), 0o755);
+    assert_eq!(synthesized_permission("bin/game.dll", b"MZ\x90\x00"), 0o755);
+    assert_eq!(
+        synthesized_permission("Game.app/Contents/MacOS/Game", &[0xcf, 0xfa, 0xed, 0xfe]),
+        0o755
+    );
+
+    assert_eq!(synthesized_permission("readme.txt", b"hello"), 0o644);
+    assert_eq!(synthesized_permission("exe", b""), 0o644);
+    assert_eq!(synthesized_permission("data/empty.pak", b""), 0o644);
+}
+
+#[cfg(unix)]
+#[test]
+fn sparse_files_are_detected() {
+    let dir = temp_dir("sparse");
+    std::fs::write(dir.join("dense.bin"), vec![1u8; 1024 * 1024]).unwrap();
+    // A hole, without anything written
+    std::fs::File::create(dir.join("save.dat"))
+        .unwrap()
+        .set_len(1024 * 1024)
+        .unwrap();
+    std::fs::write(dir.join("small.txt"), b"hello").unwrap();
+
+    block_on(async {
+        let backend = PathVersionBackend::new(dir.clone());
+        let sparse = |name: &str| {
+            let backend = &backend;
+            let name = name.to_string();
+            async move { backend.peek_file(name).await.unwrap().is_sparse }
+        };
+        assert!(sparse("save.dat").await);
+        assert!(!sparse("dense.bin").await);
+        assert!(!sparse("small.txt").await);
+
+        let (manifest, _) = generate_manifest_with_backend(
+            Box::new(PathVersionBackend::new(dir.clone())),
+            |_| {},
+            |_| {},
+            ManifestOptions::default(),
+        )
+        .await
+        .unwrap();
+        let entries: Vec<&FileEntry> = manifest.chunks.values().flat_map(|v| &v.files).collect();
+        let save = entries.iter().find(|v| v.filename == "save.dat").unwrap();
+        assert!(save.sparse && save.length == 1024 * 1024);
+        assert!(entries
+            .iter()
+            .filter(|v| v.filename != "save.dat")
+            .all(|v| !v.sparse));
+        let version_files = manifest.version_files();
+        assert!(version_files
+            .iter()
+            .any(|v| v.relative_filename == "save.dat" && v.is_sparse));
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_are_peeked_together() {
+    let dir = temp_dir("peek-files");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("game.exe"), [1u8; 2048]).unwrap();
+    std::fs::write(dir.join("data/level1.pak"), [2u8; 4096]).unwrap();
+    let paths = vec![
+        "data/level1.pak".to_string(),
+        "game.exe".to_string(),
+        "data/level1.pak".to_string(),
+    ];
+
+    block_on(async {
+        let backend = PathVersionBackend::new(dir.clone());
+        let files = backend.peek_files(&paths).await.unwrap();
+        let sizes: Vec<(&str, u64)> = files
+            .iter()
+            .map(|v| (v.relative_filename.as_str(), v.size))
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                ("data/level1.pak", 4096),
+                ("game.exe", 2048),
+                ("data/level1.pak", 4096)
+            ]
+        );
+        assert!(backend
+            .peek_files(&["missing.bin".to_string()])
+            .await
+            .is_err());
+
+        // The default, peeking one at a time
+        let memory = MemoryBackend::new(&[("a.bin", &[1u8; 3]), ("b.bin", &[2u8; 5])]);
+        let files = memory
+            .peek_files(&["b.bin".to_string(), "a.bin".to_string()])
+            .await
+            .unwrap();
+        assert_eq!((files[0].size, files[1].size), (5, 3));
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn path_backends_bound_open_files() {
+    let dir = temp_dir("open-files");
+    for index in 0..50 {
+        std::fs::write(dir.join(format!("{}.bin", index)), [index as u8; 1024]).unwrap();
+    }
+    let backend = PathVersionBackend::with_max_open_files(dir.clone(), 2);
+
+    block_on(async {
+        let files = backend.list_files().await.unwrap();
+        let first = backend.reader(&files[0], 0, None).await.unwrap();
+        let _second = backend.reader(&files[1], 0, None).await.unwrap();
+        let mut third = backend.reader(&files[2], 0, None);
+        assert!(futures::FutureExt::now_or_never(&mut third).is_none());
+        drop(first);
+        assert!(third.await.is_ok());
+    });
+    #[cfg(unix)]
+    assert!(backend.handles.len() <= 2);
+
+    let (manifest, _) = block_on(generate_manifest_with_backend(
+        Box::new(backend),
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            chunk_size: 1024,
+            wiggle: Some(0),
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+    assert_eq!(manifest.chunks.len(), 50);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn chunk_reader_joins_file_ranges() {
+    let dir = temp_dir("chunk-reader");
+    std::fs::write(dir.join("a.bin"), b"aaaaHEAD").unwrap();
+    std::fs::write(dir.join("b.bin"), b"-middle-").unwrap();
+    std::fs::write(dir.join("c.bin"), b"TAILcccc").unwrap();
+    let backend = PathVersionBackend::new(dir.clone());
+
+    let entry = |filename: &str, start, length| FileEntry {
+        filename: filename.to_string(),
+        start,
+        length,
+        permissions: 0o644,
+        symlink: None,
+        sparse: false,
+    };
+    let mut chunk = ChunkData {
+        files: vec![
+            entry("a.bin", 4, 4),
+            entry("b.bin", 0, 8),
+            entry("c.bin", 0, 4),
+        ],
+        checksum: String::new(),
+        iv: [0u8; 16],
+        length: 16,
+        compression: Compression::None,
+        stored_length: None,
+        stored_checksum: None,
+    };
+
+    block_on(async {
+        let mut contents = Vec::new();
+        ChunkReader::new(&backend, &chunk)
+            .read_to_end(&mut contents)
+            .await
+            .unwrap();
+        assert_eq!(contents, b"HEAD-middle-TAIL");
+
+        // c.bin only has 8 bytes
+        chunk.files[2].length = 12;
+        let err = ChunkReader::new(&backend, &chunk)
+            .read_to_end(&mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn repair_rewrites_only_corrupt_chunks() {
+    let pristine = temp_dir("repair-pristine");
+    std::fs::create_dir_all(pristine.join("data")).unwrap();
+    std::fs::write(pristine.join("data/level1.pak"), vec![1u8; 96 * 1024]).unwrap();
+    std::fs::write(pristine.join("data/level2.pak"), vec![2u8; 40 * 1024]).unwrap();
+    std::fs::write(pristine.join("game.exe"), vec![3u8; 20 * 1024]).unwrap();
+
+    let install = temp_dir("repair-install");
+    std::fs::create_dir_all(install.join("data")).unwrap();
+    for file in ["data/level1.pak", "data/level2.pak", "game.exe"] {
+        std::fs::copy(pristine.join(file), install.join(file)).unwrap();
+    }
+
+    block_on(async {
+        let manifest = generate_manifest_with_options(
+            &pristine,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 32 * 1024,
+                wiggle: Some(0),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let mut corrupted = std::fs::read(install.join("data/level1.pak")).unwrap();
+        corrupted[40 * 1024] = 0xff;
+        std::fs::write(install.join("data/level1.pak"), corrupted).unwrap();
+        std::fs::remove_file(install.join("game.exe")).unwrap();
+        let broken = verify_manifest(&install, &manifest).await.unwrap();
+        assert!(!broken.is_empty());
+
+        let backend = PathVersionBackend::new(pristine.clone());
+        let fetched = std::sync::Mutex::new(Vec::new());
+        let fetch = |chunk_id: String| {
+            fetched.lock().unwrap().push(chunk_id.clone());
+            let (backend, chunk) = (&backend, &manifest.chunks[&chunk_id]);
+            async move {
+                let mut contents = Vec::new();
+                ChunkReader::new(backend, chunk)
+                    .read_to_end(&mut contents)
+                    .await?;
+                anyhow::Ok(contents)
+            }
+        };
+
+        let repaired = repair_install(&install, &manifest, fetch).await.unwrap();
+        assert_eq!(repaired, broken);
+        let mut fetched = fetched.into_inner().unwrap();
+        fetched.sort();
+        assert_eq!(fetched, broken);
+        assert!(verify_manifest(&install, &manifest)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            std::fs::read(install.join("game.exe")).unwrap(),
+            std::fs::read(pristine.join("game.exe")).unwrap()
+        );
+
+        // Bad bytes from the fetcher are rejected
+        std::fs::remove_file(install.join("game.exe")).unwrap();
+        let bad = |_| async { anyhow::Ok(vec![0u8; 20 * 1024]) };
+        assert!(repair_install(&install, &manifest, bad).await.is_err());
+    });
+
+    std::fs::remove_dir_all(pristine).unwrap();
+    std::fs::remove_dir_all(install).unwrap();
+}
+
+#[test]
+fn verification_reports_progress() {
+    let dir = temp_dir("verify-progress");
+    std::fs::write(dir.join("big.pak"), vec![1u8; 96 * 1024]).unwrap();
+    std::fs::write(dir.join("small.pak"), vec![2u8; 32 * 1024]).unwrap();
+
+    block_on(async {
+        let manifest = generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 32 * 1024,
+                wiggle: Some(0),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        std::fs::write(dir.join("small.pak"), vec![3u8; 32 * 1024]).unwrap();
+
+        let progress = std::cell::RefCell::new(Vec::new());
+        let logs = std::cell::RefCell::new(Vec::new());
+        let results = verify_manifest_with_progress(
+            &dir,
+            &manifest,
+            2,
+            |v| progress.borrow_mut().push(v),
+            |v| logs.borrow_mut().push(v),
+        )
+        .await
+        .unwrap();
+
+        let progress = progress.into_inner();
+        assert_eq!(progress.len(), manifest.chunks.len());
+        assert!(progress.windows(2).all(|v| v[0] < v[1]));
+        assert_eq!(progress.last(), Some(&100.0));
+
+        let logs = logs.into_inner();
+        assert_eq!(logs.len(), manifest.chunks.len());
+        let failed: Vec<&String> = logs.iter().filter(|v| v.starts_with("failed")).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(results.values().filter(|v| !**v).count(), 1);
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn relative_paths_are_stored_with_forward_slashes() {
+    assert_eq!(
+        portable_path("Data\\Maps\\level1.pak", '\\'),
+        "Data/Maps/level1.pak"
+    );
+    assert_eq!(portable_path("config.ini", '\\'), "config.ini");
+    // A backslash is an ordinary filename character where it isn't the separator
+    assert_eq!(
+        portable_path("Data/odd\\name.txt", '/'),
+        "Data/odd\\name.txt"
+    );
+
+    assert_eq!(
+        native_path("Data/Maps/level1.pak"),
+        Path::new("Data").join("Maps").join("level1.pak")
+    );
+
+    let dir = temp_dir("portable");
+    std::fs::create_dir_all(dir.join("Data").join("Maps")).unwrap();
+    std::fs::write(dir.join("Data").join("Maps").join("level1.pak"), b"map").unwrap();
+    let backend = PathVersionBackend::new(dir.clone());
+    let files = block_on(backend.list_files()).unwrap();
+    assert_eq!(files[0].relative_filename, "Data/Maps/level1.pak");
+    let peeked = block_on(backend.peek_file(files[0].relative_filename.clone())).unwrap();
+    assert_eq!(peeked.size, 3);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn unportable_filenames_are_detected() {
+    assert_eq!(filename_problem("level1.pak"), None);
+    assert_eq!(filename_problem(".config"), None);
+    assert_eq!(filename_problem("CONSOLE.txt"), None);
+    assert_eq!(filename_problem("COM10"), None);
+    assert_eq!(
+        filename_problem("save:1.dat"),
+        Some(FilenameProblem::ReservedCharacter(':'))
+    );
+    assert_eq!(
+        filename_problem("what?.txt"),
+        Some(FilenameProblem::ReservedCharacter('?'))
+    );
+    assert_eq!(
+        filename_problem("tab\there"),
+        Some(FilenameProblem::ControlCharacter('\t'))
+    );
+    assert_eq!(
+        filename_problem("readme."),
+        Some(FilenameProblem::TrailingDotOrSpace)
+    );
+    assert_eq!(
+        filename_problem("notes "),
+        Some(FilenameProblem::TrailingDotOrSpace)
+    );
+    for name in ["CON", "nul", "Aux.txt", "lpt1", "COM9.log", "prn .tar.gz"] {
+        assert!(
+            matches!(
+                filename_problem(name),
+                Some(FilenameProblem::ReservedName(_))
+            ),
+            "{}",
+            name
+        );
+    }
+
+    let files: Vec<VersionFile> = ["data/ok.pak", "data/aux/level.pak", "bad*/file"]
+        .iter()
+        .map(|v| VersionFile {
+            relative_filename: v.to_string(),
+            permission: 0o644,
+            size: 1,
+            symlink: None,
+            is_sparse: false,
+        })
+        .collect();
+    assert_eq!(
+        unportable_filenames(&files),
+        vec![
+            (
+                "data/aux/level.pak".to_string(),
+                FilenameProblem::ReservedName("AUX".to_string())
+            ),
+            (
+                "bad*/file".to_string(),
+                FilenameProblem::ReservedCharacter('*')
+            ),
+        ]
+    );
+}
+
+#[cfg(not(windows))]
+#[test]
+fn strict_filenames_fail_generation() {
+    let dir = temp_dir("strict-filenames");
+    std::fs::write(dir.join("game.exe"), b"game").unwrap();
+    std::fs::write(dir.join("save:1.dat"), b"save").unwrap();
+
+    let logs = std::sync::Mutex::new(Vec::new());
+    block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |v| logs.lock().unwrap().push(v),
+        ManifestOptions::default(),
+    ))
+    .unwrap();
+    assert!(logs
+        .into_inner()
+        .unwrap()
+        .iter()
+        .any(|v| v.starts_with("warning: save:1.dat")));
+
+    assert!(block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            strict_filenames: true,
+            ..Default::default()
+        },
+    ))
+    .is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn empty_sources_warn_or_fail() {
+    let dir = temp_dir("empty-source");
+
+    let logs = std::sync::Mutex::new(Vec::new());
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |v| logs.lock().unwrap().push(v),
+        ManifestOptions::default(),
+    ))
+    .unwrap();
+    assert!(manifest.chunks.is_empty());
+    assert_eq!(manifest.size, 0);
+    assert!(logs
+        .into_inner()
+        .unwrap()
+        .iter()
+        .any(|v| v.starts_with("warning: no files to manifest")));
+
+    let err = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            fail_on_empty: true,
+            ..Default::default()
+        },
+    ))
+    .err()
+    .unwrap();
+    assert_eq!(
+        err.downcast_ref::<BackendError>(),
+        Some(&BackendError::EmptySource)
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn archive_listing_enforces_limits() {
+    let fixture = Path::new("testfiles/limits/declared_huge_size.tar");
+
+    block_on(async {
+        let backend = ZipVersionBackend::new(fixture.to_path_buf()).unwrap();
+        let err = backend.list_files().await.unwrap_err();
+        assert!(err.to_string().contains("declares more than"), "{}", err);
+
+        let backend = ZipVersionBackend::with_limits(
+            fixture.to_path_buf(),
+            ArchiveLimits {
+                max_entries: 0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let err = backend.list_files().await.unwrap_err();
+        assert!(err.to_string().contains("more than 0 files"), "{}", err);
+
+        assert!(generate_manifest_with_options(
+            fixture,
+            |_| {},
+            |_| {},
+            ManifestOptions::default()
+        )
+        .await
+        .is_err());
+    });
+}
+
+#[test]
+fn closing_a_path_backend_releases_pooled_handles() {
+    let dir = temp_dir("close");
+    std::fs::write(dir.join("data.bin"), b"0123456789").unwrap();
+    let backend = PathVersionBackend::new(dir.clone());
+    let handles = backend.handles.clone();
+
+    block_on(async {
+        let file = backend.peek_file("data.bin".to_string()).await.unwrap();
+        let mut contents = Vec::new();
+        let mut reader = backend.reader(&file, 2, Some(6)).await.unwrap();
+        #[cfg(unix)]
+        assert_eq!(handles.len(), 1);
+
+        Box::new(backend).close().await.unwrap();
+        assert!(handles.is_empty());
+        // Readers opened before closing still work
+        reader.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents, b"2345");
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn content_defined_chunks_survive_an_inserted_prefix() {
+    let mut contents = vec![0u8; 2 * 1024 * 1024];
+    SeededRandomSource::new(7).fill(&mut contents).unwrap();
+    let mut edited = b"patch notes: v1.0.1\n".to_vec();
+    edited.extend_from_slice(&contents);
+
+    let checksums = |data: &[u8], chunking: ChunkingStrategy| {
+        let dir = temp_dir("cdc");
+        std::fs::write(dir.join("game.pak"), data).unwrap();
+        let manifest = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 64 * 1024,
+                chunking,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        assert!(manifest.validate_structure().is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+
+        manifest
+            .chunks
+            .into_values()
+            .map(|v| v.checksum)
+            .collect::<HashSet<String>>()
+    };
+    let changed = |chunking| {
+        let before = checksums(&contents, chunking);
+        let after = checksums(&edited, chunking);
+        (after.difference(&before).count(), after.len())
+    };
+
+    let cdc = ChunkingStrategy::ContentDefined {
+        min: 16 * 1024,
+        avg: 64 * 1024,
+        max: 256 * 1024,
+    };
+    let (cdc_changed, cdc_chunks) = changed(cdc);
+    assert!(cdc_chunks > 10);
+    assert_eq!(cdc_changed, 1);
+
+    // Fixed-size boundaries all shift
+    let (fixed_changed, fixed_chunks) = changed(ChunkingStrategy::Fixed);
+    assert_eq!(fixed_changed, fixed_chunks);
+
+    assert_eq!(
+        "cdc:1:2:3".parse::<ChunkingStrategy>().unwrap().to_string(),
+        "cdc:1:2:3"
+    );
+    assert!("cdc:4:2:3".parse::<ChunkingStrategy>().is_err());
+    assert_eq!(
+        "fixed".parse::<ChunkingStrategy>().unwrap(),
+        ChunkingStrategy::Fixed
+    );
+}
+
+#[test]
+fn downloaded_chunk_bytes_are_verified() {
+    let dir = temp_dir("chunk-bytes");
+    std::fs::write(dir.join("a.bin"), vec![4u8; 50 * 1024]).unwrap();
+    std::fs::write(dir.join("b.bin"), vec![5u8; 10 * 1024]).unwrap();
+
+    for hash in [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::HmacSha256,
+    ] {
+        let manifest = block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                hash,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        let chunk = manifest.chunks.values().next().unwrap();
+        let mut bytes = vec![4u8; 50 * 1024];
+        bytes.extend_from_slice(&[5u8; 10 * 1024]);
+
+        assert_eq!(
+            hash_chunk(&bytes, hash, &manifest.hash_key()),
+            chunk.checksum
+        );
+        assert!(verify_chunk_bytes(chunk, &bytes, hash, &manifest.hash_key()).unwrap());
+        assert!(!verify_chunk_bytes(chunk, &bytes[1..], hash, &manifest.hash_key()).unwrap());
+        bytes[100] = 0;
+        assert!(!verify_chunk_bytes(chunk, &bytes, hash, &manifest.hash_key()).unwrap());
+    }
+
+    assert!(checksums_match("abcd", "abcd"));
+    assert!(!checksums_match("abcd", "abce"));
+    assert!(!checksums_match("abcd", "abc"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn archive_listing_keeps_symlinks() {
+    let fixture = Path::new("testfiles/symlinks/symlink_in_tar.tar");
+
+    block_on(async {
+        let backend = ZipVersionBackend::new(fixture.to_path_buf()).unwrap();
+        let files = backend.list_files().await.unwrap();
+        let link = files
+            .iter()
+            .find(|v| v.relative_filename == "start.sh")
+            .unwrap();
+        assert_eq!(link.symlink.as_deref(), Some("bin/game.sh"));
+        assert_eq!(link.size, 0);
+
+        let manifest =
+            generate_manifest_with_options(fixture, |_| {}, |_| {}, ManifestOptions::default())
+                .await
+                .unwrap();
+        let entries: Vec<&FileEntry> = manifest.chunks.values().flat_map(|v| &v.files).collect();
+        let link = entries.iter().find(|v| v.filename == "start.sh").unwrap();
+        assert_eq!(link.symlink.as_deref(), Some("bin/game.sh"));
+        assert_eq!(link.length, 0);
+        let script = entries
+            .iter()
+            .find(|v| v.filename == "bin/game.sh")
+            .unwrap();
+        assert_eq!((script.symlink.as_deref(), script.length), (None, 19));
+    });
+}
+
+#[test]
+fn archive_listing_reads_from_bytes() {
+    let fixture = Path::new("testfiles/symlinks/symlink_in_tar.tar");
+    let bytes = std::fs::read(fixture).unwrap();
+
+    block_on(async {
+        let listing = |mut files: Vec<VersionFile>| -> Vec<(String, u64, Option<String>)> {
+            files.sort_by(|a, b| a.relative_filename.cmp(&b.relative_filename));
+            files
+                .into_iter()
+                .map(|v| (v.relative_filename, v.size, v.symlink))
+                .collect()
+        };
+        let from_path = ZipVersionBackend::new(fixture.to_path_buf()).unwrap();
+        let from_bytes = ZipVersionBackend::from_bytes(bytes.clone()).unwrap();
+        assert_eq!(
+            listing(from_bytes.list_files().await.unwrap()),
+            listing(from_path.list_files().await.unwrap())
+        );
+
+        let (from_path, _) = generate_manifest_with_backend(
+            Box::new(from_path),
+            |_| {},
+            |_| {},
+            ManifestOptions::default(),
+        )
+        .await
+        .unwrap();
+        let (from_bytes, _) = generate_manifest_with_backend(
+            Box::new(ZipVersionBackend::from_bytes(bytes).unwrap()),
+            |_| {},
+            |_| {},
+            ManifestOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(from_bytes.to_bom(), from_path.to_bom());
+    });
+}
+
+#[test]
+fn archive_listing_peeks_files_together() {
+    let fixture = Path::new("testfiles/symlinks/symlink_in_tar.tar");
+
+    block_on(async {
+        let backend = ZipVersionBackend::new(fixture.to_path_buf()).unwrap();
+        let files = backend
+            .peek_files(&["bin/game.sh".to_string(), "start.sh".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(files[0].size, 19);
+        assert_eq!(files[1].symlink.as_deref(), Some("bin/game.sh"));
+        assert!(backend.peek_file("missing.sh".to_string()).await.is_err());
+    });
+}
+
+#[test]
+fn archive_listing_streams_from_a_reader() {
+    let fixture = Path::new("testfiles/symlinks/symlink_in_tar.tar");
+    // Paths, sizes and permissions, as chunk checksums depend on packing order
+    let listing = |manifest: &Manifest| -> Vec<String> {
+        manifest
+            .to_bom()
+            .lines()
+            .map(|line| line.rsplit_once('\t').unwrap().0.to_owned())
+            .collect()
+    };
+
+    block_on(async {
+        let listed =
+            generate_manifest_with_options(fixture, |_| {}, |_| {}, ManifestOptions::default())
+                .await
+                .unwrap();
+        let (streamed, stats) = generate_manifest_from_reader(
+            std::fs::File::open(fixture).unwrap(),
+            |_| {},
+            ManifestOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(listing(&streamed), listing(&listed));
+        assert_eq!(streamed.size, listed.size);
+        assert_eq!(stats.total_read_bytes, listed.size);
+        assert!(!streamed.seekable);
+        assert!(streamed.validate_structure().is_ok());
+    });
+}
+
+#[test]
+fn archive_listing_streams_leave_out_hidden_files() {
+    let fixture = Path::new("testfiles/hidden/dotfiles.tar");
+    let filenames = |manifest: &Manifest| {
+        let mut filenames: Vec<String> = manifest
+            .chunks
+            .values()
+            .flat_map(|v| &v.files)
+            .map(|v| v.filename.clone())
+            .collect();
+        filenames.sort();
+        filenames
+    };
+
+    block_on(async {
+        let stream = |include_hidden| async move {
+            let options = ManifestOptions {
+                include_hidden,
+                ..Default::default()
+            };
+            let file = std::fs::File::open(fixture).unwrap();
+            generate_manifest_from_reader(file, |_| {}, options)
+                .await
+                .unwrap()
+                .0
+        };
+        assert_eq!(filenames(&stream(false).await), ["game.exe"]);
+        assert_eq!(
+            filenames(&stream(true).await),
+            [".steam/config.vdf", "game.exe", "save/.cache"]
+        );
+    });
+}
+
+#[test]
+fn archive_listing_streams_check_files_before_writing_chunks() {
+    block_on(async {
+        let stream = |fixture: &str, options: ManifestOptions| {
+            let file = std::fs::File::open(fixture).unwrap();
+            generate_manifest_from_reader(file, |_| {}, options)
+        };
+
+        let output_dir = temp_dir("stream-strict-output");
+        std::fs::remove_dir(&output_dir).unwrap();
+        let strict = ManifestOptions {
+            strict_filenames: true,
+            output_dir: Some(output_dir.clone()),
+            ..Default::default()
+        };
+        let err = stream("testfiles/strict/unportable.tar", strict)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("saves/slot:1.sav"));
+        assert!(!output_dir.exists());
+
+        let fail_on_empty = ManifestOptions {
+            fail_on_empty: true,
+            output_dir: Some(output_dir.clone()),
+            ..Default::default()
+        };
+        let err = stream("testfiles/strict/empty.tar", fail_on_empty)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<BackendError>(),
+            Some(&BackendError::EmptySource)
+        );
+        assert!(!output_dir.exists());
+    });
+}
+
+#[test]
+fn dotfile_components_are_hidden() {
+    assert!(has_dotfile_component(".steam/config.vdf"));
+    assert!(has_dotfile_component("save/.cache"));
+    assert!(!has_dotfile_component("bin/game.exe"));
+    assert!(!has_dotfile_component("../game.exe"));
+}
+
+#[cfg(unix)]
+#[test]
+fn repair_restores_symlinks() {
+    let dir = temp_dir("repair-symlink");
+    std::fs::write(dir.join("game.sh"), b"echo game").unwrap();
+
+    block_on(async {
+        let mut manifest =
+            generate_manifest_with_options(&dir, |_| {}, |_| {}, ManifestOptions::default())
+                .await
+                .unwrap();
+        let chunk_id = manifest.chunks.keys().next().unwrap().clone();
+        manifest
+            .chunks
+            .get_mut(&chunk_id)
+            .unwrap()
+            .files
+            .push(FileEntry {
+                filename: "start.sh".to_string(),
+                start: 0,
+                length: 0,
+                permissions: 0o777,
+                symlink: Some("game.sh".to_string()),
+                sparse: false,
+            });
+        // Symlinks add no bytes, so the checksum still holds
+        assert!(verify_manifest(&dir, &manifest).await.unwrap().is_empty());
+
+        std::fs::write(dir.join("game.sh"), b"echo gone").unwrap();
+        let repaired = repair_install(&dir, &manifest, |_| async {
+            anyhow::Ok(b"echo game".to_vec())
+        })
+        .await
+        .unwrap();
+        assert_eq!(repaired, vec![chunk_id]);
+    });
+    assert_eq!(
+        std::fs::read_link(dir.join("start.sh")).unwrap(),
+        Path::new("game.sh")
+    );
+    assert_eq!(std::fs::read(dir.join("start.sh")).unwrap(), b"echo game");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn repair_rejects_filenames_outside_the_install() {
+    let root = temp_dir("repair-traversal");
+    let dir = root.join("install");
+    let manifest = structure_manifest(&[("a", &[("../escaped.txt", 0, 4)])]);
+
+    let err = block_on(repair_install(&dir, &manifest, |_| async {
+        anyhow::Ok(b"evil".to_vec())
+    }))
+    .unwrap_err();
+    assert!(err.to_string().contains("../escaped.txt"), "{}", err);
+    assert!(!root.join("escaped.txt").exists());
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn repair_never_writes_through_symlinks() {
+    let root = temp_dir("repair-symlink-parent");
+    let (dir, outside) = (root.join("install"), root.join("outside"));
+    std::fs::create_dir_all(dir.join("a")).unwrap();
+    std::fs::create_dir(&outside).unwrap();
+    std::fs::write(dir.join("a/file"), b"data").unwrap();
+
+    block_on(async {
+        let mut manifest =
+            generate_manifest_with_options(&dir, |_| {}, |_| {}, ManifestOptions::default())
+                .await
+                .unwrap();
+        let chunk = manifest.chunks.values_mut().next().unwrap();
+        chunk.files.insert(
+            0,
+            FileEntry {
+                filename: "a".to_string(),
+                start: 0,
+                length: 0,
+                permissions: 0o777,
+                symlink: Some(outside.to_string_lossy().into_owned()),
+                sparse: false,
+            },
+        );
+        let fetch = |_| async { anyhow::Ok(b"data".to_vec()) };
+
+        // The manifest's own symlink is restored after its files, so can't
+        // be written through, and can't replace the directory they're in
+        std::fs::remove_file(dir.join("a/file")).unwrap();
+        assert!(repair_install(&dir, &manifest, fetch).await.is_err());
+        assert!(std::fs::symlink_metadata(dir.join("a")).unwrap().is_dir());
+
+        // Nor can a symlink already in the install
+        std::fs::remove_dir_all(dir.join("a")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("a")).unwrap();
+        let err = repair_install(&dir, &manifest, fetch).await.unwrap_err();
+        assert!(err.to_string().contains("is a symlink"), "{}", err);
+    });
+    assert!(!outside.join("file").exists());
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn chunks_are_found_by_file_and_prefix() {
+    let manifest = structure_manifest(&[
+        ("a", &[("base/game.exe", 0, 10), ("base/data.pak", 0, 5)]),
+        ("b", &[("base/data.pak", 5, 5), ("DLC/map.pak", 0, 3)]),
+        ("c", &[("DLC/audio/music.ogg", 0, 8)]),
+        ("d", &[("DLC2/other.pak", 0, 4)]),
+    ]);
+
+    assert_eq!(manifest.chunks_for_file("base/data.pak"), vec!["a", "b"]);
+    assert_eq!(manifest.chunks_for_file("base/game.exe"), vec!["a"]);
+    assert!(manifest.chunks_for_file("base").is_empty());
+    assert!(manifest.chunks_for_file("missing.txt").is_empty());
+
+    assert_eq!(manifest.chunks_for_prefix("DLC"), vec!["b", "c"]);
+    assert_eq!(manifest.chunks_for_prefix("DLC/"), vec!["b", "c"]);
+    assert_eq!(manifest.chunks_for_prefix("DLC/audio"), vec!["c"]);
+    assert_eq!(manifest.chunks_for_prefix("DLC2"), vec!["d"]);
+    assert_eq!(manifest.chunks_for_prefix("base/game.exe"), vec!["a"]);
+    assert_eq!(manifest.chunks_for_prefix(""), vec!["a", "b", "c", "d"]);
+}
+
+#[test]
+fn manifest_index_matches_a_linear_scan() {
+    let dir = temp_dir("index");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("data/big.pak"), vec![1u8; 300 * 1024]).unwrap();
+    std::fs::write(dir.join("data/mid.pak"), vec![2u8; 70 * 1024]).unwrap();
+    std::fs::write(dir.join("readme.txt"), b"hello").unwrap();
+
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            chunk_size: 64 * 1024,
+            wiggle: Some(0),
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+    let index = manifest.build_index();
+    assert_eq!(index.len(), 3);
+
+    for filename in ["data/big.pak", "data/mid.pak", "readme.txt"] {
+        let mut expected = Vec::new();
+        for (chunk_id, chunk) in &manifest.chunks {
+            let mut offset = 0;
+            for entry in &chunk.files {
+                if entry.filename == filename {
+                    expected.push((chunk_id.as_str(), offset, entry.start, entry.length));
+                }
+                offset += entry.length;
+            }
+        }
+        expected.sort_by_key(|v| v.2);
+
+        let actual: Vec<(&str, u64, u64, u64)> = index
+            .get(filename)
+            .unwrap()
+            .iter()
+            .map(|v| (v.chunk_id.as_str(), v.offset, v.start, v.length))
+            .collect();
+        assert_eq!(actual, expected);
+
+        let mut chunk_ids: Vec<&str> = actual.iter().map(|v| v.0).collect();
+        chunk_ids.sort();
+        chunk_ids.dedup();
+        assert_eq!(chunk_ids, manifest.chunks_for_file(filename));
+    }
+    assert!(index.get("missing.txt").is_none());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn small_files_can_be_kept_whole() {
+    let mib = 1024 * 1024;
+    let files = vec![
+        version_file("textures.pak", 5 * mib),
+        version_file("game.exe", 2 * mib),
+        version_file("config.ini", mib / 2),
+    ];
+    let chunks_holding = |chunks: &[Vec<(VersionFile, u64, u64)>], name: &str| {
+        chunks
+            .iter()
+            .filter(|chunk| chunk.iter().any(|v| v.0.relative_filename == name))
+            .count()
+    };
+
+    let options = ManifestOptions {
+        chunk_size: 3 * mib / 2,
+        wiggle: Some(0),
+        ..Default::default()
+    };
+    let split = organize_chunks(files.clone(), false, &options);
+    assert!(chunks_holding(&split, "game.exe") > 1);
+
+    let options = ManifestOptions {
+        keep_whole_under: Some(4 * mib),
+        ..options
+    };
+    let kept = organize_chunks(files, false, &options);
+    assert_eq!(chunks_holding(&kept, "game.exe"), 1);
+    assert!(chunks_holding(&kept, "textures.pak") > 1);
+    for chunk in &kept {
+        for (file, start, length) in chunk {
+            if file.relative_filename == "game.exe" {
+                assert_eq!((*start, *length), (0, 2 * mib));
+            }
+        }
+    }
+}
+
+#[test]
+fn files_matching_patterns_are_kept_whole() {
+    let mib = 1024 * 1024;
+    let files = vec![
+        version_file("assets/textures.pak", 5 * mib),
+        version_file("assets/music.ogg", 5 * mib),
+        version_file("game.exe", mib / 2),
+    ];
+    let chunks_holding = |chunks: &[Vec<(VersionFile, u64, u64)>], name: &str| {
+        chunks
+            .iter()
+            .filter(|chunk| chunk.iter().any(|v| v.0.relative_filename == name))
+            .count()
+    };
+
+    let options = ManifestOptions {
+        chunk_size: 3 * mib / 2,
+        wiggle: Some(0),
+        keep_whole: vec!["*.pak".to_string()],
+        ..Default::default()
+    };
+    let chunks = organize_chunks(files, false, &options);
+    assert_eq!(chunks_holding(&chunks, "assets/textures.pak"), 1);
+    assert!(chunks_holding(&chunks, "assets/music.ogg") > 1);
+    let total: u64 = chunks.iter().flatten().map(|v| v.2).sum();
+    assert_eq!(total, 10 * mib + mib / 2);
+
+    let dir = temp_dir("keep-whole-pattern");
+    std::fs::write(dir.join("game.exe"), b"game").unwrap();
+    let options = ManifestOptions {
+        keep_whole: vec!["[".to_string()],
+        ..Default::default()
+    };
+    let err = block_on(plan_manifest(&dir, &options)).err().unwrap();
+    assert!(err.to_string().contains("keep-whole pattern"), "{}", err);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn merkle_roots_commit_to_every_chunk() {
+    let dir = temp_dir("merkle");
+    for index in 0..5u8 {
+        std::fs::write(dir.join(format!("{}.pak", index)), [index; 4096]).unwrap();
+    }
+    let options = ManifestOptions {
+        chunk_size: 4096,
+        wiggle: Some(0),
+        merkle_root: true,
+        ..Default::default()
+    };
+    let mut manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        options,
+    ))
+    .unwrap();
+    assert_eq!(manifest.chunks.len(), 5);
+    let root = manifest.merkle_root.clone().unwrap();
+    assert_eq!(root, manifest.compute_merkle_root());
+    assert!(manifest.validate_structure().is_ok());
+    for (chunk_id, chunk) in &manifest.chunks {
+        let proof = manifest.merkle_proof(chunk_id).unwrap();
+        assert!(verify_merkle_proof(&chunk.checksum, &proof, &root));
+        assert!(!verify_merkle_proof(&"0".repeat(64), &proof, &root));
+    }
+
+    let json = serde_json::to_string(&manifest).unwrap();
+    let parsed: Manifest = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.merkle_root.as_deref(), Some(root.as_str()));
+
+    let chunk_id = manifest.chunks.keys().next().unwrap().clone();
+    manifest.chunks.get_mut(&chunk_id).unwrap().checksum = "0".repeat(64);
+    assert_ne!(manifest.compute_merkle_root(), root);
+    let errors = manifest.validate_structure().unwrap_err();
+    assert!(matches!(
+        errors.as_slice(),
+        [StructuralError::MerkleRootMismatch { .. }]
+    ));
+
+    assert_ne!(merkle_root(&["a", "b", "c"]), merkle_root(&["a", "b", "d"]));
+    assert_ne!(merkle_root(&["a", "b"]), merkle_root(&["b", "a"]));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn content_hashes_ignore_map_order() {
+    let dir = temp_dir("content-hash");
+    for index in 0..8u8 {
+        std::fs::write(dir.join(format!("{}.pak", index)), [index; 1024]).unwrap();
+    }
+    let options = ManifestOptions {
+        chunk_size: 1024,
+        wiggle: Some(0),
+        metadata: HashMap::from([
+            ("build".to_string(), "14029".to_string()),
+            ("branch".to_string(), "beta".to_string()),
+        ]),
+        ..Default::default()
+    };
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        options,
+    ))
+    .unwrap();
+    let json = serde_json::to_string(&manifest).unwrap();
+
+    // Same contents, with the chunks inserted in the opposite order
+    let mut reordered: Manifest = serde_json::from_str(&json).unwrap();
+    let mut chunks: Vec<(String, ChunkData)> = reordered.chunks.drain().collect();
+    chunks.sort_by(|a, b| b.0.cmp(&a.0));
+    reordered.chunks = HashMap::with_capacity(64);
+    reordered.chunks.extend(chunks);
+    let mut metadata: Vec<(String, String)> = reordered.metadata.drain().collect();
+    metadata.reverse();
+    reordered.metadata = metadata.into_iter().collect();
+
+    let hash = manifest.content_hash();
+    assert_eq!(hash.len(), 64);
+    assert_eq!(reordered.content_hash(), hash);
+    let parsed: Manifest = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.content_hash(), hash);
+
+    reordered
+        .metadata
+        .insert("build".to_string(), "14030".to_string());
+    assert_ne!(reordered.content_hash(), hash);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn manifest_metadata_round_trips() {
+    let dir = temp_dir("metadata");
+    std::fs::write(dir.join("game.exe"), b"game").unwrap();
+
+    let plain = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions::default(),
+    ))
+    .unwrap();
+    assert!(plain.metadata.is_empty());
+    assert!(!serde_json::to_string(&plain).unwrap().contains("metadata"));
+
+    let metadata = HashMap::from([
+        ("build".to_string(), "14029".to_string()),
+        ("branch".to_string(), "beta".to_string()),
+    ]);
+    let tagged = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            metadata: metadata.clone(),
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+    let json = serde_json::to_string(&tagged).unwrap();
+    assert_eq!(
+        Manifest::from_json_checked(&json).unwrap().metadata,
+        metadata
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn split_files_reassemble_to_their_hash() {
+    let dir = temp_dir("reconstruct");
+    let contents: Vec<u8> = (0..100 * 1024).map(|v| (v % 251) as u8).collect();
+    std::fs::write(dir.join("big.pak"), &contents).unwrap();
+    std::fs::write(dir.join("small.txt"), b"hello").unwrap();
+
+    block_on(async {
+        let manifest = generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 32 * 1024,
+                wiggle: Some(0),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(manifest.chunks_for_file("big.pak").len() > 1);
+
+        let backend = PathVersionBackend::new(dir.clone());
+        let fetch = |chunk_id: String| {
+            let (backend, chunk) = (&backend, &manifest.chunks[&chunk_id]);
+            async move {
+                let mut contents = Vec::new();
+                ChunkReader::new(backend, chunk)
+                    .read_to_end(&mut contents)
+                    .await?;
+                anyhow::Ok(contents)
+            }
+        };
+
+        let mut hasher = ChunkHasher::new(manifest.hash, &manifest.hash_key());
+        hasher.update(&contents);
+        assert_eq!(
+            reconstruct_file_hash(&manifest, "big.pak", fetch)
+                .await
+                .unwrap(),
+            hasher.finalize()
+        );
+
+        let short = |_| async { anyhow::Ok(vec![0u8; 16]) };
+        assert!(reconstruct_file_hash(&manifest, "big.pak", short)
+            .await
+            .is_err());
+        assert!(reconstruct_file_hash(&manifest, "missing.pak", fetch)
+            .await
+            .is_err());
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn supported_extensions_create_backends() {
+    let dir = temp_dir("extensions");
+    assert!(archive_support_available());
+    let extensions = supported_extensions();
+    assert!(extensions.contains(&"zip") && extensions.contains(&"7z"));
+
+    for extension in extensions {
+        let path = dir.join(format!("game.{}", extension));
+        std::fs::write(&path, b"").unwrap();
+        assert!(create_backend_constructor(&path).is_ok(), "{}", extension);
+    }
+    let path = dir.join("game.txt");
+    std::fs::write(&path, b"").unwrap();
+    assert!(matches!(
+        create_backend_constructor(&path),
+        Err(BackendError::UnsupportedFormat(_))
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_archive_names_create_backends() {
+    use std::os::unix::ffi::OsStrExt as _;
+
+    let dir = temp_dir("non-utf8-extensions");
+    // Latin-1 "spiel", and an extension that only differs in case
+    for name in [&b"sp\xe9l.zip"[..], b"sp\xe9l.ZIP", b"GAME.Tar"] {
+        let path = dir.join(std::ffi::OsStr::from_bytes(name));
+        std::fs::write(&path, b"").unwrap();
+        assert!(create_backend_constructor(&path).is_ok(), "{:?}", path);
+    }
+    let path = dir.join(std::ffi::OsStr::from_bytes(b"game.zi\xe9"));
+    std::fs::write(&path, b"").unwrap();
+    assert!(matches!(
+        create_backend_constructor(&path),
+        Err(BackendError::UnsupportedFormat(_))
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_truncated_after_listing_fail_generation() {
+    let memory = MemoryBackend::new(&[("intact.bin", &[1u8; 1024]), ("shrunk.bin", &[2u8; 1024])])
+        .with_listed_size("shrunk.bin", 4096);
+
+    let err = block_on(generate_manifest_with_backend(
+        Box::new(memory),
+        |_| {},
+        |_| {},
+        ManifestOptions::default(),
+    ))
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("shrunk.bin"), "{}", err);
+
+    let memory = MemoryBackend::new(&[("intact.bin", &[1u8; 1024])]);
+    let (manifest, _) = block_on(generate_manifest_with_backend(
+        Box::new(memory),
+        |_| {},
+        |_| {},
+        ManifestOptions::default(),
+    ))
+    .unwrap();
+    assert_eq!(manifest.size, 1024);
+}
+
+#[test]
+fn manifests_can_be_generated_from_a_file_list() {
+    let memory = MemoryBackend::new(&[
+        ("game.exe", &[1u8; 2048]),
+        ("data/level1.pak", &[2u8; 4096]),
+        ("notes.txt", b"not shipped"),
+    ]);
+    let files = vec![
+        block_on(memory.peek_file("game.exe".to_string())).unwrap(),
+        block_on(memory.peek_file("data/level1.pak".to_string())).unwrap(),
+    ];
+
+    let (manifest, stats) = block_on(generate_manifest_from_files(
+        Box::new(memory),
+        files,
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            // Listing filters don't apply to an explicit list
+            min_file_size: Some(3000),
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+
+    let mut filenames: Vec<&str> = manifest
+        .chunks
+        .values()
+        .flat_map(|v| v.files.iter().map(|v| v.filename.as_str()))
+        .collect();
+    filenames.sort();
+    assert_eq!(filenames, vec!["data/level1.pak", "game.exe"]);
+    assert_eq!(manifest.size, 6144);
+    assert_eq!(stats.total_read_bytes, 6144);
+}
+
+#[test]
+fn slow_logging_doesnt_hold_up_chunks() {
+    let files: Vec<(String, Vec<u8>)> = (0..64)
+        .map(|v| (format!("file{}.bin", v), vec![v as u8; 1024]))
+        .collect();
+    let files: Vec<(&str, &[u8])> = files
+        .iter()
+        .map(|(name, data)| (name.as_str(), data.as_slice()))
+        .collect();
+
+    let (manifest, stats) = block_on(generate_manifest_with_backend(
+        Box::new(MemoryBackend::new(&files)),
+        |_| {},
+        |_| std::thread::sleep(std::time::Duration::from_millis(1)),
+        ManifestOptions {
+            chunk_size: 1024,
+            wiggle: Some(0),
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+    assert_eq!(manifest.chunks.len(), 64);
+    // Chunks finish as soon as they're hashed, rather than waiting their turn to log
+    assert_eq!(stats.peak_concurrent_chunks, 1);
+}
+
+#[test]
+fn manifests_can_be_rechunked() {
+    let source = || {
+        MemoryBackend::new(&[
+            ("game.exe", &[1u8; 40 * 1024]),
+            ("data/level1.pak", &[2u8; 24 * 1024]),
+        ])
+    };
+    let (manifest, _) = block_on(generate_manifest_with_backend(
+        Box::new(source()),
+        |_| {},
+        |_| {},
+        ManifestOptions::default(),
+    ))
+    .unwrap();
+    assert_eq!(manifest.chunks.len(), 1);
+
+    let rechunked = block_on(rechunk_manifest(&manifest, Box::new(source()), 16 * 1024)).unwrap();
+    assert_eq!(rechunked.chunks.len(), 4);
+    assert_eq!(rechunked.size, manifest.size);
+    let listing = |manifest: &Manifest| -> Vec<(String, u64)> {
+        manifest
+            .version_files()
+            .into_iter()
+            .map(|v| (v.relative_filename, v.size))
+            .collect()
+    };
+    assert_eq!(listing(&rechunked), listing(&manifest));
+    assert!(block_on(verify_manifest_against_backend(
+        &rechunked,
+        Box::new(source())
+    ))
+    .unwrap()
+    .is_empty());
+}
+
+#[test]
+fn manifests_are_verified_against_their_source() {
+    let source = || {
+        MemoryBackend::new(&[
+            ("game.exe", &[1u8; 2048]),
+            ("data/level1.pak", &[2u8; 4096]),
+        ])
+    };
+    let (manifest, _) = block_on(generate_manifest_with_backend(
+        Box::new(source()),
+        |_| {},
+        |_| {},
+        ManifestOptions {
+            chunk_size: 2048,
+            wiggle: Some(0),
+            ..Default::default()
+        },
+    ))
+    .unwrap();
+
+    assert!(block_on(verify_manifest_against_backend(
+        &manifest,
+        Box::new(source())
+    ))
+    .unwrap()
+    .is_empty());
+
+    let mut level = vec![2u8; 4096];
+    level[3000] = 0;
+    let changed = MemoryBackend::new(&[("game.exe", &[1u8; 2048]), ("data/level1.pak", &level)]);
+    let failed = block_on(verify_manifest_against_backend(
+        &manifest,
+        Box::new(changed),
+    ))
+    .unwrap();
+    let index = manifest.build_index();
+    let changed_chunk = index
+        .get("data/level1.pak")
+        .unwrap()
+        .iter()
+        .find(|v| (v.start..v.start + v.length).contains(&3000))
+        .unwrap();
+    assert_eq!(failed, vec![changed_chunk.chunk_id.clone()]);
+}
+
+#[test]
+fn generation_errors_name_the_file_and_chunk() {
+    let dir = temp_dir("error-context");
+    std::fs::write(dir.join("game.exe"), [1u8; 2048]).unwrap();
+    let backend = PathVersionBackend::new(dir.clone());
+    let files = block_on(backend.list_files()).unwrap();
+    std::fs::remove_file(dir.join("game.exe")).unwrap();
+
+    let err = block_on(generate_manifest_from_files(
+        Box::new(backend),
+        files,
+        |_| {},
+        |_| {},
+        ManifestOptions::default(),
+    ))
+    .err()
+    .unwrap();
+    assert!(
+        format!("{:#}", err).starts_with("opening game.exe at 0 for chunk 0: "),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn directory_locality_packs_directories_together() {
+    let files = || {
+        vec![
+            version_file("maps/a.pak", 60),
+            version_file("audio/x.ogg", 55),
+            version_file("maps/b.pak", 50),
+            version_file("audio/y.ogg", 45),
+            version_file("maps/extra/c.pak", 40),
+            version_file("audio/z.ogg", 35),
+        ]
+    };
+    // Whether every directory's files sit in one run of neighbouring chunks
+    let contiguous = |plan: &[Vec<(VersionFile, u64, u64)>]| {
+        ["maps/", "audio/"].iter().all(|prefix| {
+            let indices: Vec<usize> = plan
+                .iter()
+                .enumerate()
+                .filter(|(_, chunk)| {
+                    chunk
+                        .iter()
+                        .any(|(file, _, _)| file.relative_filename.starts_with(prefix))
+                })
+                .map(|(index, _)| index)
+                .collect();
+            indices.windows(2).all(|v| v[1] == v[0] + 1)
+        })
+    };
+    let options = |packing| ManifestOptions {
+        chunk_size: 100,
+        wiggle: Some(0),
+        packing,
+        ..Default::default()
+    };
+
+    // Packed whole, so files don't spill into the next chunk
+    let plan = organize_chunks(files(), true, &options(PackingStrategy::SizeDescending));
+    assert!(!contiguous(&plan));
+
+    let plan = organize_chunks(files(), true, &options(PackingStrategy::DirectoryLocality));
+    assert!(contiguous(&plan));
+    let order: Vec<&str> = plan
+        .iter()
+        .flatten()
+        .map(|(file, _, _)| file.relative_filename.as_str())
+        .collect();
+    assert_eq!(order.first(), Some(&"audio/x.ogg"));
+    assert_eq!(order.last(), Some(&"maps/extra/c.pak"));
+    assert_eq!(planned_manifest(plan).validate_structure(), Ok(()));
+
+    let plan = organize_chunks(files(), true, &options(PackingStrategy::Alphabetical));
+    assert_eq!(plan[0][0].0.relative_filename, "audio/x.ogg");
+    assert_eq!(
+        "directory".parse::<PackingStrategy>().unwrap(),
+        PackingStrategy::DirectoryLocality
+    );
+}
+
+#[test]
+fn manifest_keys_are_only_drawn_when_used() {
+    let dir = temp_dir("optional-key");
+    std::fs::write(dir.join("game.exe"), vec![7u8; 4096]).unwrap();
+
+    let generate = |hash| {
+        block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                hash,
+                ..Default::default()
+            },
+        ))
+        .unwrap()
+    };
+    let plain = generate(HashAlgorithm::Sha256);
+    assert_eq!(plain.key, None);
+    assert!(!serde_json::to_string(&plain).unwrap().contains("\"key\""));
+    assert!(generate(HashAlgorithm::HmacSha256).key.is_some());
+
+    // Older manifests always carried a key
+    let mut old = serde_json::to_value(&plain).unwrap();
+    old["key"] = serde_json::json!([3u8; 16].to_vec());
+    let old = Manifest::from_json_checked(&old.to_string()).unwrap();
+    assert_eq!(old.key, Some([3u8; 16]));
+
+    let mut keyless_hmac = serde_json::to_value(&plain).unwrap();
+    keyless_hmac["hash"] = serde_json::json!("hmac-sha256");
+    assert!(matches!(
+        Manifest::from_json_checked(&keyless_hmac.to_string()),
+        Err(ManifestVersionError::Invalid(_))
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn bill_of_materials_is_stable() {
+    let dir = temp_dir("bom");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::fs::write(dir.join("data/level1.pak"), vec![1u8; 80 * 1024]).unwrap();
+    std::fs::write(dir.join("game.exe"), vec![2u8; 10 * 1024]).unwrap();
+    std::fs::write(dir.join("README.txt"), b"readme").unwrap();
+
+    let generate = || {
+        block_on(generate_manifest_with_options(
+            &dir,
+            |_| {},
+            |_| {},
+            ManifestOptions {
+                chunk_size: 32 * 1024,
+                wiggle: Some(0),
+                ..Default::default()
+            },
+        ))
+        .unwrap()
+    };
+    let manifest = generate();
+    let bom = manifest.to_bom();
+    assert_eq!(bom, generate().to_bom());
+
+    let lines: Vec<Vec<&str>> = bom.lines().map(|v| v.split('\t').collect()).collect();
+    let paths: Vec<&str> = lines.iter().map(|v| v[0]).collect();
+    assert_eq!(paths, vec!["README.txt", "data/level1.pak", "game.exe"]);
+    assert_eq!(lines[1][1], (80 * 1024).to_string());
+    let checksums: Vec<&str> = lines[1][3].split(',').collect();
+    assert_eq!(
+        checksums.len(),
+        manifest.chunks_for_file("data/level1.pak").len()
+    );
+    assert!(checksums.len() > 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn generation_progress_tracks_finished_chunks() {
+    let dir = temp_dir("generation-progress");
+    std::fs::write(dir.join("big.pak"), vec![1u8; 100 * 1024]).unwrap();
+    // Collide by case, so warnings are logged alongside the chunk lines
+    std::fs::write(dir.join("Small.txt"), b"small").unwrap();
+    std::fs::write(dir.join("small.txt"), b"collides").unwrap();
+    let empty = temp_dir("generation-progress-empty");
+    std::fs::write(empty.join("a"), b"").unwrap();
+    std::fs::write(empty.join("b"), b"").unwrap();
+
+    for dir in [&dir, &empty] {
+        let progress = std::sync::Mutex::new(Vec::new());
+        let manifest = block_on(generate_manifest_with_options(
+            dir,
+            |v| progress.lock().unwrap().push(v),
+            |_| {},
+            ManifestOptions {
+                chunk_size: 16 * 1024,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+
+        let progress = progress.into_inner().unwrap();
+        assert_eq!(progress.len(), manifest.chunks.len());
+        assert!(progress.windows(2).all(|v| v[0] <= v[1]));
+        assert_eq!(progress.last(), Some(&100.0));
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(empty).unwrap();
+}
+
+#[test]
+fn chunks_over_the_maximum_fail_generation() {
+    let dir = temp_dir("max-chunk-bytes");
+    std::fs::write(dir.join("textures.pak"), vec![7u8; 10_000]).unwrap();
+    std::fs::write(dir.join("game.exe"), b"game").unwrap();
+    let options = |keep_whole: &[&str]| ManifestOptions {
+        chunk_size: 4096,
+        wiggle: Some(0),
+        keep_whole: keep_whole.iter().map(|v| v.to_string()).collect(),
+        max_chunk_bytes: Some(8192),
+        ..Default::default()
+    };
+
+    block_on(plan_manifest(&dir, &options(&[]))).unwrap();
+    let err = block_on(plan_manifest(&dir, &options(&["*.pak"])))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("textures.pak"), "{}", err);
+    assert!(
+        err.to_string().contains("over the maximum of 8192"),
+        "{}",
+        err
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn large_manifests_round_trip_through_files() {
+    let plan = (0..50_000)
+        .map(|index| vec![(version_file(&format!("data/{}.bin", index), 4096), 0, 4096)])
+        .collect();
+    let mut manifest = planned_manifest(plan);
+    for chunk in manifest.chunks.values_mut() {
+        chunk.length = chunk.files.iter().map(|v| v.length).sum();
+    }
+    let dir = temp_dir("write-json");
+    let path = dir.join("manifest.json");
+
+    manifest
+        .write_json(std::fs::File::create(&path).unwrap())
+        .unwrap();
+    let read = Manifest::from_json_reader(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(read.chunks.len(), 50_000);
+    assert_eq!(read.content_hash(), manifest.content_hash());
+
+    manifest.version = "3".to_string();
+    manifest
+        .write_json(std::fs::File::create(&path).unwrap())
+        .unwrap();
+    assert!(matches!(
+        Manifest::from_json_reader(std::fs::File::open(&path).unwrap()),
+        Err(ManifestVersionError::Unsupported(version)) if version == "3"
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn fifos_are_skipped_with_a_warning() {
+    use std::os::unix::ffi::OsStrExt as _;
+
+    let dir = temp_dir("fifo");
+    std::fs::create_dir(dir.join("run")).unwrap();
+    std::fs::write(dir.join("game.exe"), b"game").unwrap();
+    let fifo = std::ffi::CString::new(dir.join("run/pipe").as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+    let logs = std::sync::Mutex::new(Vec::new());
+    let manifest = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |v| logs.lock().unwrap().push(v),
+        ManifestOptions::default(),
+    ))
+    .unwrap();
+    let files: Vec<&str> = manifest
+        .chunks
+        .values()
+        .flat_map(|v| &v.files)
+        .map(|v| v.filename.as_str())
+        .collect();
+    assert_eq!(files, ["game.exe"]);
+    assert!(logs
+        .into_inner()
+        .unwrap()
+        .contains(&"warning: skipping run/pipe, which isn't a regular file".to_string()));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn peeked_files_keep_their_permissions() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = temp_dir("peek-permissions");
+    for (name, mode) in [("game.sh", 0o755), ("save.dat", 0o600)] {
+        std::fs::write(dir.join(name), b"data").unwrap();
+        std::fs::set_permissions(dir.join(name), std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+    let backend = PathVersionBackend::new(dir.clone());
+
+    block_on(async {
+        let game = backend.peek_file("game.sh".to_string()).await.unwrap();
+        assert_eq!(game.permission & 0o777, 0o755);
+        assert_eq!(game.size, 4);
+        let save = backend.peek_file("save.dat".to_string()).await.unwrap();
+        assert_eq!(save.permission & 0o777, 0o600);
+
+        let err = backend
+            .peek_file("missing.dat".to_string())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Path doesn't exist"), "{}", err);
+    });
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Fills from a counter, then fails once `remaining` fills are used up
+struct RunningOutRandom {
+    remaining: std::sync::atomic::AtomicUsize,
+}
+
+impl RandomSource for RunningOutRandom {
+    fn fill(&self, dest: &mut [u8]) -> anyhow::Result<()> {
+        let remaining = self
+            .remaining
+            .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        if remaining == 0 {
+            return Err(anyhow::anyhow!("out of randomness"));
+        }
+        dest.fill(remaining as u8);
+        Ok(())
+    }
+}
+
+#[test]
+fn random_failures_stop_generation_before_any_chunk() {
+    let dir = temp_dir("random-failure");
+    let output = temp_dir("random-failure-out");
+    for index in 0..8u8 {
+        std::fs::write(dir.join(format!("{}.pak", index)), [index; 1024]).unwrap();
+    }
+    let options = ManifestOptions {
+        chunk_size: 1024,
+        wiggle: Some(0),
+        output_dir: Some(output.clone()),
+        // A uuid and an IV per chunk: enough for a few chunks, not all eight
+        random: Arc::new(RunningOutRandom {
+            remaining: 7.into(),
+        }),
+        ..Default::default()
+    };
+
+    let err = block_on(generate_manifest_with_options(
+        &dir,
+        |_| {},
+        |_| {},
+        options,
+    ))
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("out of randomness"), "{}", err);
+    assert_eq!(std::fs::read_dir(&output).unwrap().count(), 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+    std::fs::remove_dir_all(output).unwrap();
+}
+
+/// `MemoryBackend` with slow readers that record whether they were still
+/// reading once the backend was dropped
+struct DropTrackingBackend {
+    inner: MemoryBackend,
+    alive: Arc<std::sync::atomic::AtomicBool>,
+    read_after_drop: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl Drop for DropTrackingBackend {
+    fn drop(&mut self) {
+        self.alive.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+struct SlowReader {
+    inner: Box<dyn MinimumFileObject>,
+    alive: Arc<std::sync::atomic::AtomicBool>,
+    read_after_drop: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl tokio::io::AsyncRead for SlowReader {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        if !self.alive.load(std::sync::atomic::Ordering::SeqCst) {
+            self.read_after_drop
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+        std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+#[async_trait::async_trait]
+impl VersionBackend for DropTrackingBackend {
+    fn require_whole_files(&self) -> bool {
+        false
+    }
+
+    async fn list_files(&self) -> anyhow::Result<Vec<VersionFile>> {
+        self.inner.list_files().await
+    }
+
+    async fn peek_file(&self, sub_path: String) -> anyhow::Result<VersionFile> {
+        self.inner.peek_file(sub_path).await
+    }
+
+    async fn reader(
+        &self,
+        file: &VersionFile,
+        start: u64,
+        end: Option<u64>,
+    ) -> anyhow::Result<Box<dyn MinimumFileObject>> {
+        Ok(Box::new(SlowReader {
+            inner: self.inner.reader(file, start, end).await?,
+            alive: self.alive.clone(),
+            read_after_drop: self.read_after_drop.clone(),
+        }))
+    }
+}
+
+#[test]
+fn dropped_generation_keeps_the_backend_alive_for_its_tasks() {
+    let data = vec![5u8; 64 * 1024];
+    let names: Vec<String> = (0..16).map(|index| format!("{}.pak", index)).collect();
+    let files: Vec<(&str, &[u8])> = names.iter().map(|v| (v.as_str(), &data[..])).collect();
+    let alive = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let read_after_drop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let backend = DropTrackingBackend {
+        inner: MemoryBackend::new(&files),
+        alive: alive.clone(),
+        read_after_drop: read_after_drop.clone(),
+    };
+    let options = ManifestOptions {
+        chunk_size: 64 * 1024,
+        read_buffer_size: 1024,
+        ..Default::default()
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let files = backend.list_files().await.unwrap();
+        let generation =
+            generate_manifest_from_files(Box::new(backend), files, |_| {}, |_| {}, options);
+        let wait = |millis| {
+            tokio::task::spawn_blocking(move || {
+                std::thread::sleep(std::time::Duration::from_millis(millis))
+            })
+        };
+        // Cancelled part way through, like a chunk stream that's dropped early
+        let result = futures::future::select(Box::pin(generation), wait(50)).await;
+        assert!(matches!(result, futures::future::Either::Right(_)));
+        drop(result);
+        wait(500).await.unwrap();
+    });
+    drop(runtime);
+
+    assert!(!alive.load(std::sync::atomic::Ordering::SeqCst));
+    assert!(!read_after_drop.load(std::sync::atomic::Ordering::SeqCst));
+}
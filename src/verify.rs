@@ -0,0 +1,490 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use std::{future::Future, io::ErrorKind};
+
+use anyhow::anyhow;
+use tokio::io::AsyncReadExt as _;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncSeekExt as _, AsyncWriteExt as _},
+    task::JoinSet,
+};
+
+use crate::{
+    checksum::{checksums_match, hash_chunk, ChunkHasher, HashAlgorithm},
+    manifest::{ChunkData, ChunkReader, Manifest},
+    versions::types::VersionBackend,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    manifest::{FileEntry, UNKEYED},
+    versions::{create_backend_constructor, native_path},
+};
+
+/// Re-hashes every chunk of `manifest` from the files in `dir`, returning the
+/// (sorted) ids of chunks that are missing data or don't match their checksum
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn verify_manifest(dir: &Path, manifest: &Manifest) -> anyhow::Result<Vec<String>> {
+    let concurrency = std::thread::available_parallelism().map_or(1, |v| v.get());
+    let results = verify_manifest_parallel(dir, manifest, concurrency).await?;
+
+    Ok(failed_chunks(results))
+}
+
+/// `verify_manifest`, keying checksums with `key` rather than the manifest's
+/// own key. For `HashAlgorithm::HmacSha256` manifests from untrusted sources,
+/// with the publisher's key obtained some other way: anyone who can rewrite a
+/// manifest can also rewrite its key and checksums to match.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn verify_manifest_with_key(
+    dir: &Path,
+    manifest: &Manifest,
+    key: &[u8; 16],
+) -> anyhow::Result<Vec<String>> {
+    let concurrency = std::thread::available_parallelism().map_or(1, |v| v.get());
+    let backend = Arc::from(create_backend_constructor(dir)?()?);
+    let results = verify_with_backend(backend, manifest, *key, concurrency, |_| {}, |_| {}).await?;
+
+    Ok(failed_chunks(results))
+}
+
+/// The (sorted) ids of chunks that didn't pass
+#[cfg(not(target_arch = "wasm32"))]
+fn failed_chunks(results: HashMap<String, bool>) -> Vec<String> {
+    let mut failed: Vec<String> = results
+        .into_iter()
+        .filter(|(_, valid)| !valid)
+        .map(|(chunk_id, _)| chunk_id)
+        .collect();
+    failed.sort();
+    failed
+}
+
+/// Re-hashes up to `concurrency` chunks of `manifest` at once, each on its own
+/// task, returning whether each chunk id passed
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn verify_manifest_parallel(
+    dir: &Path,
+    manifest: &Manifest,
+    concurrency: usize,
+) -> anyhow::Result<HashMap<String, bool>> {
+    verify_manifest_with_progress(dir, manifest, concurrency, |_| {}, |_| {}).await
+}
+
+/// `verify_manifest_parallel`, reporting progress the way generation does:
+/// `log_sfn` gets a line per finished chunk, and `progress_sfn` the percentage
+/// (0-100) of the manifest's bytes verified so far
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn verify_manifest_with_progress<T: Fn(String), V: Fn(f32)>(
+    dir: &Path,
+    manifest: &Manifest,
+    concurrency: usize,
+    progress_sfn: V,
+    log_sfn: T,
+) -> anyhow::Result<HashMap<String, bool>> {
+    let backend = Arc::from(create_backend_constructor(dir)?()?);
+    let key = manifest.hash_key();
+    verify_with_backend(backend, manifest, key, concurrency, progress_sfn, log_sfn).await
+}
+
+/// Re-hashes every chunk of `manifest` from `backend` rather than an install,
+/// returning the (sorted) ids of chunks that no longer match. For checking a
+/// manifest still describes its source directory or archive, e.g. before
+/// publishing it.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn verify_manifest_against_backend(
+    manifest: &Manifest,
+    backend: Box<dyn VersionBackend + Send + Sync>,
+) -> anyhow::Result<Vec<String>> {
+    let concurrency = std::thread::available_parallelism().map_or(1, |v| v.get());
+    let results = verify_with_backend(
+        Arc::from(backend),
+        manifest,
+        manifest.hash_key(),
+        concurrency,
+        |_| {},
+        |_| {},
+    )
+    .await?;
+
+    Ok(failed_chunks(results))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn verify_with_backend<T: Fn(String), V: Fn(f32)>(
+    backend: Arc<dyn VersionBackend + Send + Sync>,
+    manifest: &Manifest,
+    key: [u8; 16],
+    concurrency: usize,
+    progress_sfn: V,
+    log_sfn: T,
+) -> anyhow::Result<HashMap<String, bool>> {
+    let chunk_length = |chunk: &ChunkData| chunk.files.iter().map(|v| v.length).sum::<u64>();
+    let total_bytes: u64 = manifest.chunks.values().map(chunk_length).sum();
+    let mut verified_bytes = 0u64;
+
+    let mut pending = manifest.chunks.iter();
+    let mut running = JoinSet::new();
+    let mut results = HashMap::with_capacity(manifest.chunks.len());
+    loop {
+        while running.len() < concurrency.max(1) {
+            let Some((chunk_id, chunk)) = pending.next() else {
+                break;
+            };
+            let backend = backend.clone();
+            let chunk_id = chunk_id.clone();
+            let chunk = chunk.clone();
+            let hash = manifest.hash;
+            running.spawn(async move {
+                // Unreadable files (missing, permissions) count as a failed chunk
+                let valid = verify_chunk(&*backend, &chunk, hash, &key)
+                    .await
+                    .unwrap_or(false);
+                (chunk_id, valid)
+            });
+        }
+
+        let Some(result) = running.join_next().await else {
+            break;
+        };
+        let (chunk_id, valid) = result?;
+
+        let length = chunk_length(&manifest.chunks[&chunk_id]);
+        verified_bytes += length;
+        log_sfn(format!(
+            "{} chunk {} ({}b, {} of {} chunks)",
+            if valid { "verified" } else { "failed" },
+            chunk_id,
+            length,
+            results.len() + 1,
+            manifest.chunks.len()
+        ));
+        progress_sfn(if total_bytes == 0 {
+            (results.len() + 1) as f32 / manifest.chunks.len() as f32 * 100.0f32
+        } else {
+            verified_bytes as f32 / total_bytes as f32 * 100.0f32
+        });
+
+        results.insert(chunk_id, valid);
+    }
+
+    Ok(results)
+}
+
+/// Reads a chunk's file ranges from `backend` and checks they hash to its
+/// checksum. `key` is the manifest key, which keyed algorithms need.
+pub async fn verify_chunk(
+    backend: &(dyn VersionBackend + Send + Sync),
+    chunk: &ChunkData,
+    algorithm: HashAlgorithm,
+    key: &[u8; 16],
+) -> anyhow::Result<bool> {
+    let mut read_buf = vec![0u8; 1024 * 1024];
+    let mut hasher = ChunkHasher::new(algorithm, key);
+    let mut reader = ChunkReader::new(backend, chunk);
+
+    loop {
+        let amount = match reader.read(&mut read_buf).await {
+            Ok(amount) => amount,
+            // Truncated file
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        if amount == 0 {
+            break;
+        }
+        hasher.update(&read_buf[0..amount]);
+    }
+
+    Ok(hasher.finalize() == chunk.checksum)
+}
+
+/// Checks downloaded chunk contents (decompressed and decrypted) against the
+/// chunk's length and checksum, without touching the filesystem. `key` is the
+/// manifest key, which keyed algorithms need.
+pub fn verify_chunk_bytes(
+    chunk: &ChunkData,
+    bytes: &[u8],
+    algorithm: HashAlgorithm,
+    key: &[u8; 16],
+) -> anyhow::Result<bool> {
+    if chunk.checksum.is_empty() {
+        return Err(anyhow!("chunk has no checksum to verify against"));
+    }
+
+    let expected_length: u64 = chunk.files.iter().map(|v| v.length).sum();
+    if bytes.len() as u64 != expected_length {
+        return Ok(false);
+    }
+
+    Ok(checksums_match(
+        &hash_chunk(bytes, algorithm, key),
+        &chunk.checksum,
+    ))
+}
+
+/// Reassembles `filename` from its fragments across chunks, in file order, and
+/// returns the hash of the whole file under the manifest's algorithm and key.
+/// `get_chunk_bytes(chunk_id)` returns a chunk's decoded (decompressed and
+/// decrypted) contents. Errors if the fragments leave a gap or overlap, or a
+/// chunk is too short to hold its fragment.
+pub async fn reconstruct_file_hash<F, Fut>(
+    manifest: &Manifest,
+    filename: &str,
+    get_chunk_bytes: F,
+) -> anyhow::Result<String>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    let index = manifest.build_index();
+    let locations = index
+        .get(filename)
+        .ok_or_else(|| anyhow!("file not in manifest: {}", filename))?;
+
+    let mut hasher = ChunkHasher::new(manifest.hash, &manifest.hash_key());
+    let mut position = 0u64;
+    for location in locations {
+        if location.start != position {
+            return Err(anyhow!(
+                "{} has a fragment at {}, expected one at {}",
+                filename,
+                location.start,
+                position
+            ));
+        }
+
+        let contents = get_chunk_bytes(location.chunk_id.clone()).await?;
+        let end = location.offset + location.length;
+        if (contents.len() as u64) < end {
+            return Err(anyhow!(
+                "chunk {} is {} bytes, too short for {}'s fragment at {}..{}",
+                location.chunk_id,
+                contents.len(),
+                filename,
+                location.offset,
+                end
+            ));
+        }
+        hasher.update(&contents[location.offset as usize..end as usize]);
+        position += location.length;
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// Streams `entry`'s range of its file in `dir` through a hasher and checks it
+/// matches `expected`, reading the file once sequentially. Entries covering a
+/// whole file (`start` of 0, `length` of the file size) verify the whole file,
+/// whichever chunks it's split across. Keyed algorithms aren't supported.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn verify_file(
+    dir: &Path,
+    entry: &FileEntry,
+    expected: &str,
+    algorithm: HashAlgorithm,
+) -> anyhow::Result<bool> {
+    if algorithm == HashAlgorithm::HmacSha256 {
+        return Err(anyhow!("{} is keyed, and can't verify files", algorithm));
+    }
+
+    let mut file = File::open(dir.join(native_path(&entry.filename))).await?;
+    if entry.start != 0 {
+        file.seek(SeekFrom::Start(entry.start)).await?;
+    }
+    let mut reader = file.take(entry.length);
+
+    let mut read_buf = vec![0u8; 1024 * 1024];
+    let mut hasher = ChunkHasher::new(algorithm, &UNKEYED);
+    let mut total = 0u64;
+    loop {
+        let amount = reader.read(&mut read_buf).await?;
+        if amount == 0 {
+            break;
+        }
+        total += amount as u64;
+        hasher.update(&read_buf[0..amount]);
+    }
+
+    // Truncated file
+    if total != entry.length {
+        return Ok(false);
+    }
+
+    Ok(hasher.finalize() == expected)
+}
+
+/// Verifies every chunk of `manifest` against the install in `dir`, and for
+/// each one that fails, rewrites its file ranges with the contents returned by
+/// `fetch_chunk(chunk_id)`. Contents must be decoded (decompressed and
+/// decrypted), and are checked against the chunk's checksum before anything
+/// is written. Missing files and directories are created. Returns the
+/// (sorted) ids of the chunks that were repaired. Filenames that would land
+/// outside `dir`, or be written through a symlink, are rejected before
+/// anything is written, and symlinks are restored after every file.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn repair_install<F, Fut>(
+    dir: &Path,
+    manifest: &Manifest,
+    fetch_chunk: F,
+) -> anyhow::Result<Vec<String>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    tokio::fs::create_dir_all(dir).await?;
+    let failed = verify_manifest(dir, manifest).await?;
+    for chunk_id in &failed {
+        for entry in &manifest.chunks[chunk_id].files {
+            install_path(dir, &entry.filename)?;
+        }
+    }
+
+    let mut symlinks = Vec::new();
+    for chunk_id in &failed {
+        let chunk = &manifest.chunks[chunk_id];
+        let contents = fetch_chunk(chunk_id.clone()).await?;
+
+        let expected_length: u64 = chunk.files.iter().map(|v| v.length).sum();
+        if contents.len() as u64 != expected_length {
+            return Err(anyhow!(
+                "fetched chunk {} is {} bytes, expected {}",
+                chunk_id,
+                contents.len(),
+                expected_length
+            ));
+        }
+        if !verify_chunk_bytes(chunk, &contents, manifest.hash, &manifest.hash_key())? {
+            return Err(anyhow!(
+                "fetched chunk {} doesn't match its checksum",
+                chunk_id
+            ));
+        }
+
+        let mut offset = 0usize;
+        for entry in &chunk.files {
+            let path = install_path(dir, &entry.filename)?;
+            if let Some(target) = &entry.symlink {
+                symlinks.push((path, target));
+                continue;
+            }
+            check_no_symlink_parents(dir, &path).await?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            // Replace a symlink rather than writing to wherever it points
+            if tokio::fs::symlink_metadata(&path)
+                .await
+                .is_ok_and(|v| v.file_type().is_symlink())
+            {
+                tokio::fs::remove_file(&path).await?;
+            }
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .await?;
+            file.seek(SeekFrom::Start(entry.start)).await?;
+            file.write_all(&contents[offset..offset + entry.length as usize])
+                .await?;
+            file.flush().await?;
+            offset += entry.length as usize;
+        }
+    }
+    for (path, target) in symlinks {
+        check_no_symlink_parents(dir, &path).await?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        restore_symlink(&path, target).await?;
+    }
+
+    Ok(failed)
+}
+
+/// Where `filename` goes under `dir`, erroring unless every component is a
+/// plain name, so `..`, absolute paths and (on Windows) drive prefixes and
+/// backslashes can't escape it
+#[cfg(not(target_arch = "wasm32"))]
+fn install_path(dir: &Path, filename: &str) -> anyhow::Result<PathBuf> {
+    let escapes = filename.starts_with('/')
+        || (cfg!(windows) && filename.contains('\\'))
+        || filename.split('/').filter(|v| !v.is_empty()).any(|part| {
+            let mut components = Path::new(part).components();
+            !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            )
+        });
+    if escapes || filename.split('/').all(str::is_empty) {
+        return Err(anyhow!(
+            "manifest file {:?} isn't a relative path inside the install",
+            filename
+        ));
+    }
+
+    Ok(dir.join(native_path(filename)))
+}
+
+/// Errors if any directory between `dir` and `path` is a symlink, which
+/// writing to `path` would follow out of `dir`
+#[cfg(not(target_arch = "wasm32"))]
+async fn check_no_symlink_parents(dir: &Path, path: &Path) -> anyhow::Result<()> {
+    let relative = path.strip_prefix(dir)?;
+    let mut current = dir.to_path_buf();
+    let mut parents = relative.components().peekable();
+    while let Some(component) = parents.next() {
+        if parents.peek().is_none() {
+            break;
+        }
+        current.push(component);
+        match tokio::fs::symlink_metadata(&current).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(anyhow!(
+                    "{} is a symlink, so won't be written through",
+                    current.display()
+                ));
+            }
+            Ok(_) => {}
+            // Missing parents are created as plain directories
+            Err(err) if err.kind() == ErrorKind::NotFound => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Points `path` at `target`, replacing whatever is there
+#[cfg(not(target_arch = "wasm32"))]
+async fn restore_symlink(path: &Path, target: &str) -> anyhow::Result<()> {
+    if let Ok(existing) = tokio::fs::read_link(path).await {
+        if existing == Path::new(target) {
+            return Ok(());
+        }
+    }
+    if tokio::fs::symlink_metadata(path).await.is_ok() {
+        tokio::fs::remove_file(path).await?;
+    }
+
+    #[cfg(unix)]
+    {
+        tokio::fs::symlink(target, path).await?;
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        Err(anyhow!(
+            "can't restore symlink {} -> {} on this platform",
+            path.display(),
+            target
+        ))
+    }
+}
@@ -1,39 +1,336 @@
-use std::{path::PathBuf, task::Poll};
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+    task::Poll,
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context as _};
 use async_trait::async_trait;
 use libarchive_drop::{
     archive::{Entry, FileType, ReadCompression, ReadFormat},
-    reader::{Builder, FileReader, Reader},
+    reader::{Builder, FileReader, Reader, StreamReader},
 };
-use tokio::io::AsyncRead;
+use tokio::{io::AsyncRead, sync::mpsc::Sender};
 
 use crate::versions::types::{MinimumFileObject, VersionBackend, VersionFile};
 
+/// Default `ArchiveLimits::max_entries`
+pub const DEFAULT_MAX_ARCHIVE_ENTRIES: usize = 1024 * 1024;
+/// Default `ArchiveLimits::max_total_size`, 1 TiB
+pub const DEFAULT_MAX_ARCHIVE_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
+
+/// Bounds on what an archive's headers may declare, so a malicious archive
+/// can't claim millions of entries or absurd sizes that chunking then trusts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveLimits {
+    /// Most regular files the archive may list
+    pub max_entries: usize,
+    /// Most bytes the archive's files may declare in total
+    pub max_total_size: u64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: DEFAULT_MAX_ARCHIVE_ENTRIES,
+            max_total_size: DEFAULT_MAX_ARCHIVE_SIZE,
+        }
+    }
+}
+
+/// Checks an archive's headers against its `ArchiveLimits` as they're read
+struct EntryLimiter {
+    limits: ArchiveLimits,
+    /// What to call the archive in errors
+    source: String,
+    entries: usize,
+    total_size: u64,
+}
+
+impl EntryLimiter {
+    fn new(limits: ArchiveLimits, source: String) -> Self {
+        Self {
+            limits,
+            source,
+            entries: 0,
+            total_size: 0,
+        }
+    }
+
+    /// The file or symlink `header` describes, or `None` for other entries
+    /// (directories, devices) which manifests leave out
+    fn admit(&mut self, header: &impl Entry) -> anyhow::Result<Option<VersionFile>> {
+        let symlink = match header.filetype() {
+            FileType::RegularFile => None,
+            FileType::SymbolicLink => Some(header.symlink().to_string()),
+            _ => return Ok(None),
+        };
+        if self.entries >= self.limits.max_entries {
+            return Err(anyhow!(
+                "archive {} has more than {} files and symlinks",
+                self.source,
+                self.limits.max_entries
+            ));
+        }
+        let size: u64 = if symlink.is_some() {
+            0
+        } else {
+            header.size().try_into()?
+        };
+        self.total_size = self.total_size.saturating_add(size);
+        if self.total_size > self.limits.max_total_size {
+            return Err(anyhow!(
+                "archive {} declares more than {} bytes of files ({} is {} bytes)",
+                self.source,
+                self.limits.max_total_size,
+                header.pathname(),
+                size
+            ));
+        }
+        self.entries += 1;
+
+        Ok(Some(VersionFile {
+            relative_filename: header.pathname().to_string(),
+            permission: 0o744,
+            size,
+            symlink,
+            is_sparse: false,
+        }))
+    }
+}
+
+/// What `stream_archive` reads from an archive, in archive order
+pub(crate) enum StreamEntry {
+    /// A file or symlink, followed by its contents as `Data`
+    File(VersionFile),
+    Data(Vec<u8>),
+}
+
+/// Reads the archive in `source` in a single pass, for sources that can only
+/// be read once like stdin. Blocks, so should run on a blocking thread. Stops
+/// early if `sender`'s receiver is dropped.
+pub(crate) fn stream_archive<R: Read + Any>(
+    source: R,
+    limits: ArchiveLimits,
+    sender: Sender<StreamEntry>,
+) -> anyhow::Result<()> {
+    let mut archive = Builder::new();
+    archive.support_format(ReadFormat::All)?;
+    archive.support_compression(ReadCompression::All)?;
+    let mut archive = archive
+        .open_stream(source)
+        .context("failed to open archive stream")?;
+
+    let mut limiter = EntryLimiter::new(limits, "stream".to_string());
+    while let Some(header) = archive.next_header() {
+        let Some(file) = limiter.admit(&*header)? else {
+            continue;
+        };
+        let filename = file.relative_filename.clone();
+        if sender.blocking_send(StreamEntry::File(file)).is_err() {
+            return Ok(());
+        }
+        while let Some(block) = archive
+            .read_block()
+            .with_context(|| format!("reading {} from archive stream", filename))?
+        {
+            if sender
+                .blocking_send(StreamEntry::Data(block.to_vec()))
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a byte-split archive's volumes (`game.7z.001`, `game.7z.002`, ...)
+/// one after another, as the single archive they were cut from
+pub(crate) struct VolumeReader {
+    volumes: std::vec::IntoIter<PathBuf>,
+    current: Option<std::fs::File>,
+}
+
+impl VolumeReader {
+    pub fn new(volumes: Vec<PathBuf>) -> Self {
+        Self {
+            volumes: volumes.into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let file = match &mut self.current {
+                Some(file) => file,
+                None => match self.volumes.next() {
+                    Some(volume) => self.current.insert(std::fs::File::open(volume)?),
+                    None => return Ok(0),
+                },
+            };
+            let read = file.read(buf)?;
+            // An empty read means this volume is done, unless nothing was asked for
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.current = None;
+        }
+    }
+}
+
+/// Where a `ZipVersionBackend` reads its archive from
+enum ArchiveSource {
+    Path(PathBuf),
+    /// Shared with every reader, each of which reads it from the start
+    Bytes(Arc<[u8]>),
+    /// A byte-split archive's volumes, in order
+    Volumes(Vec<PathBuf>),
+}
+
+impl fmt::Display for ArchiveSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveSource::Path(path) => write!(f, "{}", path.display()),
+            ArchiveSource::Bytes(bytes) => write!(f, "({} bytes in memory)", bytes.len()),
+            ArchiveSource::Volumes(volumes) => {
+                write!(f, "{} ({} volumes)", volumes[0].display(), volumes.len())
+            }
+        }
+    }
+}
+
 pub struct ZipVersionBackend {
-    path: PathBuf,
+    source: ArchiveSource,
+    limits: ArchiveLimits,
 }
 impl ZipVersionBackend {
     pub fn new(path: PathBuf) -> anyhow::Result<Self> {
-        Ok(Self { path })
+        Self::with_limits(path, ArchiveLimits::default())
+    }
+
+    pub fn with_limits(path: PathBuf, limits: ArchiveLimits) -> anyhow::Result<Self> {
+        Ok(Self {
+            source: ArchiveSource::Path(path),
+            limits,
+        })
+    }
+
+    /// An archive held in memory, like an upload, so it can be manifested
+    /// without writing it to disk first
+    pub fn from_bytes(bytes: Vec<u8>) -> anyhow::Result<Self> {
+        Self::from_bytes_with_limits(bytes, ArchiveLimits::default())
+    }
+
+    pub fn from_bytes_with_limits(bytes: Vec<u8>, limits: ArchiveLimits) -> anyhow::Result<Self> {
+        Ok(Self {
+            source: ArchiveSource::Bytes(bytes.into()),
+            limits,
+        })
+    }
+
+    /// A byte-split archive, read as its `volumes` joined in order. Volumes
+    /// aren't seekable together, so each reader reads them from the start.
+    pub fn from_volumes(volumes: Vec<PathBuf>, limits: ArchiveLimits) -> anyhow::Result<Self> {
+        if volumes.is_empty() {
+            return Err(anyhow!("a split archive needs at least one volume"));
+        }
+
+        Ok(Self {
+            source: ArchiveSource::Volumes(volumes),
+            limits,
+        })
     }
 
-    fn open_archive(&self) -> Result<FileReader, anyhow::Error> {
+    fn builder() -> anyhow::Result<Builder> {
         let mut archive = Builder::new();
         archive.support_format(ReadFormat::All)?;
         archive.support_compression(ReadCompression::All)?;
-        let archive = archive.open_file(&self.path)?;
 
         Ok(archive)
     }
+
+    fn open_file(&self, path: &Path) -> Result<FileReader, anyhow::Error> {
+        Self::builder()?
+            .open_file(path)
+            .with_context(|| format!("failed to open archive {}", self.source))
+    }
+
+    fn open_bytes(&self, bytes: &Arc<[u8]>) -> Result<StreamReader, anyhow::Error> {
+        Self::builder()?
+            .open_stream(Cursor::new(bytes.clone()))
+            .with_context(|| format!("failed to open archive {}", self.source))
+    }
+
+    fn open_volumes(&self, volumes: &[PathBuf]) -> Result<StreamReader, anyhow::Error> {
+        Self::builder()?
+            .open_stream(VolumeReader::new(volumes.to_vec()))
+            .with_context(|| format!("failed to open archive {}", self.source))
+    }
+
+    fn is_rar(&self) -> bool {
+        let rar_extension = |path: &Path| {
+            path.extension()
+                .is_some_and(|v| v.eq_ignore_ascii_case("rar"))
+        };
+        match &self.source {
+            ArchiveSource::Path(path) => rar_extension(path),
+            ArchiveSource::Bytes(bytes) => bytes.starts_with(b"Rar!\x1a\x07"),
+            // `game.rar.001` and on
+            ArchiveSource::Volumes(volumes) => rar_extension(&volumes[0].with_extension("")),
+        }
+    }
 }
 
-struct ArchiveReader<'a> {
-    archive: FileReader,
+/// Every file and symlink in `archive`, checked against `limiter`
+fn list_entries<R: Reader>(
+    mut archive: R,
+    limiter: &mut EntryLimiter,
+) -> anyhow::Result<Vec<VersionFile>> {
+    let mut results = Vec::new();
+    while let Some(header) = archive.next_header() {
+        if let Some(file) = limiter.admit(&*header)? {
+            results.push(file);
+        }
+    }
+
+    Ok(results)
+}
+
+/// A reader of `filename`'s contents, skipping `archive` ahead to it
+fn open_entry<R: Reader + Send + Unpin + 'static>(
+    mut archive: R,
+    filename: &str,
+) -> anyhow::Result<Box<dyn MinimumFileObject>> {
+    loop {
+        let entry = match archive.next_header() {
+            Some(v) => v,
+            None => return Err(anyhow!("entry not found:{}", filename)),
+        };
+        if entry.pathname() == filename {
+            break;
+        }
+    }
+
+    Ok(Box::new(ArchiveReader {
+        archive,
+        prev_block: None,
+    }))
+}
+
+struct ArchiveReader<'a, R> {
+    archive: R,
     prev_block: Option<&'a [u8]>,
 }
 
-impl<'a> AsyncRead for ArchiveReader<'a> {
+impl<'a, R: Reader + Unpin> AsyncRead for ArchiveReader<'a, R> {
     fn poll_read(
         mut self: std::pin::Pin<&mut Self>,
         _cx: &mut std::task::Context<'_>,
@@ -82,21 +379,23 @@ impl<'a> AsyncRead for ArchiveReader<'a> {
 #[async_trait]
 impl VersionBackend for ZipVersionBackend {
     async fn list_files(&self) -> anyhow::Result<Vec<VersionFile>> {
-        let mut archive = self.open_archive()?;
-        let mut results = Vec::new();
-
-        while let Some(header) = archive.next_header() {
-            match header.filetype() {
-                FileType::RegularFile => (),
-                _ => {
-                    continue;
-                }
+        let mut limiter = EntryLimiter::new(self.limits, self.source.to_string());
+        let results = match &self.source {
+            ArchiveSource::Path(path) => list_entries(self.open_file(path)?, &mut limiter)?,
+            ArchiveSource::Bytes(bytes) => list_entries(self.open_bytes(bytes)?, &mut limiter)?,
+            ArchiveSource::Volumes(volumes) => {
+                list_entries(self.open_volumes(volumes)?, &mut limiter)?
             }
-            results.push(VersionFile {
-                relative_filename: header.pathname().to_string(),
-                permission: 0o744,
-                size: header.size().try_into()?,
-            });
+        };
+
+        // libarchive stops at the first header it can't parse, which for RAR
+        // usually means an unsupported feature rather than an empty archive
+        if results.is_empty() && self.is_rar() {
+            return Err(anyhow!(
+                "no files could be read from RAR archive {}: libarchive can't read some RAR features \
+                 (e.g. RAR5 solid or encrypted archives), repack it as .7z or .zip",
+                self.source
+            ));
         }
 
         Ok(results)
@@ -106,35 +405,42 @@ impl VersionBackend for ZipVersionBackend {
         &self,
         file: &VersionFile,
         _start: u64,
-        _end: u64,
+        _end: Option<u64>,
     ) -> anyhow::Result<Box<dyn MinimumFileObject>> {
-        let mut archive = self.open_archive()?;
-
-        // Find entry in archive
-        loop {
-            let entry = match archive.next_header() {
-                Some(v) => v,
-                None => return Err(anyhow!("entry not found:{}", file.relative_filename)),
-            };
-            if entry.pathname() == file.relative_filename {
-                break;
+        match &self.source {
+            ArchiveSource::Path(path) => open_entry(self.open_file(path)?, &file.relative_filename),
+            ArchiveSource::Bytes(bytes) => {
+                open_entry(self.open_bytes(bytes)?, &file.relative_filename)
+            }
+            ArchiveSource::Volumes(volumes) => {
+                open_entry(self.open_volumes(volumes)?, &file.relative_filename)
             }
         }
-
-        Ok(Box::new(ArchiveReader {
-            archive,
-            prev_block: None,
-        }))
     }
 
     async fn peek_file(&self, sub_path: String) -> anyhow::Result<VersionFile> {
-        let files = self.list_files().await?;
-        let file = files
-            .iter()
-            .find(|v| v.relative_filename == sub_path)
-            .expect("file not found");
+        let mut files = self.peek_files(&[sub_path]).await?;
+        Ok(files.remove(0))
+    }
+
+    /// Reads the archive's headers once, however many files are looked up
+    async fn peek_files(&self, sub_paths: &[String]) -> anyhow::Result<Vec<VersionFile>> {
+        let listed: HashMap<String, VersionFile> = self
+            .list_files()
+            .await?
+            .into_iter()
+            .map(|v| (v.relative_filename.clone(), v))
+            .collect();
 
-        Ok(file.clone())
+        sub_paths
+            .iter()
+            .map(|sub_path| {
+                listed
+                    .get(sub_path)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("{} not found in archive {}", sub_path, self.source))
+            })
+            .collect()
     }
 
     fn require_whole_files(&self) -> bool {
@@ -1,25 +1,39 @@
+#[cfg(not(target_arch = "wasm32"))]
 use std::{
+    collections::VecDeque,
+    ffi::OsStr,
     fs::{metadata, read_dir},
-    path::{Path, PathBuf},
+    path::Path,
 };
+use std::{fmt, path::PathBuf};
 
 use anyhow::Result;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::task::JoinSet;
 
+use crate::versions::types::VersionBackend;
+#[cfg(not(target_arch = "wasm32"))]
 use crate::versions::{
-    archive_backend::ZipVersionBackend, path_backend::PathVersionBackend, types::VersionBackend,
+    archive_backend::{ArchiveLimits, ZipVersionBackend},
+    path_backend::PathVersionBackend,
 };
 
+// Backends read from the filesystem, so aren't available on wasm32
+#[cfg(not(target_arch = "wasm32"))]
 pub mod archive_backend;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod path_backend;
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn _list_files(vec: &mut Vec<PathBuf>, path: &Path) -> Result<()> {
     if metadata(path)?.is_dir() {
         let paths = read_dir(path)?;
         for path_result in paths {
             let full_path = path_result?.path();
-            if metadata(&full_path)?.is_dir() {
+            let metadata = metadata(&full_path)?;
+            if metadata.is_dir() {
                 _list_files(vec, &full_path)?;
-            } else {
+            } else if metadata.is_file() {
                 vec.push(full_path);
             }
         }
@@ -28,33 +42,276 @@ pub fn _list_files(vec: &mut Vec<PathBuf>, path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// How many directories `walk_files` reads at once
+#[cfg(not(target_arch = "wasm32"))]
+pub const WALK_CONCURRENCY: usize = 16;
+
+/// What `read_dir_entries` found in a directory
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct DirEntries {
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+    /// FIFOs, sockets, devices and the like
+    special: Vec<PathBuf>,
+}
+
+/// Lists a single directory
+#[cfg(not(target_arch = "wasm32"))]
+async fn read_dir_entries(path: PathBuf) -> Result<DirEntries> {
+    let mut found = DirEntries::default();
+    let mut entries = tokio::fs::read_dir(&path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let full_path = entry.path();
+        // Follows symlinks, like `_list_files`
+        let metadata = tokio::fs::metadata(&full_path).await?;
+        if metadata.is_dir() {
+            found.dirs.push(full_path);
+        } else if metadata.is_file() {
+            found.files.push(full_path);
+        } else {
+            found.special.push(full_path);
+        }
+    }
+
+    Ok(found)
+}
+
+/// Async equivalent of `_list_files`, reading up to `concurrency` directories
+/// at once. The result is sorted so that manifests stay stable.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn walk_files(path: &Path, concurrency: usize) -> Result<Vec<PathBuf>> {
+    Ok(walk_files_with_special(path, concurrency).await?.0)
+}
+
+/// `walk_files`, also returning the (sorted) files it left out for not being
+/// regular files, like FIFOs, which opening or reading can block on forever
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn walk_files_with_special(
+    path: &Path,
+    concurrency: usize,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    if !tokio::fs::metadata(path).await?.is_dir() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+
+    let mut files = Vec::new();
+    let mut special = Vec::new();
+    let mut pending = VecDeque::from([path.to_path_buf()]);
+    let mut running = JoinSet::new();
+    loop {
+        while running.len() < concurrency.max(1) {
+            let Some(dir) = pending.pop_front() else {
+                break;
+            };
+            running.spawn(read_dir_entries(dir));
+        }
+
+        let Some(result) = running.join_next().await else {
+            break;
+        };
+        let found = result??;
+        files.extend(found.files);
+        special.extend(found.special);
+        pending.extend(found.dirs);
+    }
+    files.sort();
+    special.sort();
+
+    Ok((files, special))
+}
+
+/// Rewrites a relative path that uses `separator` to use `/`, which manifests
+/// always store so they match across OSes. Pass `std::path::MAIN_SEPARATOR`
+/// for native paths.
+pub fn portable_path(relative: &str, separator: char) -> String {
+    if separator == '/' {
+        return relative.to_owned();
+    }
+    relative.replace(separator, "/")
+}
+
+/// Whether any component of the `/`-separated relative path `relative` is a
+/// dotfile, which is how Unix hides files
+pub fn has_dotfile_component(relative: &str) -> bool {
+    relative
+        .split('/')
+        .any(|v| v.starts_with('.') && v != "." && v != "..")
+}
+
+/// Converts a manifest's `/`-separated relative path to a native one, for
+/// joining onto an install directory
+pub fn native_path(relative: &str) -> PathBuf {
+    relative.split('/').filter(|v| !v.is_empty()).collect()
+}
+
 const SUPPORTED_FILE_EXTENSIONS: [&str; 11] = [
     "tar", "pax", "cpio", "zip", "jar", "ar", "xar", "rar", "rpm", "7z", "iso",
 ];
 
+/// Whether `extension` is one of `SUPPORTED_FILE_EXTENSIONS`, ignoring ASCII
+/// case. Compared as bytes, so archives whose names aren't valid UTF-8 are
+/// still recognised.
+#[cfg(not(target_arch = "wasm32"))]
+fn is_supported_extension(extension: &OsStr) -> bool {
+    SUPPORTED_FILE_EXTENSIONS.iter().any(|v| {
+        extension
+            .as_encoded_bytes()
+            .eq_ignore_ascii_case(v.as_bytes())
+    })
+}
+
+/// Whether archives can be read in this build, rather than only directories.
+/// libarchive is linked in everywhere but wasm32.
+pub fn archive_support_available() -> bool {
+    cfg!(not(target_arch = "wasm32"))
+}
+
+/// Archive extensions (lowercase, without the dot) a backend can be created
+/// for in this build
+pub fn supported_extensions() -> Vec<&'static str> {
+    if !archive_support_available() {
+        return Vec::new();
+    }
+    SUPPORTED_FILE_EXTENSIONS.to_vec()
+}
+
+/// Whether `path` is the first volume of an archive split into byte ranges,
+/// like `game.7z.001`, whose volumes just need joining to be read
+#[cfg(not(target_arch = "wasm32"))]
+fn is_byte_split(path: &Path) -> bool {
+    path.extension().is_some_and(|v| v == "001")
+        && Path::new(path.file_stem().unwrap_or_default())
+            .extension()
+            .is_some_and(is_supported_extension)
+}
+
+/// For the first volume of a split archive (`game.7z.001`, `game.part1.rar`),
+/// returns every volume of the set in order. A first volume with no others
+/// isn't a set.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn split_volume_set(path: &Path) -> Option<Vec<PathBuf>> {
+    let file_name = path.file_name()?.to_str()?;
+    let lowercase = file_name.to_ascii_lowercase();
+
+    // (text before the volume number, the number, text after it)
+    let (prefix, number, suffix) = if let Some(stem) = lowercase.strip_suffix(".001") {
+        if !is_byte_split(path) {
+            return None;
+        }
+        (&file_name[..stem.len() + 1], "001", "")
+    } else {
+        let stem = lowercase.strip_suffix(".rar")?;
+        let part_index = stem.rfind(".part")? + ".part".len();
+        let number = &file_name[part_index..stem.len()];
+        if number.is_empty() || !number.bytes().all(|v| v.is_ascii_digit()) {
+            return None;
+        }
+        if number.parse::<u32>().ok()? != 1 {
+            return None;
+        }
+        (&file_name[..part_index], number, &file_name[stem.len()..])
+    };
+
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let width = number.len();
+    let volumes: Vec<PathBuf> = (1..)
+        .map(|index| parent.join(format!("{}{:0width$}{}", prefix, index, suffix)))
+        .take_while(|volume| volume.exists())
+        .collect();
+
+    (volumes.len() > 1).then_some(volumes)
+}
+
 pub mod types;
-#[allow(clippy::type_complexity)]
-pub fn create_backend_constructor<'a>(
+
+pub type BackendConstructor<'a> =
+    Box<dyn FnOnce() -> Result<Box<dyn VersionBackend + Send + Sync + 'a>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendError {
+    PathNotFound(PathBuf),
+    /// Neither a directory nor an archive with a supported extension
+    UnsupportedFormat(PathBuf),
+    /// The first volume of a multi-volume RAR set (`game.part1.rar`), whose
+    /// volumes each have their own headers, so can't just be read one after
+    /// another like `game.7z.001` sets. libarchive-drop only opens one file (or
+    /// stream) at a time, so these have to be repacked first.
+    SplitArchive {
+        first: PathBuf,
+        volumes: usize,
+    },
+    /// Nothing left to manifest once listed and filtered, with
+    /// `ManifestOptions::fail_on_empty`
+    EmptySource,
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::PathNotFound(path) => write!(f, "path not found: {}", path.display()),
+            BackendError::UnsupportedFormat(path) => write!(
+                f,
+                "{} is not a directory or a supported archive ({})",
+                path.display(),
+                SUPPORTED_FILE_EXTENSIONS.join(", ")
+            ),
+            BackendError::SplitArchive { first, volumes } => write!(
+                f,
+                "{} is the first of {} volumes of a multi-volume RAR archive, which can't be read \
+                 directly; repack the volumes into a single archive first",
+                first.display(),
+                volumes
+            ),
+            BackendError::EmptySource => write!(f, "no files to manifest"),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn create_backend_constructor<'a>(path: &Path) -> Result<BackendConstructor<'a>, BackendError> {
+    create_backend_constructor_with_limits(path, ArchiveLimits::default())
+}
+
+/// `create_backend_constructor`, with `limits` applied to archives
+#[cfg(not(target_arch = "wasm32"))]
+pub fn create_backend_constructor_with_limits<'a>(
     path: &Path,
-) -> Option<Box<dyn FnOnce() -> Result<Box<dyn VersionBackend + Send + Sync + 'a>>>> {
+    limits: ArchiveLimits,
+) -> Result<BackendConstructor<'a>, BackendError> {
     if !path.exists() {
-        return None;
+        return Err(BackendError::PathNotFound(path.to_path_buf()));
     }
 
     let is_directory = path.is_dir();
     if is_directory {
         let base_dir = path.to_path_buf();
-        return Some(Box::new(move || {
-            Ok(Box::new(PathVersionBackend { base_dir }))
+        return Ok(Box::new(move || {
+            Ok(Box::new(PathVersionBackend::new(base_dir)))
         }));
     };
 
-    let file_extension = path.extension().and_then(|v| v.to_str())?;
+    if is_byte_split(path) {
+        let volumes = split_volume_set(path).unwrap_or_else(|| vec![path.to_path_buf()]);
+        return Ok(Box::new(move || {
+            Ok(Box::new(ZipVersionBackend::from_volumes(volumes, limits)?))
+        }));
+    }
+    if let Some(volumes) = split_volume_set(path) {
+        return Err(BackendError::SplitArchive {
+            first: path.to_path_buf(),
+            volumes: volumes.len(),
+        });
+    }
 
-    if SUPPORTED_FILE_EXTENSIONS.contains(&file_extension) {
+    if path.extension().is_some_and(is_supported_extension) {
         let buf = path.to_path_buf();
-        return Some(Box::new(move || Ok(Box::new(ZipVersionBackend::new(buf)?))));
+        return Ok(Box::new(move || {
+            Ok(Box::new(ZipVersionBackend::with_limits(buf, limits)?))
+        }));
     }
 
-    None
+    Err(BackendError::UnsupportedFormat(path.to_path_buf()))
 }
@@ -1,89 +1,435 @@
+#[cfg(not(unix))]
+use std::io::SeekFrom;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
-use std::{io::SeekFrom, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf, MAIN_SEPARATOR},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+#[cfg(unix)]
+use std::{future::Future as _, task::ready};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
+use futures::{StreamExt as _, TryStreamExt as _};
+#[cfg(not(unix))]
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+#[cfg(unix)]
+use tokio::task::JoinHandle;
 use tokio::{
     fs::File,
-    io::{AsyncReadExt as _, AsyncSeekExt as _},
+    io::{AsyncRead, ReadBuf},
+    sync::{OwnedSemaphorePermit, Semaphore},
 };
 
 #[derive(Clone)]
 pub struct PathVersionBackend {
     pub base_dir: PathBuf,
+    /// Open handles shared by every reader (and clone) of this backend, so
+    /// chunks of one large file don't each reopen it
+    pub handles: HandlePool,
+    /// Bounds readers (and files opened while listing) across every clone, so
+    /// a low `ulimit -n` isn't exhausted
+    open_files: Arc<Semaphore>,
+    /// What the last listing left out, for `skipped_files`
+    skipped: Arc<Mutex<Vec<String>>>,
 }
 
 use crate::versions::{
-    _list_files,
+    has_dotfile_component, native_path, portable_path,
     types::{MinimumFileObject, VersionBackend, VersionFile},
+    walk_files_with_special, WALK_CONCURRENCY,
 };
 
+/// How many files a `HandlePool` keeps open at once
+pub const MAX_POOLED_HANDLES: usize = 64;
+
+/// Open files a `PathVersionBackend` allows where the fd limit can't be queried
+pub const DEFAULT_MAX_OPEN_FILES: usize = 256;
+
+/// A quarter of the soft fd limit, leaving the rest for the process (and the
+/// handles `HandlePool` keeps), within 16..=1024
+pub fn default_max_open_files() -> usize {
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit only writes to the struct it's given
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+            let soft = usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX);
+            return (soft / 4).clamp(16, 1024);
+        }
+    }
+
+    DEFAULT_MAX_OPEN_FILES
+}
+
+/// Extensions Windows runs directly, compared case-insensitively
+const EXECUTABLE_EXTENSIONS: [&str; 4] = ["exe", "bat", "cmd", "com"];
+
+/// Leading bytes of PE, ELF and Mach-O (thin, either byte order, and fat)
+/// binaries
+const EXECUTABLE_MAGIC: [&[u8]; 7] = [
+    b"MZ",
+    b"\x7fELF",
+    &[0xfe, 0xed, 0xfa, 0xce],
+    &[0xfe, 0xed, 0xfa, 0xcf],
+    &[0xce, 0xfa, 0xed, 0xfe],
+    &[0xcf, 0xfa, 0xed, 0xfe],
+    &[0xca, 0xfe, 0xba, 0xbe],
+];
+
+/// Bytes of a file `synthesized_permission` looks at
+pub const EXECUTABLE_MAGIC_LENGTH: usize = 4;
+
+/// A Unix mode for a file on a platform without them: `0o755` if `filename`
+/// has an executable extension or `header` (its first bytes) is an executable
+/// format's magic, so it's runnable once installed on Linux, and `0o644`
+/// otherwise
+pub fn synthesized_permission(filename: &str, header: &[u8]) -> u32 {
+    let executable_extension = Path::new(filename)
+        .extension()
+        .and_then(|v| v.to_str())
+        .is_some_and(|extension| {
+            EXECUTABLE_EXTENSIONS
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(extension))
+        });
+    if executable_extension || EXECUTABLE_MAGIC.iter().any(|v| header.starts_with(v)) {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+/// Whether at least a block's worth of a file is holes rather than allocated.
+/// Filesystems that compress files can report them as sparse too.
+fn is_sparse(metadata: &std::fs::Metadata) -> bool {
+    let sparse: bool;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt as _;
+
+        // Blocks are counted in 512-byte units, whatever the filesystem's are
+        let allocated = metadata.blocks().saturating_mul(512);
+        sparse = allocated.saturating_add(metadata.blksize()) <= metadata.len();
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        sparse = false;
+    }
+    sparse
+}
+
+/// Holds an open-file permit for as long as the reader is alive
+struct PermittedReader<R> {
+    inner: R,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for PermittedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+/// Largest single positional read, matching tokio's own `File` buffer cap
+const MAX_POSITIONAL_READ: usize = 2 * 1024 * 1024;
+
+/// Read-only handles keyed by path. Once full, the oldest handle is dropped
+/// from the pool; readers still using it keep it open until they finish.
+#[derive(Clone)]
+pub struct HandlePool {
+    inner: Arc<Mutex<HandlePoolInner>>,
+    capacity: usize,
+}
+
+impl Default for HandlePool {
+    fn default() -> Self {
+        Self::with_capacity(MAX_POOLED_HANDLES)
+    }
+}
+
+#[derive(Default)]
+struct HandlePoolInner {
+    handles: HashMap<PathBuf, Arc<std::fs::File>>,
+    order: VecDeque<PathBuf>,
+}
+
+impl HandlePool {
+    /// Keeps at most `capacity` handles open
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Arc::default(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub async fn open(&self, path: &Path) -> std::io::Result<Arc<std::fs::File>> {
+        if let Some(handle) = self.inner.lock().unwrap().handles.get(path) {
+            return Ok(handle.clone());
+        }
+
+        let handle = Arc::new(File::open(path).await?.into_std().await);
+
+        let mut inner = self.inner.lock().unwrap();
+        // Another reader may have opened it in the meantime
+        if let Some(existing) = inner.handles.get(path) {
+            return Ok(existing.clone());
+        }
+        if inner.handles.len() >= self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.handles.remove(&oldest);
+            }
+        }
+        inner.handles.insert(path.to_path_buf(), handle.clone());
+        inner.order.push_back(path.to_path_buf());
+
+        Ok(handle)
+    }
+
+    /// Drops every pooled handle. Readers still using one keep it open until
+    /// they finish.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.handles.clear();
+        inner.order.clear();
+    }
+
+    /// How many handles are currently pooled
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Reads `offset..end` of a shared handle with `read_at`, which never moves
+/// the handle's cursor, so any number of these can read one file at once
+#[cfg(unix)]
+struct PositionalReader {
+    file: Arc<std::fs::File>,
+    offset: u64,
+    end: Option<u64>,
+    pending: Option<JoinHandle<std::io::Result<Vec<u8>>>>,
+    buffered: Vec<u8>,
+    buffered_pos: usize,
+}
+
+#[cfg(unix)]
+impl AsyncRead for PositionalReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if self.buffered_pos < self.buffered.len() {
+                let available = &self.buffered[self.buffered_pos..];
+                let amount = available.len().min(buf.remaining());
+                buf.put_slice(&available[..amount]);
+                self.buffered_pos += amount;
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(pending) = &mut self.pending {
+                let data = match ready!(Pin::new(pending).poll(cx)) {
+                    Ok(result) => result?,
+                    Err(err) => return Poll::Ready(Err(std::io::Error::other(err))),
+                };
+                self.pending = None;
+                if data.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                self.offset += data.len() as u64;
+                self.buffered = data;
+                self.buffered_pos = 0;
+                continue;
+            }
+
+            let mut length = buf.remaining().min(MAX_POSITIONAL_READ);
+            if let Some(end) = self.end {
+                length = length.min(end.saturating_sub(self.offset) as usize);
+            }
+            if length == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            let file = self.file.clone();
+            let offset = self.offset;
+            self.pending = Some(tokio::task::spawn_blocking(move || {
+                use std::os::unix::fs::FileExt as _;
+
+                let mut data = vec![0; length];
+                let read = loop {
+                    match file.read_at(&mut data, offset) {
+                        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                        result => break result?,
+                    }
+                };
+                data.truncate(read);
+                Ok(data)
+            }));
+        }
+    }
+}
+
+impl PathVersionBackend {
+    pub fn new(base_dir: PathBuf) -> Self {
+        Self::with_max_open_files(base_dir, default_max_open_files())
+    }
+
+    /// Allows at most `max_open_files` readers at once, with `reader` waiting
+    /// for one to be dropped past that, and pools no more handles than that
+    pub fn with_max_open_files(base_dir: PathBuf, max_open_files: usize) -> Self {
+        Self {
+            base_dir,
+            handles: HandlePool::with_capacity(max_open_files.min(MAX_POOLED_HANDLES)),
+            open_files: Arc::new(Semaphore::new(max_open_files.max(1))),
+            skipped: Arc::default(),
+        }
+    }
+
+    async fn open_file_permit(&self) -> anyhow::Result<OwnedSemaphorePermit> {
+        Ok(self.open_files.clone().acquire_owned().await?)
+    }
+
+    async fn list_files_in(&self, dir: &Path) -> anyhow::Result<Vec<VersionFile>> {
+        let (vec, special) = walk_files_with_special(dir, WALK_CONCURRENCY).await?;
+
+        let sub_path = |pathbuf: &PathBuf| -> anyhow::Result<String> {
+            let relative = pathbuf.strip_prefix(&self.base_dir)?;
+
+            Ok(portable_path(
+                relative.to_str().ok_or(anyhow!(
+                    "Could not parse path: {}",
+                    relative.to_string_lossy()
+                ))?,
+                MAIN_SEPARATOR,
+            ))
+        };
+        let sub_paths = vec
+            .iter()
+            .map(sub_path)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        *self.skipped.lock().unwrap() = special
+            .iter()
+            .map(sub_path)
+            .collect::<anyhow::Result<_>>()?;
+
+        self.peek_files(&sub_paths).await
+    }
+}
+
 #[async_trait]
 impl VersionBackend for PathVersionBackend {
     async fn list_files(&self) -> anyhow::Result<Vec<VersionFile>> {
-        let mut vec = Vec::new();
-        _list_files(&mut vec, &self.base_dir)?;
-
-        let mut results = Vec::new();
-
-        for pathbuf in vec.iter() {
-            let relative = pathbuf.strip_prefix(self.base_dir.clone())?;
-
-            results.push(
-                self.peek_file(
-                    relative
-                        .to_str()
-                        .ok_or(anyhow!(
-                            "Could not parse path: {}",
-                            relative.to_string_lossy()
-                        ))?
-                        .to_owned(),
-                )
-                .await?,
-            );
+        self.list_files_in(&self.base_dir).await
+    }
+
+    async fn list_files_under(&self, prefix: &str) -> anyhow::Result<Vec<VersionFile>> {
+        let dir = self.base_dir.join(native_path(prefix));
+        if dir.is_file() {
+            return Ok(vec![self.peek_file(prefix.to_owned()).await?]);
+        }
+        if !dir.exists() {
+            return Ok(Vec::new());
         }
 
-        Ok(results)
+        self.list_files_in(&dir).await
     }
 
     async fn reader(
         &self,
         file: &VersionFile,
         start: u64,
-        end: u64,
+        end: Option<u64>,
     ) -> anyhow::Result<Box<dyn MinimumFileObject>> {
-        let mut file = File::open(self.base_dir.join(file.relative_filename.clone())).await?;
-
-        if start != 0 {
-            file.seek(SeekFrom::Start(start)).await?;
+        let path = self.base_dir.join(native_path(&file.relative_filename));
+        if let Some(end) = end {
+            if end < start {
+                return Err(anyhow!("invalid range: {}..{}", start, end));
+            }
         }
 
-        if end != 0 {
-            return Ok(Box::new(file.take(end - start)));
+        let permit = self.open_file_permit().await?;
+
+        #[cfg(unix)]
+        {
+            let file = self.handles.open(&path).await?;
+            Ok(Box::new(PermittedReader {
+                inner: PositionalReader {
+                    file,
+                    offset: start,
+                    end,
+                    pending: None,
+                    buffered: Vec::new(),
+                    buffered_pos: 0,
+                },
+                _permit: permit,
+            }))
         }
 
-        Ok(Box::new(file))
+        // Other platforms' positional reads move the shared cursor, so each
+        // reader gets its own handle
+        #[cfg(not(unix))]
+        {
+            let mut file = File::open(path).await?;
+            if start != 0 {
+                file.seek(SeekFrom::Start(start)).await?;
+            }
+            if let Some(end) = end {
+                return Ok(Box::new(PermittedReader {
+                    inner: file.take(end - start),
+                    _permit: permit,
+                }));
+            }
+
+            Ok(Box::new(PermittedReader {
+                inner: file,
+                _permit: permit,
+            }))
+        }
     }
 
     async fn peek_file(&self, sub_path: String) -> anyhow::Result<VersionFile> {
-        let pathbuf = self.base_dir.join(sub_path.clone());
-        if !pathbuf.exists() {
-            return Err(anyhow!("Path doesn't exist: {}", pathbuf.to_string_lossy()));
+        let sub_path = portable_path(&sub_path, MAIN_SEPARATOR);
+        let pathbuf = self.base_dir.join(native_path(&sub_path));
+        // Stat without opening, which is all Unix needs
+        let metadata = match tokio::fs::metadata(&pathbuf).await {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(anyhow!("Path doesn't exist: {}", pathbuf.to_string_lossy()));
+            }
+            result => result?,
         };
-
-        let file = File::open(pathbuf.clone()).await?;
-        let metadata = file.try_clone().await?.metadata().await?;
-        let permission_object = metadata.permissions();
         let permissions = {
             let perm: u32;
             #[cfg(target_family = "unix")]
             {
-                perm = permission_object.mode();
+                perm = metadata.permissions().mode();
             }
             #[cfg(not(target_family = "unix"))]
             {
-                perm = 0
+                let _permit = self.open_file_permit().await?;
+                let mut header = Vec::with_capacity(EXECUTABLE_MAGIC_LENGTH);
+                File::open(&pathbuf)
+                    .await?
+                    .take(EXECUTABLE_MAGIC_LENGTH as u64)
+                    .read_to_end(&mut header)
+                    .await?;
+                perm = synthesized_permission(&sub_path, &header);
             }
             perm
         };
@@ -92,9 +438,59 @@ impl VersionBackend for PathVersionBackend {
             relative_filename: sub_path,
             permission: permissions,
             size: metadata.len(),
+            symlink: None,
+            is_sparse: is_sparse(&metadata),
         })
     }
 
+    /// Stats up to `WALK_CONCURRENCY` files at once. Off Unix, where each
+    /// file is opened to sniff it, that's still bounded by the open file limit.
+    async fn peek_files(&self, sub_paths: &[String]) -> anyhow::Result<Vec<VersionFile>> {
+        futures::stream::iter(sub_paths.iter().cloned())
+            .map(|sub_path| self.peek_file(sub_path))
+            .buffered(WALK_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    /// Unix dotfiles, or files with the Windows hidden or system attribute,
+    /// including anything inside such a directory
+    async fn is_hidden(&self, file: &VersionFile) -> anyhow::Result<bool> {
+        let relative = Path::new(&file.relative_filename);
+        for component in relative.ancestors().filter(|v| !v.as_os_str().is_empty()) {
+            #[cfg(not(windows))]
+            if component
+                .file_name()
+                .is_some_and(|v| has_dotfile_component(&v.to_string_lossy()))
+            {
+                return Ok(true);
+            }
+            #[cfg(windows)]
+            {
+                use std::os::windows::fs::MetadataExt as _;
+                const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+                const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+
+                let metadata = tokio::fs::metadata(self.base_dir.join(component)).await?;
+                if metadata.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0
+                {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn skipped_files(&self) -> Vec<String> {
+        self.skipped.lock().unwrap().clone()
+    }
+
+    async fn close(self: Box<Self>) -> anyhow::Result<()> {
+        self.handles.clear();
+        Ok(())
+    }
+
     fn require_whole_files(&self) -> bool {
         false
     }
@@ -1,13 +1,20 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, path::Path};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt as _};
 
 #[derive(Debug, Clone)]
 pub struct VersionFile {
     pub relative_filename: String,
     pub permission: u32,
     pub size: u64,
+    /// Target of a symlink, which has no contents of its own (`size` is 0)
+    pub symlink: Option<String>,
+    /// Takes up less space on disk than `size`, as some of it is holes that
+    /// read as zeros. Only detected on Unix. The zeros are still read into
+    /// chunks, but installs can recreate the holes instead of writing them.
+    pub is_sparse: bool,
 }
 
 pub trait MinimumFileObject: AsyncRead + Send + Unpin {}
@@ -17,11 +24,82 @@ impl<T: AsyncRead + Send + Unpin> MinimumFileObject for T {}
 pub trait VersionBackend {
     fn require_whole_files(&self) -> bool;
     async fn list_files(&self) -> anyhow::Result<Vec<VersionFile>>;
+    /// Files at or below `prefix` (a relative directory path), matched by
+    /// whole path components
+    async fn list_files_under(&self, prefix: &str) -> anyhow::Result<Vec<VersionFile>> {
+        let files = self.list_files().await?;
+        Ok(files
+            .into_iter()
+            .filter(|v| Path::new(&v.relative_filename).starts_with(prefix))
+            .collect())
+    }
     async fn peek_file(&self, sub_path: String) -> anyhow::Result<VersionFile>;
+    /// `peek_file` for each of `sub_paths`, in the same order. Backends
+    /// override this where looking files up together is cheaper than one at
+    /// a time.
+    async fn peek_files(&self, sub_paths: &[String]) -> anyhow::Result<Vec<VersionFile>> {
+        let mut files = Vec::with_capacity(sub_paths.len());
+        for sub_path in sub_paths {
+            files.push(self.peek_file(sub_path.clone()).await?);
+        }
+
+        Ok(files)
+    }
+    /// Reads `file` from `start` up to (not including) `end`, or to EOF if
+    /// `end` is `None`. Backends that require whole files ignore the range.
     async fn reader(
         &self,
         file: &VersionFile,
         start: u64,
-        end: u64,
+        end: Option<u64>,
     ) -> anyhow::Result<Box<dyn MinimumFileObject>>;
+    /// Whether `file` is hidden or a system file, and so left out of manifests
+    /// by default. Backends without file attributes include everything.
+    async fn is_hidden(&self, _file: &VersionFile) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+    /// Files the last listing left out for not being regular files, like
+    /// FIFOs, sockets and device nodes. Backends that can't hold them have
+    /// none.
+    fn skipped_files(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Releases whatever the backend holds open, like file handles, once the
+    /// caller is done with it. Dropping a backend releases them too, but
+    /// without a chance to report errors.
+    async fn close(self: Box<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// Reads the whole of `file` into memory
+    async fn read_to_end(&self, file: &VersionFile) -> anyhow::Result<Vec<u8>> {
+        self.read_to_end_limited(file, u64::MAX).await
+    }
+    /// Reads the whole of `file` into memory, erroring rather than reading
+    /// more than `max` bytes, whatever size the backend claims it has
+    async fn read_to_end_limited(&self, file: &VersionFile, max: u64) -> anyhow::Result<Vec<u8>> {
+        if file.size > max {
+            return Err(anyhow!(
+                "{} is {} bytes, over the {} byte limit",
+                file.relative_filename,
+                file.size,
+                max
+            ));
+        }
+
+        let reader = self.reader(file, 0, None).await?;
+        let mut data = Vec::new();
+        reader
+            .take(max.saturating_add(1))
+            .read_to_end(&mut data)
+            .await?;
+        if data.len() as u64 > max {
+            return Err(anyhow!(
+                "{} is over the {} byte limit",
+                file.relative_filename,
+                max
+            ));
+        }
+
+        Ok(data)
+    }
 }